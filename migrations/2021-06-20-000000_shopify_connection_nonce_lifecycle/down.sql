@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "shopify_connections"
+  DROP COLUMN nonce_expires_at,
+  DROP COLUMN nonce_consumed_at;
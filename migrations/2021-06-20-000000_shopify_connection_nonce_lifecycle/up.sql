@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE "shopify_connections"
+  ADD COLUMN nonce_expires_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  ADD COLUMN nonce_consumed_at TIMESTAMP;
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE "shopify_connections" ADD COLUMN scope VARCHAR;
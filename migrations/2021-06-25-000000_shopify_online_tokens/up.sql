@@ -0,0 +1,17 @@
+-- Your SQL goes here
+CREATE TABLE "shopify_online_tokens" (
+  id SERIAL PRIMARY KEY,
+  shop VARCHAR NOT NULL,
+  shopify_user_id BIGINT NOT NULL,
+  access_token VARCHAR NOT NULL,
+  scope VARCHAR NOT NULL,
+  associated_user_scope VARCHAR NOT NULL,
+  expires_at TIMESTAMP NOT NULL,
+  email VARCHAR,
+  first_name VARCHAR,
+  last_name VARCHAR,
+  account_owner BOOLEAN NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  updated_at TIMESTAMP,
+  UNIQUE (shop, shopify_user_id)
+);
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE "compliance_requests" (
+  id SERIAL PRIMARY KEY,
+  shop VARCHAR NOT NULL,
+  topic VARCHAR NOT NULL,
+  webhook_id VARCHAR NOT NULL,
+  customer_id BIGINT,
+  customer_email VARCHAR,
+  payload TEXT NOT NULL,
+  result TEXT,
+  received_at TIMESTAMP NOT NULL,
+  completed_at TIMESTAMP
+);
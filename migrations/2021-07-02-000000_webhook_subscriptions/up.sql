@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE "webhook_subscriptions" (
+  id SERIAL PRIMARY KEY,
+  shop VARCHAR NOT NULL,
+  topic VARCHAR NOT NULL,
+  address VARCHAR NOT NULL,
+  shopify_webhook_id BIGINT,
+  failed_at TIMESTAMP,
+  last_error TEXT,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  created_at TIMESTAMP NOT NULL,
+  updated_at TIMESTAMP,
+  UNIQUE (shop, topic)
+);
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE "processed_webhooks" (
+  id SERIAL PRIMARY KEY,
+  shop VARCHAR NOT NULL,
+  webhook_id VARCHAR NOT NULL,
+  topic VARCHAR NOT NULL,
+  received_at TIMESTAMP NOT NULL,
+  UNIQUE (shop, webhook_id)
+);
+
+CREATE INDEX processed_webhooks_received_at ON processed_webhooks (received_at);
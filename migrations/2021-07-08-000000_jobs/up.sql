@@ -0,0 +1,16 @@
+-- Your SQL goes here
+CREATE TABLE "jobs" (
+  id SERIAL PRIMARY KEY,
+  kind VARCHAR NOT NULL,
+  shop VARCHAR NOT NULL,
+  payload TEXT NOT NULL,
+  status VARCHAR NOT NULL,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  run_at TIMESTAMP NOT NULL,
+  locked_at TIMESTAMP,
+  last_error TEXT,
+  created_at TIMESTAMP NOT NULL,
+  updated_at TIMESTAMP
+);
+
+CREATE INDEX jobs_status_run_at ON jobs (status, run_at);
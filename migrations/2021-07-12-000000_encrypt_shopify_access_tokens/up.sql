@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- access_token holds ciphertext once access_token_key_id is set,
+-- rows without one are left over from before and get sealed on rotation
+ALTER TABLE "shopify_connections" ADD COLUMN access_token_data_key VARCHAR;
+ALTER TABLE "shopify_connections" ADD COLUMN access_token_key_id VARCHAR;
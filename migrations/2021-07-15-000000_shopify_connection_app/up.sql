@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- which of our apps the shop installed, everything before this was the default app
+ALTER TABLE "shopify_connections" ADD COLUMN app VARCHAR NOT NULL DEFAULT 'default';
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+-- woocommerce hands over a consumer key and secret instead of a token,
+-- the secret is sealed the same way shopify access tokens are
+CREATE TABLE "woocommerce_connections" (
+  id SERIAL PRIMARY KEY,
+  store_url VARCHAR NOT NULL,
+  nonce VARCHAR NOT NULL,
+  consumer_key VARCHAR,
+  consumer_secret VARCHAR,
+  consumer_secret_data_key VARCHAR,
+  consumer_secret_key_id VARCHAR,
+  key_permissions VARCHAR,
+  created_at TIMESTAMP NOT NULL,
+  updated_at TIMESTAMP,
+  deleted_at TIMESTAMP,
+  active BOOLEAN NOT NULL,
+  nonce_expires_at TIMESTAMP NOT NULL,
+  nonce_consumed_at TIMESTAMP
+);
+
+CREATE INDEX woocommerce_connections_nonce ON woocommerce_connections (nonce);
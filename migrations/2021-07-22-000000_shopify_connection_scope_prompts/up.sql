@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- how many times in a row we've sent the shop back to grant scopes it left out
+ALTER TABLE "shopify_connections" ADD COLUMN scope_prompts INT NOT NULL DEFAULT 0;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- when the shop finished installing, online installs never leave a token on the row
+ALTER TABLE "shopify_connections" ADD COLUMN installed_at TIMESTAMP;
+UPDATE "shopify_connections" SET installed_at = COALESCE(updated_at, created_at)
+  WHERE access_token IS NOT NULL;
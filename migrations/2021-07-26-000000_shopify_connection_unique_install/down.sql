@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX shopify_connections_installed_app_shop;
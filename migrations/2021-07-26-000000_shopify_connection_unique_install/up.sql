@@ -0,0 +1,17 @@
+-- Your SQL goes here
+-- a shop has at most one live install per app, older duplicates are retired first
+UPDATE "shopify_connections" SET
+    access_token = NULL,
+    access_token_data_key = NULL,
+    access_token_key_id = NULL,
+    active = FALSE,
+    deleted_at = NOW(),
+    updated_at = NOW()
+  WHERE installed_at IS NOT NULL AND deleted_at IS NULL AND id NOT IN (
+    SELECT DISTINCT ON (app, shop) id FROM "shopify_connections"
+      WHERE installed_at IS NOT NULL AND deleted_at IS NULL
+      ORDER BY app, shop, updated_at DESC NULLS LAST, id DESC
+  );
+CREATE UNIQUE INDEX shopify_connections_installed_app_shop
+  ON "shopify_connections" (app, shop)
+  WHERE installed_at IS NOT NULL AND deleted_at IS NULL;
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "compliance_requests" ADD COLUMN customer_email VARCHAR;
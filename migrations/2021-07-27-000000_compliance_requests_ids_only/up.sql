@@ -0,0 +1,6 @@
+-- Your SQL goes here
+-- only the ids a request is about are kept, never the customer's contact details
+ALTER TABLE "compliance_requests" DROP COLUMN customer_email;
+UPDATE "compliance_requests" SET
+    payload = (payload::jsonb #- '{customer,email}' #- '{customer,phone}')::text,
+    result = (result::jsonb #- '{customer,email}' #- '{customer,phone}')::text;
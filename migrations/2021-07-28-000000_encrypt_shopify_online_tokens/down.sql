@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "shopify_online_tokens" DROP COLUMN access_token_key_id;
+ALTER TABLE "shopify_online_tokens" DROP COLUMN access_token_data_key;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- the same envelope as shopify_connections, rows without a key id are
+-- left over from before and get sealed on rotation
+ALTER TABLE "shopify_online_tokens" ADD COLUMN access_token_data_key VARCHAR;
+ALTER TABLE "shopify_online_tokens" ADD COLUMN access_token_key_id VARCHAR;
@@ -0,0 +1,16 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "jobs" DROP COLUMN app;
+
+ALTER TABLE "webhook_subscriptions" DROP CONSTRAINT webhook_subscriptions_app_shop_topic_key;
+DELETE FROM "webhook_subscriptions" a USING "webhook_subscriptions" b
+  WHERE a.shop = b.shop AND a.topic = b.topic AND a.id < b.id;
+ALTER TABLE "webhook_subscriptions" ADD CONSTRAINT webhook_subscriptions_shop_topic_key
+  UNIQUE (shop, topic);
+ALTER TABLE "webhook_subscriptions" DROP COLUMN app;
+
+ALTER TABLE "shopify_online_tokens" DROP CONSTRAINT shopify_online_tokens_app_shop_shopify_user_id_key;
+DELETE FROM "shopify_online_tokens" a USING "shopify_online_tokens" b
+  WHERE a.shop = b.shop AND a.shopify_user_id = b.shopify_user_id AND a.id < b.id;
+ALTER TABLE "shopify_online_tokens" ADD CONSTRAINT shopify_online_tokens_shop_shopify_user_id_key
+  UNIQUE (shop, shopify_user_id);
+ALTER TABLE "shopify_online_tokens" DROP COLUMN app;
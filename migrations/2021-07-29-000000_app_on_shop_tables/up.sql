@@ -0,0 +1,16 @@
+-- Your SQL goes here
+-- which of our apps these belong to, everything before this was the default app
+ALTER TABLE "shopify_online_tokens" ADD COLUMN app VARCHAR NOT NULL DEFAULT 'default';
+ALTER TABLE "shopify_online_tokens" DROP CONSTRAINT shopify_online_tokens_shop_shopify_user_id_key;
+ALTER TABLE "shopify_online_tokens" ADD CONSTRAINT shopify_online_tokens_app_shop_shopify_user_id_key
+  UNIQUE (app, shop, shopify_user_id);
+
+ALTER TABLE "webhook_subscriptions" ADD COLUMN app VARCHAR NOT NULL DEFAULT 'default';
+ALTER TABLE "webhook_subscriptions" DROP CONSTRAINT webhook_subscriptions_shop_topic_key;
+ALTER TABLE "webhook_subscriptions" ADD CONSTRAINT webhook_subscriptions_app_shop_topic_key
+  UNIQUE (app, shop, topic);
+
+-- queued webhooks already say which app they came in on
+ALTER TABLE "jobs" ADD COLUMN app VARCHAR NOT NULL DEFAULT 'default';
+UPDATE "jobs" SET app = payload::jsonb->>'app'
+  WHERE kind = 'shopify_webhook' AND payload::jsonb ? 'app';
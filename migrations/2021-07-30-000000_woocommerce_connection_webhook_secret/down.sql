@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE "woocommerce_connections" DROP COLUMN webhook_secret_key_id;
+ALTER TABLE "woocommerce_connections" DROP COLUMN webhook_secret_data_key;
+ALTER TABLE "woocommerce_connections" DROP COLUMN webhook_secret;
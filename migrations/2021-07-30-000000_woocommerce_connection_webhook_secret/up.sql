@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- each store signs its webhooks with a secret we made up for it at install
+ALTER TABLE "woocommerce_connections" ADD COLUMN webhook_secret VARCHAR;
+ALTER TABLE "woocommerce_connections" ADD COLUMN webhook_secret_data_key VARCHAR;
+ALTER TABLE "woocommerce_connections" ADD COLUMN webhook_secret_key_id VARCHAR;
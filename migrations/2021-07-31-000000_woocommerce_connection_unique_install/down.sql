@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX woocommerce_connections_installed_store_url;
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+-- a store has at most one live install, older duplicates are retired first
+UPDATE "woocommerce_connections" SET
+    consumer_key = NULL,
+    consumer_secret = NULL,
+    consumer_secret_data_key = NULL,
+    consumer_secret_key_id = NULL,
+    webhook_secret = NULL,
+    webhook_secret_data_key = NULL,
+    webhook_secret_key_id = NULL,
+    active = FALSE,
+    deleted_at = NOW(),
+    updated_at = NOW()
+  WHERE consumer_secret IS NOT NULL AND deleted_at IS NULL AND id NOT IN (
+    SELECT DISTINCT ON (store_url) id FROM "woocommerce_connections"
+      WHERE consumer_secret IS NOT NULL AND deleted_at IS NULL
+      ORDER BY store_url, updated_at DESC NULLS LAST, id DESC
+  );
+CREATE UNIQUE INDEX woocommerce_connections_installed_store_url
+  ON "woocommerce_connections" (store_url)
+  WHERE consumer_secret IS NOT NULL AND deleted_at IS NULL;
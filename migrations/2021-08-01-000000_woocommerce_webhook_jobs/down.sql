@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+UPDATE "jobs" SET kind = 'shopify_webhook', app = 'default'
+  WHERE kind = 'woocommerce_webhook';
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- woocommerce webhooks were queued as shopify ones under the default app,
+-- a store address is the only thing telling them apart
+UPDATE "jobs" SET kind = 'woocommerce_webhook', app = 'woocommerce'
+  WHERE kind = 'shopify_webhook' AND (shop LIKE 'https://%' OR shop LIKE 'http://%');
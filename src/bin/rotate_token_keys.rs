@@ -0,0 +1,73 @@
+use rust_oauth2_study::{
+    config::Config,
+    db_conn::DbConn,
+    models::{shopify_connection, shopify_online_token, woocommerce_connection},
+};
+use std::env;
+
+// reseals every access token, online ones included, and every woocommerce
+// consumer and webhook secret under the first key in TOKEN_ENCRYPTION_KEYS.
+// keep the old key listed after the new one until this finishes, the app can
+// still open tokens under either in the meantime.
+//
+// cargo run --bin rotate_token_keys [batch size]
+fn main() {
+    let config = Config::new(false);
+    let db_conn = DbConn::new(&config.db_path);
+    let cipher = config.token_cipher();
+
+    let batch_size = env::args()
+        .nth(1)
+        .map(|arg| arg.parse().expect("batch size must be a number"))
+        .unwrap_or(100);
+
+    let mut after_id = 0;
+    let mut batches = 0;
+
+    while let Some(last_id) = shopify_connection::rotate_access_token_keys(
+        &db_conn.get_conn(),
+        &cipher,
+        after_id,
+        batch_size,
+    )
+    .expect("Error rotating access token keys")
+    {
+        after_id = last_id;
+        batches += 1;
+        println!("rotated up to connection {}", last_id);
+    }
+
+    after_id = 0;
+    while let Some(last_id) = shopify_online_token::rotate_access_token_keys(
+        &db_conn.get_conn(),
+        &cipher,
+        after_id,
+        batch_size,
+    )
+    .expect("Error rotating online access token keys")
+    {
+        after_id = last_id;
+        batches += 1;
+        println!("rotated up to online token {}", last_id);
+    }
+
+    after_id = 0;
+    while let Some(last_id) = woocommerce_connection::rotate_consumer_secret_keys(
+        &db_conn.get_conn(),
+        &cipher,
+        after_id,
+        batch_size,
+    )
+    .expect("Error rotating consumer secret keys")
+    {
+        after_id = last_id;
+        batches += 1;
+        println!("rotated up to woocommerce connection {}", last_id);
+    }
+
+    println!(
+        "done, {} batches now under key {}",
+        batches,
+        cipher.active_key_id()
+    );
+}
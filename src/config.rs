@@ -1,16 +1,96 @@
+use crate::encryption::{self, TokenCipher, TokenKey};
 use dotenv::dotenv;
 use std::env;
 
+// offline tokens belong to the shop and never expire,
+// online tokens belong to the staff member who installed us
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessMode {
+    Offline,
+    Online,
+}
+
+// the app everything belonged to before we ran more than one,
+// its routes don't take an app segment
+pub const DEFAULT_SHOPIFY_APP: &str = "default";
+
+// what woocommerce rows and jobs carry in their app column,
+// kept apart from every shopify app handle
+pub const WOOCOMMERCE_APP: &str = "woocommerce";
+
+// one app listing in the partner dashboard, e.x. staging or production
+#[derive(Clone, Debug)]
+pub struct ShopifyApp {
+    pub handle: String,
+    pub api_key: String,
+    pub api_secret: String,
+    pub previous_api_secrets: Vec<String>,
+}
+
+impl ShopifyApp {
+    pub fn new(handle: &str, api_key: String, api_secret: String) -> Self {
+        ShopifyApp {
+            handle: handle.to_string(),
+            api_key,
+            api_secret,
+            previous_api_secrets: vec![],
+        }
+    }
+
+    // the current secret first, then the ones it replaced
+    pub fn secrets(&self) -> Vec<&str> {
+        let mut secrets = vec![self.api_secret.as_str()];
+        secrets.extend(self.previous_api_secrets.iter().map(String::as_str));
+        secrets
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.handle == DEFAULT_SHOPIFY_APP
+    }
+
+    // e.x. /shopify_confirm for the default app, /shopify_confirm/staging for the rest
+    pub fn path(&self, prefix: &str) -> String {
+        if self.is_default() {
+            format!("/{}", prefix)
+        } else {
+            format!("/{}/{}", prefix, self.handle)
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Config {
     pub app_addr: String,
-    pub shopify_api_key: String,
-    pub shopify_api_secret: String,
+    // the default app first, it's always there
+    pub shopify_apps: Vec<ShopifyApp>,
+    pub token_keys: Vec<TokenKey>,
     pub shopify_api_uri: String,
+    pub shopify_scopes: Vec<String>,
+    pub shopify_access_mode: AccessMode,
+    pub shopify_api_version: String,
+    pub shopify_webhook_topics: Vec<String>,
+    pub shopify_api_bucket_size: f64,
+    pub shopify_api_leak_rate: f64,
+    pub shopify_api_max_retries: usize,
+    pub woocommerce_app_name: String,
+    pub woocommerce_scope: String,
+    pub woocommerce_webhook_topics: Vec<String>,
+    pub app_url: String,
     pub tls: bool,
     pub cert_path: Option<String>,
     pub key_path: Option<String>,
     pub db_path: String,
+    pub timestamp_skew_secs: i64,
+    pub nonce_ttl_secs: i64,
+    pub nonce_sweep_interval_secs: u64,
+    pub webhook_retry_interval_secs: u64,
+    pub processed_webhook_retention_secs: i64,
+    pub webhook_sweep_interval_secs: u64,
+    pub job_workers: usize,
+    pub job_poll_interval_ms: u64,
+    pub job_lease_secs: i64,
+    pub job_max_attempts: i32,
+    pub job_retry_base_secs: i64,
     pub is_mocking: bool,
 }
 
@@ -23,9 +103,67 @@ impl Config {
 
         let app_addr = format!("{}:{}", app_host, app_port);
 
-        let shopify_api_key = env::var("API_KEY_SHOPIFY").expect("API_KEY_SHOPIFY must be set");
-        let shopify_api_secret =
-            env::var("API_SECRET_SHOPIFY").expect("API_SECRET_SHOPIFY must be set");
+        let mut shopify_apps = vec![shopify_app_from_env(DEFAULT_SHOPIFY_APP, "")];
+
+        // e.x. "staging,second-listing", each with its own keys under
+        // API_KEY_SHOPIFY_STAGING, API_SECRET_SHOPIFY_STAGING and so on
+        for handle in env::var("SHOPIFY_APPS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|handle| !handle.is_empty())
+        {
+            if shopify_apps.iter().any(|app| app.handle == handle) {
+                panic!("SHOPIFY_APPS lists {} more than once", handle);
+            }
+            if handle == WOOCOMMERCE_APP {
+                panic!("SHOPIFY_APPS can't use {}, woocommerce has it", handle);
+            }
+            let suffix = format!("_{}", handle.to_uppercase().replace('-', "_"));
+            shopify_apps.push(shopify_app_from_env(handle, &suffix));
+        }
+
+        // e.x. "2021-07:{base64 key},2021-06:{base64 key}", the first one seals new tokens
+        let token_keys = encryption::parse_keys(
+            &env::var("TOKEN_ENCRYPTION_KEYS").expect("TOKEN_ENCRYPTION_KEYS must be set"),
+        )
+        .expect("TOKEN_ENCRYPTION_KEYS must be id:base64 pairs of 32 byte keys");
+        if token_keys.is_empty() {
+            panic!("TOKEN_ENCRYPTION_KEYS must have at least one key");
+        }
+
+        let shopify_scopes = env::var("SHOPIFY_SCOPES")
+            .unwrap_or_else(|_| String::from("read_orders,write_orders"))
+            .split(',')
+            .map(|scope| scope.trim().to_string())
+            .filter(|scope| !scope.is_empty())
+            .collect();
+
+        let shopify_access_mode = match env::var("SHOPIFY_ACCESS_MODE")
+            .unwrap_or_else(|_| String::from("offline"))
+            .as_str()
+        {
+            "offline" => AccessMode::Offline,
+            "online" => AccessMode::Online,
+            _ => panic!("SHOPIFY_ACCESS_MODE must be offline or online"),
+        };
+
+        // admin api version every call after install is made against
+        let shopify_api_version =
+            env::var("SHOPIFY_API_VERSION").unwrap_or_else(|_| String::from("2021-07"));
+
+        // what we subscribe each shop to once it has installed us,
+        // the compliance topics are set up in the partner dashboard instead
+        let shopify_webhook_topics = env::var("SHOPIFY_WEBHOOK_TOPICS")
+            .unwrap_or_else(|_| String::from("app/uninstalled,orders/paid,orders/create"))
+            .split(',')
+            .map(|topic| topic.trim().to_string())
+            .filter(|topic| !topic.is_empty())
+            .collect();
+
+        // public url shopify sends the shop back to, e.x. https://gifts.example.com
+        let app_url =
+            env::var("APP_URL").unwrap_or_else(|_| String::from("https://localhost:3030"));
 
         let tls = env::var("ENABLE_TLS")
             .expect("ENABLE_TLS must be set")
@@ -48,24 +186,273 @@ impl Config {
 
         let shopify_api_uri = String::from("https://");
 
+        // how far a signed callback's timestamp may drift from our clock
+        let timestamp_skew_secs = env::var("TIMESTAMP_SKEW_SECS")
+            .unwrap_or_else(|_| String::from("300"))
+            .parse()
+            .expect("TIMESTAMP_SKEW_SECS must be a number");
+
+        // how long a shop has to come back from the authorize page
+        let nonce_ttl_secs = env::var("NONCE_TTL_SECS")
+            .unwrap_or_else(|_| String::from("600"))
+            .parse()
+            .expect("NONCE_TTL_SECS must be a number");
+
+        let nonce_sweep_interval_secs = env::var("NONCE_SWEEP_INTERVAL_SECS")
+            .unwrap_or_else(|_| String::from("3600"))
+            .parse()
+            .expect("NONCE_SWEEP_INTERVAL_SECS must be a number");
+        if nonce_sweep_interval_secs == 0 {
+            panic!("NONCE_SWEEP_INTERVAL_SECS must be more than 0");
+        }
+
+        let webhook_retry_interval_secs = env::var("WEBHOOK_RETRY_INTERVAL_SECS")
+            .unwrap_or_else(|_| String::from("300"))
+            .parse()
+            .expect("WEBHOOK_RETRY_INTERVAL_SECS must be a number");
+        if webhook_retry_interval_secs == 0 {
+            panic!("WEBHOOK_RETRY_INTERVAL_SECS must be more than 0");
+        }
+
+        // shopify stops retrying a webhook after 48 hours,
+        // we remember the ones we've handled for a good while longer
+        let processed_webhook_retention_secs = env::var("PROCESSED_WEBHOOK_RETENTION_SECS")
+            .unwrap_or_else(|_| String::from("604800"))
+            .parse()
+            .expect("PROCESSED_WEBHOOK_RETENTION_SECS must be a number");
+
+        let webhook_sweep_interval_secs = env::var("WEBHOOK_SWEEP_INTERVAL_SECS")
+            .unwrap_or_else(|_| String::from("3600"))
+            .parse()
+            .expect("WEBHOOK_SWEEP_INTERVAL_SECS must be a number");
+        if webhook_sweep_interval_secs == 0 {
+            panic!("WEBHOOK_SWEEP_INTERVAL_SECS must be more than 0");
+        }
+
+        // how many jobs run at once, and how often an idle worker looks for more
+        let job_workers = env::var("JOB_WORKERS")
+            .unwrap_or_else(|_| String::from("4"))
+            .parse()
+            .expect("JOB_WORKERS must be a number");
+
+        let job_poll_interval_ms = env::var("JOB_POLL_INTERVAL_MS")
+            .unwrap_or_else(|_| String::from("1000"))
+            .parse()
+            .expect("JOB_POLL_INTERVAL_MS must be a number");
+        // 0 would have idle workers hammering postgres
+        if job_poll_interval_ms == 0 {
+            panic!("JOB_POLL_INTERVAL_MS must be more than 0");
+        }
+
+        // a job running longer than this is assumed lost and run again
+        let job_lease_secs = env::var("JOB_LEASE_SECS")
+            .unwrap_or_else(|_| String::from("300"))
+            .parse()
+            .expect("JOB_LEASE_SECS must be a number");
+
+        let job_max_attempts = env::var("JOB_MAX_ATTEMPTS")
+            .unwrap_or_else(|_| String::from("8"))
+            .parse()
+            .expect("JOB_MAX_ATTEMPTS must be a number");
+
+        // the first retry waits this long, every one after twice as long
+        let job_retry_base_secs = env::var("JOB_RETRY_BASE_SECS")
+            .unwrap_or_else(|_| String::from("10"))
+            .parse()
+            .expect("JOB_RETRY_BASE_SECS must be a number");
+
+        // shopify's rest limits per shop, a bucket of calls that drains every second
+        let shopify_api_bucket_size: f64 = env::var("SHOPIFY_API_BUCKET_SIZE")
+            .unwrap_or_else(|_| String::from("40"))
+            .parse()
+            .expect("SHOPIFY_API_BUCKET_SIZE must be a number");
+        if !(shopify_api_bucket_size.is_finite() && shopify_api_bucket_size >= 1.0) {
+            panic!("SHOPIFY_API_BUCKET_SIZE must be at least 1");
+        }
+
+        let shopify_api_leak_rate: f64 = env::var("SHOPIFY_API_LEAK_RATE")
+            .unwrap_or_else(|_| String::from("2"))
+            .parse()
+            .expect("SHOPIFY_API_LEAK_RATE must be a number");
+        // the rate limiter divides by it to work out how long to wait
+        if !(shopify_api_leak_rate.is_finite() && shopify_api_leak_rate > 0.0) {
+            panic!("SHOPIFY_API_LEAK_RATE must be more than 0");
+        }
+
+        // how many times a throttled GET, PUT or DELETE is tried again
+        let shopify_api_max_retries = env::var("SHOPIFY_API_MAX_RETRIES")
+            .unwrap_or_else(|_| String::from("3"))
+            .parse()
+            .expect("SHOPIFY_API_MAX_RETRIES must be a number");
+
+        // what merchants see on the woocommerce approve page
+        let woocommerce_app_name =
+            env::var("WOOCOMMERCE_APP_NAME").unwrap_or_else(|_| String::from("Gift Cards"));
+
+        let woocommerce_scope =
+            env::var("WOOCOMMERCE_SCOPE").unwrap_or_else(|_| String::from("read_write"));
+        if !["read", "write", "read_write"].contains(&woocommerce_scope.as_str()) {
+            panic!("WOOCOMMERCE_SCOPE must be read, write or read_write");
+        }
+
+        // what we set up on each store once it has installed us, e.x. "order.created".
+        // every store gets its own secret to sign them with
+        let woocommerce_webhook_topics = env::var("WOOCOMMERCE_WEBHOOK_TOPICS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|topic| topic.trim().to_string())
+            .filter(|topic| !topic.is_empty())
+            .collect();
+
         Config {
             app_addr,
-            shopify_api_key,
-            shopify_api_secret,
+            shopify_apps,
+            token_keys,
             shopify_api_uri,
+            shopify_scopes,
+            shopify_access_mode,
+            shopify_api_version,
+            shopify_webhook_topics,
+            shopify_api_bucket_size,
+            shopify_api_leak_rate,
+            shopify_api_max_retries,
+            woocommerce_app_name,
+            woocommerce_scope,
+            woocommerce_webhook_topics,
+            app_url,
             tls,
             cert_path,
             key_path,
             db_path,
+            timestamp_skew_secs,
+            nonce_ttl_secs,
+            nonce_sweep_interval_secs,
+            webhook_retry_interval_secs,
+            processed_webhook_retention_secs,
+            webhook_sweep_interval_secs,
+            job_workers,
+            job_poll_interval_ms,
+            job_lease_secs,
+            job_max_attempts,
+            job_retry_base_secs,
             is_mocking,
         }
     }
 
+    pub fn default_shopify_app(&self) -> &ShopifyApp {
+        &self.shopify_apps[0]
+    }
+
+    // the app named in a route, no name means the default app
+    pub fn shopify_app(&self, handle: Option<&str>) -> Option<&ShopifyApp> {
+        let handle = handle.unwrap_or(DEFAULT_SHOPIFY_APP);
+        self.shopify_apps.iter().find(|app| app.handle == handle)
+    }
+
+    // session tokens name their app by api key in the aud claim
+    pub fn shopify_app_by_api_key(&self, api_key: &str) -> Option<&ShopifyApp> {
+        self.shopify_apps.iter().find(|app| app.api_key == api_key)
+    }
+
+    pub fn token_cipher(&self) -> TokenCipher {
+        TokenCipher::new(self.token_keys.clone())
+    }
+
+    // where to reach a shop's admin, everything goes to the mock server in tests
+    pub fn shopify_shop_uri(&self, shop: &str) -> String {
+        if self.is_mocking {
+            self.shopify_api_uri.clone()
+        } else {
+            format!("{}{}", self.shopify_api_uri, shop)
+        }
+    }
+
+    // a url shopify sends an app's shops or webhooks to
+    pub fn app_url_for(&self, app: &ShopifyApp, prefix: &str) -> String {
+        format!("{}{}", self.app_url.trim_end_matches('/'), app.path(prefix))
+    }
+
+    // where shopify delivers the webhooks we subscribe to for an app
+    pub fn webhook_address(&self, app: &ShopifyApp) -> String {
+        self.app_url_for(app, "webhooks/shopify")
+    }
+
     pub fn set_shopify_api_uri(&mut self, uri: String) {
         self.shopify_api_uri = uri;
     }
 
     pub fn set_shopify_secret_key(&mut self, uri: String) {
-        self.shopify_api_secret = uri;
+        self.shopify_apps[0].api_secret = uri;
+    }
+
+    pub fn set_shopify_scopes(&mut self, scopes: Vec<String>) {
+        self.shopify_scopes = scopes;
     }
+
+    pub fn set_shopify_access_mode(&mut self, access_mode: AccessMode) {
+        self.shopify_access_mode = access_mode;
+    }
+
+    pub fn set_shopify_webhook_topics(&mut self, topics: Vec<String>) {
+        self.shopify_webhook_topics = topics;
+    }
+
+    pub fn set_shopify_previous_secret_keys(&mut self, secrets: Vec<String>) {
+        self.shopify_apps[0].previous_api_secrets = secrets;
+    }
+
+    pub fn add_shopify_app(&mut self, app: ShopifyApp) {
+        self.shopify_apps.push(app);
+    }
+
+    pub fn set_woocommerce_webhook_topics(&mut self, topics: Vec<String>) {
+        self.woocommerce_webhook_topics = topics;
+    }
+
+    pub fn set_token_keys(&mut self, keys: Vec<TokenKey>) {
+        self.token_keys = keys;
+    }
+
+    pub fn set_shopify_api_rate_limit(&mut self, bucket_size: f64, leak_rate: f64) {
+        self.shopify_api_bucket_size = bucket_size;
+        self.shopify_api_leak_rate = leak_rate;
+    }
+
+    pub fn set_app_url(&mut self, app_url: String) {
+        self.app_url = app_url;
+    }
+
+    pub fn set_timestamp_skew_secs(&mut self, secs: i64) {
+        self.timestamp_skew_secs = secs;
+    }
+
+    pub fn set_nonce_ttl_secs(&mut self, secs: i64) {
+        self.nonce_ttl_secs = secs;
+    }
+
+    pub fn set_job_max_attempts(&mut self, attempts: i32) {
+        self.job_max_attempts = attempts;
+    }
+}
+
+fn shopify_app_from_env(handle: &str, suffix: &str) -> ShopifyApp {
+    let key_var = format!("API_KEY_SHOPIFY{}", suffix);
+    let secret_var = format!("API_SECRET_SHOPIFY{}", suffix);
+
+    let mut app = ShopifyApp::new(
+        handle,
+        env::var(&key_var).unwrap_or_else(|_| panic!("{} must be set", key_var)),
+        env::var(&secret_var).unwrap_or_else(|_| panic!("{} must be set", secret_var)),
+    );
+
+    // secrets rotated away from in the partner dashboard, still accepted
+    // for anything shopify signed before the switch
+    app.previous_api_secrets = env::var(format!("API_PREVIOUS_SECRETS_SHOPIFY{}", suffix))
+        .unwrap_or_default()
+        .split(',')
+        .map(|secret| secret.trim().to_string())
+        .filter(|secret| !secret.is_empty())
+        .collect();
+
+    app
 }
@@ -0,0 +1,382 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::convert::Infallible;
+use std::fmt;
+use warp::{http::StatusCode, Rejection, Reply};
+
+// everything that can go wrong while a shop is installing our app
+#[derive(Debug)]
+pub enum OAuthError {
+    UnknownApp,
+    InvalidRequest,
+    InvalidShop,
+    InvalidHmac,
+    StaleTimestamp,
+    UnknownNonce,
+    NonceExpired,
+    NonceAlreadyUsed,
+    MissingScopes,
+    AccessTokenRequest(reqwest::Error),
+    Database(diesel::result::Error),
+}
+
+impl warp::reject::Reject for OAuthError {}
+
+impl OAuthError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            OAuthError::UnknownApp => StatusCode::NOT_FOUND,
+            OAuthError::InvalidRequest => StatusCode::BAD_REQUEST,
+            OAuthError::InvalidShop => StatusCode::BAD_REQUEST,
+            OAuthError::InvalidHmac => StatusCode::UNAUTHORIZED,
+            OAuthError::StaleTimestamp => StatusCode::UNAUTHORIZED,
+            OAuthError::UnknownNonce => StatusCode::FORBIDDEN,
+            OAuthError::NonceExpired => StatusCode::FORBIDDEN,
+            OAuthError::NonceAlreadyUsed => StatusCode::FORBIDDEN,
+            OAuthError::MissingScopes => StatusCode::FORBIDDEN,
+            OAuthError::AccessTokenRequest(_) => StatusCode::BAD_GATEWAY,
+            OAuthError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            OAuthError::UnknownApp => "Could not find app",
+            OAuthError::InvalidRequest => "Could not read request",
+            OAuthError::InvalidShop => "Could not validate shop uri",
+            OAuthError::InvalidHmac => "Could not verify request",
+            OAuthError::StaleTimestamp => "Request has expired",
+            OAuthError::UnknownNonce => "Could not find shop and nonce",
+            OAuthError::NonceExpired => "Install took too long, please try again",
+            OAuthError::NonceAlreadyUsed => "Install has already been confirmed",
+            OAuthError::MissingScopes => "Shop did not grant the permissions we need",
+            OAuthError::AccessTokenRequest(_) => "Could not fetch access token",
+            OAuthError::Database(_) => "Could not save shop",
+        }
+    }
+}
+
+impl From<diesel::result::Error> for OAuthError {
+    fn from(err: diesel::result::Error) -> Self {
+        OAuthError::Database(err)
+    }
+}
+
+impl From<reqwest::Error> for OAuthError {
+    fn from(err: reqwest::Error) -> Self {
+        OAuthError::AccessTokenRequest(err)
+    }
+}
+
+// everything that can go wrong checking an app bridge session token
+#[derive(Debug, PartialEq)]
+pub enum SessionError {
+    MissingToken,
+    Malformed,
+    InvalidSignature,
+    Expired,
+    NotYetValid,
+    WrongAudience,
+    InvalidShop,
+    WrongIssuer,
+    UnknownShop,
+    MissingScopes,
+    TokenExchangeFailed,
+    Database,
+}
+
+impl warp::reject::Reject for SessionError {}
+
+impl SessionError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            SessionError::MissingScopes => StatusCode::FORBIDDEN,
+            SessionError::TokenExchangeFailed => StatusCode::BAD_GATEWAY,
+            SessionError::Database => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            SessionError::MissingToken => "Missing session token",
+            SessionError::Malformed => "Could not read session token",
+            SessionError::InvalidSignature => "Could not verify session token",
+            SessionError::Expired => "Session token has expired",
+            SessionError::NotYetValid => "Session token is not valid yet",
+            SessionError::WrongAudience => "Session token is for another app",
+            SessionError::InvalidShop => "Session token is not for a shop",
+            SessionError::WrongIssuer => "Session token was issued by another shop",
+            SessionError::UnknownShop => "Shop has not installed the app",
+            SessionError::MissingScopes => "Shop did not grant the permissions we need",
+            SessionError::TokenExchangeFailed => "Could not exchange session token",
+            SessionError::Database => "Could not save session",
+        }
+    }
+}
+
+// everything that can go wrong taking in a webhook from shopify
+#[derive(Debug)]
+pub enum WebhookError {
+    UnknownApp,
+    InvalidHmac,
+    MissingHeader(&'static str),
+    InvalidPayload(serde_json::Error),
+    Database(diesel::result::Error),
+}
+
+impl warp::reject::Reject for WebhookError {}
+
+impl WebhookError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            WebhookError::UnknownApp => StatusCode::NOT_FOUND,
+            WebhookError::InvalidHmac => StatusCode::UNAUTHORIZED,
+            WebhookError::MissingHeader(_) => StatusCode::BAD_REQUEST,
+            WebhookError::InvalidPayload(_) => StatusCode::BAD_REQUEST,
+            // anything but a 2xx and shopify will try again later
+            WebhookError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            WebhookError::UnknownApp => "Could not find app",
+            WebhookError::InvalidHmac => "Could not verify webhook",
+            WebhookError::MissingHeader(_) => "Missing webhook header",
+            WebhookError::InvalidPayload(_) => "Could not read webhook payload",
+            WebhookError::Database(_) => "Could not process webhook",
+        }
+    }
+}
+
+impl From<serde_json::Error> for WebhookError {
+    fn from(err: serde_json::Error) -> Self {
+        WebhookError::InvalidPayload(err)
+    }
+}
+
+impl From<diesel::result::Error> for WebhookError {
+    fn from(err: diesel::result::Error) -> Self {
+        WebhookError::Database(err)
+    }
+}
+
+// shopify's top level graphql errors, e.x.
+// { "errors": [{ "message": "Throttled", "extensions": { "code": "THROTTLED" } }] }
+#[derive(Debug, Deserialize)]
+pub struct GraphqlError {
+    pub message: String,
+    #[serde(default)]
+    pub extensions: Option<Value>,
+}
+
+impl GraphqlError {
+    pub fn is_throttled(&self) -> bool {
+        self.extensions
+            .as_ref()
+            .and_then(|extensions| extensions["code"].as_str())
+            == Some("THROTTLED")
+    }
+}
+
+// returned inside a mutation's payload, e.x. { "giftCardCreate": { "userErrors": [ ... ] } }
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct UserError {
+    pub field: Option<Vec<String>>,
+    pub message: String,
+}
+
+// everything that can go wrong calling a shop's admin api
+#[derive(Debug)]
+pub enum AdminApiError {
+    UnknownApp,
+    MissingAccessToken,
+    Encryption(EncryptionError),
+    Unauthorized,
+    NotFound,
+    // shopify wants us to back off for this many seconds
+    Throttled(Option<f64>),
+    Status(StatusCode, String),
+    Graphql(Vec<GraphqlError>),
+    UserErrors(Vec<UserError>),
+    Request(reqwest::Error),
+    Decode(serde_json::Error),
+}
+
+impl fmt::Display for AdminApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminApiError::UnknownApp => write!(f, "could not find app"),
+            AdminApiError::MissingAccessToken => write!(f, "shop has no access token"),
+            AdminApiError::Encryption(e) => write!(f, "could not open access token: {}", e),
+            AdminApiError::Unauthorized => write!(f, "access token was rejected"),
+            AdminApiError::NotFound => write!(f, "resource not found"),
+            AdminApiError::Throttled(Some(secs)) => write!(f, "throttled, retry in {}s", secs),
+            AdminApiError::Throttled(None) => write!(f, "throttled"),
+            AdminApiError::Status(status, body) => write!(f, "{}: {}", status, body),
+            AdminApiError::Graphql(errors) => write!(
+                f,
+                "graphql errors: {}",
+                errors
+                    .iter()
+                    .map(|e| e.message.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            AdminApiError::UserErrors(errors) => write!(
+                f,
+                "user errors: {}",
+                errors
+                    .iter()
+                    .map(|e| e.message.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            AdminApiError::Request(e) => write!(f, "{}", e),
+            AdminApiError::Decode(e) => write!(f, "could not read response: {}", e),
+        }
+    }
+}
+
+impl From<EncryptionError> for AdminApiError {
+    fn from(err: EncryptionError) -> Self {
+        AdminApiError::Encryption(err)
+    }
+}
+
+impl From<reqwest::Error> for AdminApiError {
+    fn from(err: reqwest::Error) -> Self {
+        AdminApiError::Request(err)
+    }
+}
+
+impl From<serde_json::Error> for AdminApiError {
+    fn from(err: serde_json::Error) -> Self {
+        AdminApiError::Decode(err)
+    }
+}
+
+// access tokens we can't get back out of the database
+#[derive(Debug, PartialEq)]
+pub enum EncryptionError {
+    UnknownKey(String),
+    Malformed,
+    Tampered,
+}
+
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptionError::UnknownKey(id) => write!(f, "no key with id {}", id),
+            EncryptionError::Malformed => write!(f, "malformed ciphertext or key"),
+            EncryptionError::Tampered => write!(f, "ciphertext failed authentication"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorMessage {
+    code: u16,
+    message: String,
+}
+
+// turn any rejection into a json body with a matching status code
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let (code, message) = if err.is_not_found() {
+        (StatusCode::NOT_FOUND, String::from("Not found"))
+    } else if let Some(e) = err.find::<OAuthError>() {
+        log::warn!("oauth error: {:?}", e);
+        (e.status_code(), String::from(e.message()))
+    } else if let Some(e) = err.find::<SessionError>() {
+        (e.status_code(), String::from(e.message()))
+    } else if let Some(e) = err.find::<WebhookError>() {
+        log::warn!("webhook error: {:?}", e);
+        (e.status_code(), String::from(e.message()))
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            String::from("Method not allowed"),
+        )
+    } else {
+        log::error!("unhandled rejection: {:?}", err);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            String::from("Internal server error"),
+        )
+    };
+
+    let json = warp::reply::json(&ErrorMessage {
+        code: code.as_u16(),
+        message,
+    });
+
+    Ok(warp::reply::with_status(json, code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn status_for(err: OAuthError) -> StatusCode {
+        handle_rejection(warp::reject::custom(err))
+            .await
+            .unwrap()
+            .into_response()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn it_maps_each_oauth_error_to_a_status_code() {
+        assert_eq!(
+            status_for(OAuthError::InvalidShop).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status_for(OAuthError::InvalidHmac).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status_for(OAuthError::StaleTimestamp).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status_for(OAuthError::UnknownNonce).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status_for(OAuthError::NonceExpired).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status_for(OAuthError::NonceAlreadyUsed).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status_for(OAuthError::Database(diesel::result::Error::NotFound)).await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn it_maps_session_errors_to_401() {
+        let res = handle_rejection(warp::reject::custom(SessionError::Expired))
+            .await
+            .unwrap()
+            .into_response();
+
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn it_maps_not_found_to_404() {
+        let res = handle_rejection(warp::reject::not_found())
+            .await
+            .unwrap()
+            .into_response();
+
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
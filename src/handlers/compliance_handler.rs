@@ -0,0 +1,220 @@
+use crate::{
+    db_conn::DbConn,
+    errors::WebhookError,
+    models::{
+        compliance_request::{self, NewComplianceRequest},
+        job::{self, Job},
+        shopify_connection, shopify_online_token, webhook_subscription,
+    },
+    webhooks::Webhook,
+};
+use diesel::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+// e.x. {
+//   "shop_id": 954889,
+//   "shop_domain": "some-shop.myshopify.com",
+//   "orders_requested": [299938, 280263],
+//   "customer": { "id": 191167, "email": "john@example.com", "phone": "555-625-1199" },
+//   "data_request": { "id": 9999 }
+// }
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CustomersDataRequestPayload {
+    pub shop_id: i64,
+    pub shop_domain: String,
+    #[serde(default)]
+    pub orders_requested: Vec<i64>,
+    pub customer: Customer,
+    pub data_request: DataRequest,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CustomersRedactPayload {
+    pub shop_id: i64,
+    pub shop_domain: String,
+    pub customer: Customer,
+    #[serde(default)]
+    pub orders_to_redact: Vec<i64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ShopRedactPayload {
+    pub shop_id: i64,
+    pub shop_domain: String,
+}
+
+// only the id is ever written down, the rest is for finding what we hold
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Customer {
+    pub id: i64,
+    #[serde(skip_serializing)]
+    pub email: Option<String>,
+    #[serde(skip_serializing)]
+    pub phone: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DataRequest {
+    pub id: i64,
+}
+
+// the shop owner wants to see what we hold on one of their customers,
+// we gather it up and keep it with the request for them to collect
+pub fn customers_data_request(webhook: &Webhook, db_conn: &DbConn) -> Result<(), WebhookError> {
+    let payload: CustomersDataRequestPayload = serde_json::from_slice(&webhook.body)?;
+    let conn = db_conn.get_conn();
+
+    let compliance_request = new_compliance_request(webhook, &payload)?
+        .for_customer(payload.customer.id)
+        .insert(&conn)?;
+
+    // nothing is stored per customer yet, gift card holders
+    // will be gathered here once we keep them
+    let export = json!({
+        "data_request_id": payload.data_request.id,
+        "customer": payload.customer,
+        "orders_requested": payload.orders_requested,
+        "records": [],
+    });
+
+    compliance_request::complete(&conn, &compliance_request, export.to_string())?;
+    Ok(())
+}
+
+// erase everything we hold on one of the shop's customers
+pub fn customers_redact(webhook: &Webhook, db_conn: &DbConn) -> Result<(), WebhookError> {
+    let payload: CustomersRedactPayload = serde_json::from_slice(&webhook.body)?;
+    let conn = db_conn.get_conn();
+
+    let compliance_request = new_compliance_request(webhook, &payload)?
+        .for_customer(payload.customer.id)
+        .insert(&conn)?;
+
+    // earlier requests about the customer and any webhook still queued
+    // or dead with their details in it, gift card holders will join once we keep them
+    let (compliance_requests, jobs) = conn.transaction::<_, diesel::result::Error, _>(|| {
+        let compliance_requests = compliance_request::delete_by_customer(
+            &conn,
+            webhook.shop_domain.clone(),
+            payload.customer.id,
+            &compliance_request,
+        )?;
+        let mentioning = job::read_waiting_by_app_and_shop(
+            &conn,
+            webhook.app.clone(),
+            webhook.shop_domain.clone(),
+        )?
+        .into_iter()
+        .filter(|job| mentions_customer(job, &payload.customer))
+        .map(|job| job.id)
+        .collect();
+
+        Ok((compliance_requests, job::delete_waiting(&conn, mentioning)?))
+    })?;
+
+    let result = json!({
+        "customer_id": payload.customer.id,
+        "orders_to_redact": payload.orders_to_redact,
+        "compliance_requests": compliance_requests,
+        "jobs": jobs,
+    });
+
+    compliance_request::complete(&conn, &compliance_request, result.to_string())?;
+    Ok(())
+}
+
+// sent a couple of days after a shop uninstalls the app, everything we
+// have on the shop for that app goes except the record that we were asked to
+pub fn shop_redact(webhook: &Webhook, db_conn: &DbConn) -> Result<(), WebhookError> {
+    let payload: ShopRedactPayload = serde_json::from_slice(&webhook.body)?;
+    let conn = db_conn.get_conn();
+
+    let compliance_request = new_compliance_request(webhook, &payload)?.insert(&conn)?;
+
+    let (online_tokens, subscriptions, jobs, connections) = conn
+        .transaction::<_, diesel::result::Error, _>(|| {
+            Ok((
+                shopify_online_token::delete_by_app_and_shop(
+                    &conn,
+                    webhook.app.clone(),
+                    payload.shop_domain.clone(),
+                )?,
+                webhook_subscription::delete_by_app_and_shop(
+                    &conn,
+                    webhook.app.clone(),
+                    payload.shop_domain.clone(),
+                )?,
+                job::cancel_pending_by_app_and_shop(
+                    &conn,
+                    webhook.app.clone(),
+                    payload.shop_domain.clone(),
+                )?,
+                shopify_connection::delete_by_app_and_shop(
+                    &conn,
+                    webhook.app.clone(),
+                    payload.shop_domain.clone(),
+                )?,
+            ))
+        })?;
+
+    let result = json!({
+        "jobs": jobs,
+        "shopify_connections": connections,
+        "shopify_online_tokens": online_tokens,
+        "webhook_subscriptions": subscriptions,
+    });
+
+    compliance_request::complete(&conn, &compliance_request, result.to_string())?;
+    Ok(())
+}
+
+// the payload is kept minus anything that identifies a person
+fn new_compliance_request<T: Serialize>(
+    webhook: &Webhook,
+    payload: &T,
+) -> Result<NewComplianceRequest, WebhookError> {
+    Ok(NewComplianceRequest::new(
+        webhook.shop_domain.clone(),
+        webhook.topic.clone(),
+        webhook.webhook_id.clone(),
+        serde_json::to_string(payload)?,
+    ))
+}
+
+// e.x. an orders/paid webhook has the buyer under "customer",
+// a customers/update one is the customer itself
+fn mentions_customer(job: &Job, customer: &Customer) -> bool {
+    let webhook = match Webhook::from_payload(&job.payload) {
+        Ok(webhook) => webhook,
+        Err(_) => return false,
+    };
+    let body: Value = match serde_json::from_slice(&webhook.body) {
+        Ok(body) => body,
+        Err(_) => return false,
+    };
+
+    (webhook.topic.starts_with("customers/") && body.get("id") == Some(&json!(customer.id)))
+        || contains_customer(&body, customer)
+}
+
+fn contains_customer(value: &Value, customer: &Customer) -> bool {
+    match value {
+        Value::Object(fields) => {
+            fields
+                .get("customer")
+                .and_then(|found| found.get("id"))
+                .and_then(Value::as_i64)
+                == Some(customer.id)
+                || fields
+                    .values()
+                    .any(|field| contains_customer(field, customer))
+        }
+        Value::Array(items) => items.iter().any(|item| contains_customer(item, customer)),
+        Value::String(text) => {
+            customer.email.as_deref() == Some(text.as_str())
+                || customer.phone.as_deref() == Some(text.as_str())
+        }
+        _ => false,
+    }
+}
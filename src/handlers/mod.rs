@@ -1 +1,4 @@
-pub mod shopify_handler;
+pub mod compliance_handler;
+pub mod platform_handler;
+pub mod session_handler;
+pub mod webhook_handler;
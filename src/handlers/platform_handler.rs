@@ -0,0 +1,112 @@
+use crate::{
+    db_conn::DbConn,
+    errors::WebhookError,
+    models::{job::NewJob, processed_webhook::NewProcessedWebhook},
+    platforms::{Callback, CallbackReply, CommercePlatform},
+    webhooks::{Webhook, WebhookRegistry},
+};
+use diesel::prelude::*;
+use std::sync::Arc;
+use warp::{
+    http::{HeaderMap, StatusCode},
+    hyper::body::Bytes,
+    Reply,
+};
+
+// the merchant asked to install us, send them off to approve it
+pub async fn install(
+    platform: Arc<dyn CommercePlatform>,
+    app: Option<String>,
+    raw_query: String,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let store = platform
+        .verify_install(app.as_deref(), &raw_query)
+        .map_err(warp::reject::custom)?;
+
+    let authorize_url = platform
+        .authorize_url(app.as_deref(), &store)
+        .map_err(warp::reject::custom)?;
+
+    Ok(warp::redirect(authorize_url))
+}
+
+// the platform is back with the merchant's approval
+pub async fn confirm(
+    platform: Arc<dyn CommercePlatform>,
+    app: Option<String>,
+    callback: Callback,
+) -> Result<Box<dyn Reply>, warp::Rejection> {
+    let authorization = platform
+        .verify_callback(app.as_deref(), &callback)
+        .map_err(warp::reject::custom)?;
+
+    let reply = platform
+        .exchange_token(app.as_deref(), authorization)
+        .await
+        .map_err(warp::reject::custom)?;
+
+    Ok(match reply {
+        CallbackReply::Redirect(uri) => Box::new(warp::redirect(uri)),
+        CallbackReply::Accepted => Box::new(StatusCode::OK),
+    })
+}
+
+pub async fn webhook(
+    platform: Arc<dyn CommercePlatform>,
+    app: Option<String>,
+    headers: HeaderMap,
+    body: Bytes,
+    db_conn: Arc<DbConn>,
+    registry: Arc<WebhookRegistry>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let webhook = platform
+        .verify_webhook(app.as_deref(), &headers, body)
+        .map_err(warp::reject::custom)?;
+
+    if !registry.handles(&webhook.topic) {
+        log::info!(
+            "no handler for {} webhook from {}",
+            webhook.topic,
+            webhook.shop_domain
+        );
+        return Ok(StatusCode::OK);
+    }
+
+    enqueue_once(platform.webhook_job(), &webhook, &db_conn.get_conn())
+        .map_err(|e| warp::reject::custom(WebhookError::from(e)))?;
+
+    Ok(StatusCode::OK)
+}
+
+// platforms only wait a few seconds for us, the handler runs later from
+// the job queue. a redelivery of something we've already queued is
+// acknowledged without queueing it again
+fn enqueue_once(kind: &str, webhook: &Webhook, conn: &PgConnection) -> QueryResult<()> {
+    conn.transaction(|| {
+        let claimed = NewProcessedWebhook::new(
+            webhook.shop_domain.clone(),
+            webhook.webhook_id.clone(),
+            webhook.topic.clone(),
+        )
+        .claim(conn)?;
+
+        if claimed {
+            NewJob::new(
+                kind.to_string(),
+                webhook.app.clone(),
+                webhook.shop_domain.clone(),
+                webhook.to_payload(),
+            )
+            .enqueue(conn)?;
+        } else {
+            log::info!(
+                "already queued {} webhook {} from {}",
+                webhook.topic,
+                webhook.webhook_id,
+                webhook.shop_domain
+            );
+        }
+
+        Ok(())
+    })
+}
@@ -0,0 +1,184 @@
+use crate::{
+    config::{AccessMode, Config, ShopifyApp},
+    db_conn::DbConn,
+    errors::SessionError,
+    models::{
+        shopify_connection::{self, NewShopifyConnection, ShopifyConnection},
+        shopify_online_token::{self, NewShopifyOnlineToken, OnlineTokenLookup},
+    },
+    services::{rate_limiter::RateLimiter, shopify_service, webhook_subscription_service},
+    session_token::{self, ShopSession},
+    utils::{gen_uuid, now_timestamp},
+    verification::missing_scopes,
+};
+use reqwest::Client;
+use std::sync::Arc;
+
+// app bridge sends its session token along with every request
+// as "Authorization: Bearer {jwt}"
+pub async fn authenticate(
+    authorization: Option<String>,
+    config: Arc<Config>,
+    db_conn: Arc<DbConn>,
+    client: Arc<Client>,
+    rate_limiter: Arc<RateLimiter>,
+) -> Result<ShopSession, warp::Rejection> {
+    let token = authorization
+        .as_ref()
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .ok_or_else(|| warp::reject::custom(SessionError::MissingToken))?;
+
+    // whichever of our apps the token was issued for
+    let app = session_token::audience(token)
+        .and_then(|api_key| {
+            config
+                .shopify_app_by_api_key(&api_key)
+                .ok_or(SessionError::WrongAudience)
+        })
+        .map_err(warp::reject::custom)?;
+
+    let claims =
+        session_token::decode_with_secrets(token, &app.secrets(), &app.api_key, now_timestamp())
+            .map_err(warp::reject::custom)?;
+
+    // the token is only good for shops that actually have the app installed,
+    // if we don't have an access token yet shopify can trade us one for it.
+    // online tokens expire, so the staff member's own one has to still be good
+    let connection = match shopify_connection::read_installed_by_app_and_shop(
+        &db_conn.get_conn(),
+        app.handle.clone(),
+        claims.shop().to_string(),
+    ) {
+        Some(connection)
+            if has_valid_token(&config, &db_conn, app, claims.shop(), claims.user_id()) =>
+        {
+            connection
+        }
+        _ => exchange_session_token(
+            token,
+            claims.shop(),
+            &config,
+            app,
+            &db_conn,
+            client,
+            rate_limiter,
+        )
+        .await
+        .map_err(warp::reject::custom)?,
+    };
+
+    Ok(ShopSession {
+        shop: claims.shop().to_string(),
+        user_id: claims.user_id(),
+        session_id: claims.sid,
+        connection,
+    })
+}
+
+fn has_valid_token(
+    config: &Config,
+    db_conn: &DbConn,
+    app: &ShopifyApp,
+    shop: &str,
+    user_id: Option<i64>,
+) -> bool {
+    match (&config.shopify_access_mode, user_id) {
+        (AccessMode::Online, Some(user_id)) => matches!(
+            shopify_online_token::lookup_valid(
+                &db_conn.get_conn(),
+                app.handle.clone(),
+                shop.to_string(),
+                user_id
+            ),
+            OnlineTokenLookup::Valid(_)
+        ),
+        _ => true,
+    }
+}
+
+async fn exchange_session_token(
+    token: &str,
+    shop: &str,
+    config: &Config,
+    app: &ShopifyApp,
+    db_conn: &DbConn,
+    client: Arc<Client>,
+    rate_limiter: Arc<RateLimiter>,
+) -> Result<ShopifyConnection, SessionError> {
+    let access_token_json = shopify_service::exchange_session_token(
+        client.clone(),
+        &rate_limiter,
+        config,
+        app,
+        shop,
+        token.to_string(),
+    )
+    .await
+    .map_err(|e| {
+        log::warn!("could not exchange session token for {}: {:?}", shop, e);
+        match e.status() {
+            // shopify won't hand out a token for a shop that doesn't have us installed
+            Some(status) if status.is_client_error() => SessionError::UnknownShop,
+            _ => SessionError::TokenExchangeFailed,
+        }
+    })?;
+
+    // there's no authorize step to send the shop back to from here,
+    // it has to go through the install again to grant the rest
+    let missing = missing_scopes(&config.shopify_scopes, &access_token_json.scope);
+    if !missing.is_empty() {
+        log::warn!("{} did not grant {}", shop, missing.join(","));
+        return Err(SessionError::MissingScopes);
+    }
+
+    let conn = db_conn.get_conn();
+    let database_error = |e: diesel::result::Error| {
+        log::error!("could not save the session for {}: {:?}", shop, e);
+        SessionError::Database
+    };
+
+    // online access tokens belong to the staff member, only offline ones are the shop's
+    let new_connection = match NewShopifyOnlineToken::from_access_token_response(
+        &config.token_cipher(),
+        &app.handle,
+        shop,
+        &access_token_json,
+    ) {
+        Some(online_token) => {
+            online_token.upsert(&conn).map_err(database_error)?;
+            NewShopifyConnection::installed_online(
+                shop.to_string(),
+                gen_uuid(),
+                access_token_json.scope,
+            )
+        }
+        None => NewShopifyConnection::installed(
+            &config.token_cipher(),
+            shop.to_string(),
+            gen_uuid(),
+            access_token_json.access_token.clone(),
+            access_token_json.scope,
+        ),
+    };
+
+    let connection = new_connection
+        .for_app(&app.handle)
+        .upsert_installed(&conn)
+        .map_err(database_error)?;
+
+    // a request that raced us here already did this
+    if connection.updated_at.is_none() {
+        webhook_subscription_service::sync_subscriptions(
+            client,
+            rate_limiter,
+            config,
+            app,
+            db_conn,
+            shop,
+            &access_token_json.access_token,
+        )
+        .await;
+    }
+
+    Ok(connection)
+}
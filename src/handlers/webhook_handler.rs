@@ -0,0 +1,40 @@
+use crate::{
+    db_conn::DbConn,
+    errors::WebhookError,
+    models::{job, shopify_connection, shopify_online_token, webhook_subscription},
+    webhooks::Webhook,
+};
+use diesel::prelude::*;
+
+// the shop's token stops working the moment this arrives,
+// so everything we kept for it on that app goes in one go
+pub fn app_uninstalled(webhook: &Webhook, db_conn: &DbConn) -> Result<(), WebhookError> {
+    let conn = db_conn.get_conn();
+
+    let deactivated = conn.transaction::<_, diesel::result::Error, _>(|| {
+        shopify_online_token::delete_by_app_and_shop(
+            &conn,
+            webhook.app.clone(),
+            webhook.shop_domain.clone(),
+        )?;
+        webhook_subscription::delete_by_app_and_shop(
+            &conn,
+            webhook.app.clone(),
+            webhook.shop_domain.clone(),
+        )?;
+        job::cancel_pending_by_app_and_shop(
+            &conn,
+            webhook.app.clone(),
+            webhook.shop_domain.clone(),
+        )?;
+        shopify_connection::deactivate_shop(&conn, webhook.app.clone(), webhook.shop_domain.clone())
+    })?;
+
+    log::info!(
+        "{} uninstalled, deactivated {} connections",
+        webhook.shop_domain,
+        deactivated
+    );
+
+    Ok(())
+}
@@ -1,20 +1,29 @@
 #![cfg_attr(test, feature(proc_macro_hygiene))]
 
-pub mod api;
 pub mod config;
 pub mod db_conn;
+pub mod encryption;
+pub mod errors;
 pub mod handlers;
 pub mod models;
+pub mod platforms;
 pub mod routes;
 pub mod schema;
 pub mod services;
+pub mod session_token;
 pub mod utils;
+pub mod verification;
+pub mod webhooks;
+pub mod workers;
 
 #[macro_use]
 extern crate diesel;
 extern crate dotenv;
 
-use crate::{config::Config, db_conn::DbConn};
+use crate::{
+    config::Config, db_conn::DbConn, platforms::CommercePlatform,
+    services::rate_limiter::RateLimiter, webhooks::WebhookRegistry,
+};
 use diesel::prelude::*;
 use dotenv::dotenv;
 use reqwest::Client;
@@ -44,6 +53,19 @@ pub struct ConfirmQueryParams {
 pub struct AccessTokenResponse {
     access_token: String,
     scope: String,
+    // only sent back for online (per-user) access mode
+    expires_in: Option<i64>,
+    associated_user_scope: Option<String>,
+    associated_user: Option<AssociatedUser>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AssociatedUser {
+    id: i64,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    email: Option<String>,
+    account_owner: bool,
 }
 
 pub fn with_config(config: Arc<Config>) -> warp::filters::BoxedFilter<(Arc<Config>,)> {
@@ -58,6 +80,24 @@ pub fn with_reqwest_client(client: Arc<Client>) -> warp::filters::BoxedFilter<(A
     warp::any().map(move || client.clone()).boxed()
 }
 
+pub fn with_rate_limiter(
+    rate_limiter: Arc<RateLimiter>,
+) -> warp::filters::BoxedFilter<(Arc<RateLimiter>,)> {
+    warp::any().map(move || rate_limiter.clone()).boxed()
+}
+
+pub fn with_webhook_registry(
+    registry: Arc<WebhookRegistry>,
+) -> warp::filters::BoxedFilter<(Arc<WebhookRegistry>,)> {
+    warp::any().map(move || registry.clone()).boxed()
+}
+
+pub fn with_platform(
+    platform: Arc<dyn CommercePlatform>,
+) -> warp::filters::BoxedFilter<(Arc<dyn CommercePlatform>,)> {
+    warp::any().map(move || platform.clone()).boxed()
+}
+
 pub fn establish_connection() -> PgConnection {
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
     PgConnection::establish(&database_url).expect(&format!("Error connecting to {}", database_url))
@@ -1,22 +1,103 @@
 use rust_oauth2_study::{
-    config::Config, db_conn::DbConn, handlers::shopify_handler, routes::shopify_route,
+    config::Config,
+    db_conn::DbConn,
+    errors::handle_rejection,
+    handlers::{compliance_handler, webhook_handler},
+    models::{shopify_connection, shopify_online_token},
+    platforms::{
+        shopify::ShopifyPlatform,
+        woocommerce::{self, WooCommercePlatform},
+        PlatformRegistry,
+    },
+    routes::platform_route,
+    services::rate_limiter::RateLimiter,
+    webhooks::{self, WebhookJobs, WebhookRegistry},
+    workers::{job_runner, nonce_sweeper, processed_webhook_sweeper, webhook_subscription_retrier},
 };
 use std::net::SocketAddr;
 use std::sync::Arc;
 use warp::Filter;
 
-pub mod api;
-
 #[tokio::main]
 async fn main() {
     let config = Arc::new(Config::new(false));
     let db_conn = Arc::new(DbConn::new(&config.db_path));
     let client = Arc::new(reqwest::Client::new());
+    let rate_limiter = Arc::new(RateLimiter::new(&config));
+
+    // tokens saved before they were sealed can't be read any more, seal
+    // them before anything goes looking for one
+    let cipher = config.token_cipher();
+    let sealed = shopify_connection::seal_plaintext_access_tokens(&db_conn.get_conn(), &cipher)
+        .expect("Error sealing plaintext access tokens")
+        + shopify_online_token::seal_plaintext_access_tokens(&db_conn.get_conn(), &cipher)
+            .expect("Error sealing plaintext online access tokens");
+    if sealed > 0 {
+        println!(
+            "sealed {} access tokens that were saved as plaintext",
+            sealed
+        );
+    }
+    let webhook_jobs = Arc::new(
+        WebhookJobs::new()
+            .register(
+                webhooks::WEBHOOK_JOB,
+                WebhookRegistry::new()
+                    .register(webhooks::APP_UNINSTALLED, webhook_handler::app_uninstalled)
+                    .register(
+                        webhooks::CUSTOMERS_DATA_REQUEST,
+                        compliance_handler::customers_data_request,
+                    )
+                    .register(
+                        webhooks::CUSTOMERS_REDACT,
+                        compliance_handler::customers_redact,
+                    )
+                    .register(webhooks::SHOP_REDACT, compliance_handler::shop_redact),
+            )
+            // nothing handles woocommerce topics yet, see WOOCOMMERCE_WEBHOOK_TOPICS
+            .register(woocommerce::WEBHOOK_JOB, WebhookRegistry::new()),
+    );
+
+    for _ in 0..config.job_workers {
+        tokio::spawn(job_runner::run(
+            config.clone(),
+            db_conn.clone(),
+            webhook_jobs.clone(),
+        ));
+    }
+    tokio::spawn(nonce_sweeper::run(
+        db_conn.clone(),
+        config.nonce_sweep_interval_secs,
+    ));
+    tokio::spawn(processed_webhook_sweeper::run(
+        db_conn.clone(),
+        config.webhook_sweep_interval_secs,
+        config.processed_webhook_retention_secs,
+    ));
+    tokio::spawn(webhook_subscription_retrier::run(
+        config.clone(),
+        db_conn.clone(),
+        client.clone(),
+        rate_limiter.clone(),
+    ));
+
+    let platforms = PlatformRegistry::new()
+        .register(Arc::new(ShopifyPlatform::new(
+            config.clone(),
+            db_conn.clone(),
+            client.clone(),
+            rate_limiter.clone(),
+        )))
+        .register(Arc::new(WooCommercePlatform::new(
+            config.clone(),
+            db_conn.clone(),
+            client.clone(),
+        )));
 
-    let shopify =
-        shopify!(config.clone(), db_conn.clone(), client.clone()).with(warp::log("shopify"));
+    let routes = platform_route::platform_routes(&platforms, db_conn.clone(), &webhook_jobs)
+        .with(warp::log("platforms"));
 
-    let end = shopify;
+    let end = routes.recover(handle_rejection);
 
     let socket_address = config
         .clone()
@@ -0,0 +1,143 @@
+use crate::schema::compliance_requests;
+use crate::utils::now;
+use chrono::naive::NaiveDateTime;
+use diesel::prelude::*;
+
+// a privacy request shopify passed on to us, kept around
+// as proof of when it came in and when we dealt with it
+#[derive(Debug, Identifiable, Queryable)]
+#[table_name = "compliance_requests"]
+pub struct ComplianceRequest {
+    pub id: i32,
+    pub shop: String,
+    pub topic: String,
+    pub webhook_id: String,
+    pub customer_id: Option<i64>,
+    pub payload: String,
+    pub result: Option<String>,
+    pub received_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[table_name = "compliance_requests"]
+pub struct NewComplianceRequest {
+    pub shop: String,
+    pub topic: String,
+    pub webhook_id: String,
+    pub customer_id: Option<i64>,
+    pub payload: String,
+    pub result: Option<String>,
+    pub received_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+}
+
+impl NewComplianceRequest {
+    pub fn new(shop: String, topic: String, webhook_id: String, payload: String) -> Self {
+        NewComplianceRequest {
+            shop,
+            topic,
+            webhook_id,
+            customer_id: None,
+            payload,
+            result: None,
+            received_at: now(),
+            completed_at: None,
+        }
+    }
+
+    pub fn for_customer(mut self, customer_id: i64) -> Self {
+        self.customer_id = Some(customer_id);
+        self
+    }
+
+    pub fn insert(&self, conn: &PgConnection) -> QueryResult<ComplianceRequest> {
+        create(conn, self)
+    }
+}
+
+pub fn create(
+    conn: &PgConnection,
+    new_compliance_request: &NewComplianceRequest,
+) -> QueryResult<ComplianceRequest> {
+    diesel::insert_into(compliance_requests::table)
+        .values(new_compliance_request)
+        .get_result(conn)
+}
+
+pub fn read_by_shop(conn: &PgConnection, shop: String) -> Vec<ComplianceRequest> {
+    compliance_requests::table
+        .filter(compliance_requests::shop.eq(shop))
+        .order(compliance_requests::received_at.asc())
+        .load::<ComplianceRequest>(conn)
+        .expect("Error loading compliance_request")
+}
+
+// a redacted customer's earlier requests go too, e.x. the export
+// gathered for a data request, leaving only the redaction itself
+pub fn delete_by_customer(
+    conn: &PgConnection,
+    shop: String,
+    customer_id: i64,
+    except: &ComplianceRequest,
+) -> QueryResult<usize> {
+    diesel::delete(
+        compliance_requests::table
+            .filter(compliance_requests::shop.eq(shop))
+            .filter(compliance_requests::customer_id.eq(customer_id))
+            .filter(compliance_requests::id.ne(except.id)),
+    )
+    .execute(conn)
+}
+
+// what we found, exported or erased goes in the result
+pub fn complete(
+    conn: &PgConnection,
+    compliance_request: &ComplianceRequest,
+    result: String,
+) -> QueryResult<ComplianceRequest> {
+    diesel::update(compliance_request)
+        .set((
+            compliance_requests::result.eq(result),
+            compliance_requests::completed_at.eq(now()),
+        ))
+        .get_result(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::establish_connection_test;
+
+    fn cleanup_table(conn: &PgConnection) {
+        diesel::delete(compliance_requests::table)
+            .execute(conn)
+            .unwrap();
+    }
+
+    #[test]
+    fn it_records_and_completes_a_compliance_request() {
+        let conn = establish_connection_test();
+
+        let compliance_request = NewComplianceRequest::new(
+            String::from("ShopName"),
+            String::from("customers/redact"),
+            String::from("b54557e4-bdd9-4b37-8a5f-bf7d70bcd043"),
+            String::from("{}"),
+        )
+        .for_customer(207119551)
+        .insert(&conn)
+        .unwrap();
+        assert!(compliance_request.completed_at.is_none());
+
+        let completed =
+            complete(&conn, &compliance_request, String::from("{\"erased\":0}")).unwrap();
+
+        assert_eq!(completed.id, compliance_request.id);
+        assert!(completed.completed_at.is_some());
+        assert_eq!(completed.result.as_deref(), Some("{\"erased\":0}"));
+        assert_eq!(1, read_by_shop(&conn, String::from("ShopName")).len());
+
+        cleanup_table(&conn);
+    }
+}
@@ -0,0 +1,386 @@
+use crate::schema::jobs;
+use crate::utils::now;
+use chrono::{naive::NaiveDateTime, Duration};
+use diesel::prelude::*;
+
+pub const PENDING: &str = "pending";
+pub const RUNNING: &str = "running";
+pub const DEAD: &str = "dead";
+
+// no matter how many times a job has failed, try it at least this often
+const MAX_RETRY_DELAY_SECS: i64 = 60 * 60;
+
+// work that happens after we've already answered the request that asked for it
+#[derive(Debug, Identifiable, Queryable)]
+#[table_name = "jobs"]
+pub struct Job {
+    pub id: i32,
+    pub kind: String,
+    pub shop: String,
+    pub payload: String,
+    pub status: String,
+    pub attempts: i32,
+    pub run_at: NaiveDateTime,
+    pub locked_at: Option<NaiveDateTime>,
+    pub last_error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: Option<NaiveDateTime>,
+    pub app: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "jobs"]
+pub struct NewJob {
+    pub kind: String,
+    pub shop: String,
+    pub payload: String,
+    pub status: String,
+    pub attempts: i32,
+    pub run_at: NaiveDateTime,
+    pub locked_at: Option<NaiveDateTime>,
+    pub last_error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: Option<NaiveDateTime>,
+    pub app: String,
+}
+
+impl NewJob {
+    pub fn new(kind: String, app: String, shop: String, payload: String) -> Self {
+        let created_at = now();
+
+        NewJob {
+            kind,
+            shop,
+            payload,
+            status: String::from(PENDING),
+            attempts: 0,
+            run_at: created_at,
+            locked_at: None,
+            last_error: None,
+            created_at,
+            updated_at: None,
+            app,
+        }
+    }
+
+    pub fn enqueue(&self, conn: &PgConnection) -> QueryResult<Job> {
+        enqueue(conn, self)
+    }
+}
+
+pub fn enqueue(conn: &PgConnection, new_job: &NewJob) -> QueryResult<Job> {
+    diesel::insert_into(jobs::table)
+        .values(new_job)
+        .get_result(conn)
+}
+
+// takes the next job that is due, skipping any another worker has locked.
+// a claimed job's run_at is pushed out by the lease, if the worker dies
+// holding it the job comes due again once the lease runs out. one that keeps
+// taking its worker down with it is out of attempts like any other and dead
+pub fn claim_next(
+    conn: &PgConnection,
+    lease: Duration,
+    max_attempts: i32,
+) -> QueryResult<Option<Job>> {
+    conn.transaction(|| loop {
+        let current_time = now();
+
+        let next = jobs::table
+            .filter(jobs::status.eq_any(vec![PENDING, RUNNING]))
+            .filter(jobs::run_at.le(current_time))
+            .order(jobs::run_at.asc())
+            .for_update()
+            .skip_locked()
+            .first::<Job>(conn)
+            .optional()?;
+
+        match next {
+            Some(job) if job.status == RUNNING && job.attempts >= max_attempts => {
+                diesel::update(&job)
+                    .set((
+                        jobs::status.eq(DEAD),
+                        jobs::locked_at.eq(None::<NaiveDateTime>),
+                        jobs::last_error.eq(format!(
+                            "lease ran out on attempt {}, the worker never finished it",
+                            job.attempts
+                        )),
+                        jobs::updated_at.eq(current_time),
+                    ))
+                    .execute(conn)?;
+            }
+            Some(job) => {
+                return diesel::update(&job)
+                    .set((
+                        jobs::status.eq(RUNNING),
+                        jobs::attempts.eq(jobs::attempts + 1),
+                        jobs::run_at.eq(current_time + lease),
+                        jobs::locked_at.eq(current_time),
+                        jobs::updated_at.eq(current_time),
+                    ))
+                    .get_result(conn)
+                    .map(Some)
+            }
+            None => return Ok(None),
+        }
+    })
+}
+
+// done jobs have nothing left to tell us
+pub fn complete(conn: &PgConnection, job: &Job) -> QueryResult<usize> {
+    diesel::delete(job).execute(conn)
+}
+
+// back off exponentially, once it's out of attempts the job is left
+// in the table as dead for someone to look at
+pub fn fail(
+    conn: &PgConnection,
+    job: &Job,
+    error: String,
+    max_attempts: i32,
+    base_retry_secs: i64,
+) -> QueryResult<Job> {
+    let current_time = now();
+    let status = if job.attempts >= max_attempts {
+        DEAD
+    } else {
+        PENDING
+    };
+
+    diesel::update(job)
+        .set((
+            jobs::status.eq(status),
+            jobs::run_at.eq(current_time + retry_delay(job.attempts, base_retry_secs)),
+            jobs::locked_at.eq(None::<NaiveDateTime>),
+            jobs::last_error.eq(error),
+            jobs::updated_at.eq(current_time),
+        ))
+        .get_result(conn)
+}
+
+pub fn retry_delay(attempts: i32, base_retry_secs: i64) -> Duration {
+    let exponent = (attempts - 1).clamp(0, 30) as u32;
+    let delay_secs = base_retry_secs.saturating_mul(2i64.saturating_pow(exponent));
+
+    Duration::seconds(delay_secs.min(MAX_RETRY_DELAY_SECS))
+}
+
+pub fn read_dead(conn: &PgConnection) -> Vec<Job> {
+    jobs::table
+        .filter(jobs::status.eq(DEAD))
+        .order(jobs::updated_at.desc())
+        .load::<Job>(conn)
+        .expect("Error loading job")
+}
+
+// nothing still waiting to run for a shop that has left the app,
+// the job doing the cancelling is running so it's left alone. a running
+// job whose lease ran out is only waiting to be picked up again
+pub fn cancel_pending_by_app_and_shop(
+    conn: &PgConnection,
+    app: String,
+    shop: String,
+) -> QueryResult<usize> {
+    diesel::delete(
+        jobs::table
+            .filter(jobs::app.eq(app))
+            .filter(jobs::shop.eq(shop))
+            .filter(jobs::status.ne(RUNNING).or(jobs::run_at.le(now()))),
+    )
+    .execute(conn)
+}
+
+// everything queued or dead for a shop on one app, the running job is left
+// alone unless its lease already ran out
+pub fn read_waiting_by_app_and_shop(
+    conn: &PgConnection,
+    app: String,
+    shop: String,
+) -> QueryResult<Vec<Job>> {
+    jobs::table
+        .filter(jobs::app.eq(app))
+        .filter(jobs::shop.eq(shop))
+        .filter(jobs::status.ne(RUNNING).or(jobs::run_at.le(now())))
+        .order(jobs::id.asc())
+        .load::<Job>(conn)
+}
+
+pub fn delete_waiting(conn: &PgConnection, ids: Vec<i32>) -> QueryResult<usize> {
+    diesel::delete(
+        jobs::table
+            .filter(jobs::id.eq_any(ids))
+            .filter(jobs::status.ne(RUNNING).or(jobs::run_at.le(now()))),
+    )
+    .execute(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::establish_connection_test;
+
+    fn cleanup_table(conn: &PgConnection) {
+        diesel::delete(jobs::table).execute(conn).unwrap();
+    }
+
+    fn mock_struct() -> NewJob {
+        NewJob::new(
+            String::from("shopify_webhook"),
+            String::from("default"),
+            String::from("ShopName"),
+            String::from("{}"),
+        )
+    }
+
+    #[test]
+    fn it_claims_a_due_job_only_once() {
+        let conn = establish_connection_test();
+
+        let mut later = mock_struct();
+        later.run_at = now() + Duration::minutes(5);
+        later.enqueue(&conn).unwrap();
+        let due = mock_struct().enqueue(&conn).unwrap();
+
+        let claimed = claim_next(&conn, Duration::seconds(300), 8)
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.id, due.id);
+        assert_eq!(claimed.status, RUNNING);
+        assert_eq!(claimed.attempts, 1);
+
+        assert!(claim_next(&conn, Duration::seconds(300), 8)
+            .unwrap()
+            .is_none());
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_reclaims_a_job_whose_lease_ran_out() {
+        let conn = establish_connection_test();
+
+        let job = mock_struct().enqueue(&conn).unwrap();
+
+        let claimed = claim_next(&conn, Duration::seconds(-1), 8)
+            .unwrap()
+            .unwrap();
+        let reclaimed = claim_next(&conn, Duration::seconds(300), 8)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(claimed.id, job.id);
+        assert_eq!(reclaimed.id, job.id);
+        assert_eq!(reclaimed.attempts, 2);
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_gives_up_on_a_job_that_keeps_losing_its_lease() {
+        let conn = establish_connection_test();
+
+        let job = mock_struct().enqueue(&conn).unwrap();
+
+        let claimed = claim_next(&conn, Duration::seconds(-1), 2)
+            .unwrap()
+            .unwrap();
+        let reclaimed = claim_next(&conn, Duration::seconds(-1), 2)
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.id, job.id);
+        assert_eq!(reclaimed.attempts, 2);
+
+        // the worker went down with it both times
+        assert!(claim_next(&conn, Duration::seconds(300), 2)
+            .unwrap()
+            .is_none());
+        let dead = read_dead(&conn);
+        assert_eq!(1, dead.len());
+        assert_eq!(dead[0].id, job.id);
+        assert!(dead[0].last_error.is_some());
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_retries_a_failed_job_until_it_is_dead() {
+        let conn = establish_connection_test();
+
+        mock_struct().enqueue(&conn).unwrap();
+
+        let claimed = claim_next(&conn, Duration::seconds(300), 8)
+            .unwrap()
+            .unwrap();
+        let retrying = fail(&conn, &claimed, String::from("boom"), 2, 10).unwrap();
+        assert_eq!(retrying.status, PENDING);
+        assert!(retrying.run_at > now());
+        assert!(claim_next(&conn, Duration::seconds(300), 8)
+            .unwrap()
+            .is_none());
+
+        diesel::update(&retrying)
+            .set(jobs::run_at.eq(now()))
+            .execute(&conn)
+            .unwrap();
+        let claimed = claim_next(&conn, Duration::seconds(300), 8)
+            .unwrap()
+            .unwrap();
+        let dead = fail(&conn, &claimed, String::from("boom again"), 2, 10).unwrap();
+
+        assert_eq!(dead.status, DEAD);
+        assert_eq!(dead.last_error.as_deref(), Some("boom again"));
+        assert!(claim_next(&conn, Duration::seconds(300), 8)
+            .unwrap()
+            .is_none());
+        assert_eq!(1, read_dead(&conn).len());
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_backs_off_exponentially() {
+        assert_eq!(retry_delay(1, 10), Duration::seconds(10));
+        assert_eq!(retry_delay(2, 10), Duration::seconds(20));
+        assert_eq!(retry_delay(4, 10), Duration::seconds(80));
+        assert_eq!(
+            retry_delay(100, 10),
+            Duration::seconds(MAX_RETRY_DELAY_SECS)
+        );
+    }
+
+    #[test]
+    fn it_cancels_a_shops_pending_jobs() {
+        let conn = establish_connection_test();
+
+        mock_struct().enqueue(&conn).unwrap();
+        let running = mock_struct().enqueue(&conn).unwrap();
+        diesel::update(&running)
+            .set((
+                jobs::status.eq(RUNNING),
+                jobs::run_at.eq(now() + Duration::minutes(5)),
+            ))
+            .execute(&conn)
+            .unwrap();
+        // its worker died, nothing is coming back for it
+        let lost = mock_struct().enqueue(&conn).unwrap();
+        diesel::update(&lost)
+            .set((jobs::status.eq(RUNNING), jobs::run_at.eq(now())))
+            .execute(&conn)
+            .unwrap();
+        let mut other_app = mock_struct();
+        other_app.app = String::from("staging");
+        other_app.enqueue(&conn).unwrap();
+
+        assert_eq!(
+            2,
+            cancel_pending_by_app_and_shop(
+                &conn,
+                String::from("default"),
+                String::from("ShopName")
+            )
+            .unwrap()
+        );
+
+        cleanup_table(&conn);
+    }
+}
@@ -1 +1,7 @@
+pub mod compliance_request;
+pub mod job;
+pub mod processed_webhook;
 pub mod shopify_connection;
+pub mod shopify_online_token;
+pub mod webhook_subscription;
+pub mod woocommerce_connection;
@@ -0,0 +1,114 @@
+use crate::schema::processed_webhooks;
+use crate::utils::now;
+use chrono::naive::NaiveDateTime;
+use diesel::prelude::*;
+
+// shopify delivers at least once, every webhook we've queued up is
+// remembered here so a redelivery doesn't get handled twice
+#[derive(Debug, Identifiable, Queryable)]
+#[table_name = "processed_webhooks"]
+pub struct ProcessedWebhook {
+    pub id: i32,
+    pub shop: String,
+    pub webhook_id: String,
+    pub topic: String,
+    pub received_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "processed_webhooks"]
+pub struct NewProcessedWebhook {
+    pub shop: String,
+    pub webhook_id: String,
+    pub topic: String,
+    pub received_at: NaiveDateTime,
+}
+
+impl NewProcessedWebhook {
+    pub fn new(shop: String, webhook_id: String, topic: String) -> Self {
+        NewProcessedWebhook {
+            shop,
+            webhook_id,
+            topic,
+            received_at: now(),
+        }
+    }
+
+    pub fn claim(&self, conn: &PgConnection) -> QueryResult<bool> {
+        claim(conn, self)
+    }
+}
+
+// true if we're the first to see this webhook, the unique key
+// makes sure only one of two racing deliveries gets to handle it
+pub fn claim(
+    conn: &PgConnection,
+    new_processed_webhook: &NewProcessedWebhook,
+) -> QueryResult<bool> {
+    diesel::insert_into(processed_webhooks::table)
+        .values(new_processed_webhook)
+        .on_conflict((processed_webhooks::shop, processed_webhooks::webhook_id))
+        .do_nothing()
+        .execute(conn)
+        .map(|inserted| inserted == 1)
+}
+
+// shopify gives up retrying well before these go
+pub fn delete_received_before(conn: &PgConnection, cutoff: NaiveDateTime) -> QueryResult<usize> {
+    diesel::delete(processed_webhooks::table.filter(processed_webhooks::received_at.lt(cutoff)))
+        .execute(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::establish_connection_test;
+    use chrono::Duration;
+
+    fn cleanup_table(conn: &PgConnection) {
+        diesel::delete(processed_webhooks::table)
+            .execute(conn)
+            .unwrap();
+    }
+
+    fn mock_struct() -> NewProcessedWebhook {
+        NewProcessedWebhook::new(
+            String::from("ShopName"),
+            String::from("b54557e4-bdd9-4b37-8a5f-bf7d70bcd043"),
+            String::from("orders/paid"),
+        )
+    }
+
+    #[test]
+    fn it_claims_a_webhook_only_once() {
+        let conn = establish_connection_test();
+
+        assert!(mock_struct().claim(&conn).unwrap());
+        assert!(!mock_struct().claim(&conn).unwrap());
+
+        let mut other_shop = mock_struct();
+        other_shop.shop = String::from("OtherShopName");
+        assert!(other_shop.claim(&conn).unwrap());
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_deletes_processed_webhooks_past_retention() {
+        let conn = establish_connection_test();
+
+        let mut old = mock_struct();
+        old.received_at = now() - Duration::days(8);
+        old.claim(&conn).unwrap();
+        let mut recent = mock_struct();
+        recent.webhook_id = String::from("another-webhook-id");
+        recent.claim(&conn).unwrap();
+
+        assert_eq!(
+            1,
+            delete_received_before(&conn, now() - Duration::days(7)).unwrap()
+        );
+
+        cleanup_table(&conn);
+    }
+}
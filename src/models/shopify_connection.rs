@@ -1,19 +1,59 @@
+use crate::config::DEFAULT_SHOPIFY_APP;
+use crate::encryption::TokenCipher;
+use crate::errors::EncryptionError;
 use crate::schema::shopify_connections;
 use crate::utils::now;
-use chrono::naive::NaiveDateTime;
+use chrono::{naive::NaiveDateTime, Duration};
 use diesel::prelude::*;
+use diesel::sql_types::{Int4, Nullable, Timestamp, Varchar};
 
-#[derive(Debug, Identifiable, Queryable)]
+// how long a shop has to finish installing before the nonce goes stale
+pub const DEFAULT_NONCE_TTL_SECS: i64 = 600;
+
+#[derive(Debug, Identifiable, Queryable, QueryableByName)]
 #[table_name = "shopify_connections"]
 pub struct ShopifyConnection {
     pub id: i32,
     pub shop: String,
     pub nonce: String,
+    // sealed, see decrypt_access_token
     pub access_token: Option<String>,
     pub created_at: NaiveDateTime,
     pub updated_at: Option<NaiveDateTime>,
     pub deleted_at: Option<NaiveDateTime>,
     pub active: bool,
+    pub nonce_expires_at: NaiveDateTime,
+    pub nonce_consumed_at: Option<NaiveDateTime>,
+    pub scope: Option<String>,
+    pub access_token_data_key: Option<String>,
+    pub access_token_key_id: Option<String>,
+    // handle of the app the shop installed, see Config::shopify_app
+    pub app: String,
+    // how many times in a row the shop was sent back for missing scopes
+    pub scope_prompts: i32,
+    // online installs have no token here, see shopify_online_token
+    pub installed_at: Option<NaiveDateTime>,
+}
+
+impl ShopifyConnection {
+    // rows saved before tokens were sealed are sealed at startup,
+    // see seal_plaintext_access_tokens, so a token without a key id is broken
+    pub fn decrypt_access_token(
+        &self,
+        cipher: &TokenCipher,
+    ) -> Result<Option<String>, EncryptionError> {
+        match (
+            &self.access_token,
+            &self.access_token_data_key,
+            &self.access_token_key_id,
+        ) {
+            (None, _, _) => Ok(None),
+            (Some(ciphertext), Some(data_key), Some(key_id)) => cipher
+                .open(&self.shop, ciphertext, data_key, key_id)
+                .map(Some),
+            _ => Err(EncryptionError::Malformed),
+        }
+    }
 }
 
 #[derive(Insertable)]
@@ -26,6 +66,14 @@ pub struct NewShopifyConnection {
     pub updated_at: Option<NaiveDateTime>,
     pub deleted_at: Option<NaiveDateTime>,
     pub active: bool,
+    pub nonce_expires_at: NaiveDateTime,
+    pub nonce_consumed_at: Option<NaiveDateTime>,
+    pub scope: Option<String>,
+    pub access_token_data_key: Option<String>,
+    pub access_token_key_id: Option<String>,
+    pub app: String,
+    pub scope_prompts: i32,
+    pub installed_at: Option<NaiveDateTime>,
 }
 
 impl NewShopifyConnection {
@@ -38,12 +86,65 @@ impl NewShopifyConnection {
             updated_at: None,
             deleted_at: None,
             active: true,
+            nonce_expires_at: now() + Duration::seconds(DEFAULT_NONCE_TTL_SECS),
+            nonce_consumed_at: None,
+            scope: None,
+            access_token_data_key: None,
+            access_token_key_id: None,
+            app: String::from(DEFAULT_SHOPIFY_APP),
+            scope_prompts: 0,
+            installed_at: None,
         }
     }
 
+    // for shops that got us a token without going through the authorize
+    // redirect, the nonce is never handed out so it starts off used
+    pub fn installed(
+        cipher: &TokenCipher,
+        shop: String,
+        nonce: String,
+        access_token: String,
+        scope: String,
+    ) -> Self {
+        let sealed = cipher.seal(&shop, &access_token);
+        let mut new_shopify_connection = NewShopifyConnection::installed_online(shop, nonce, scope);
+        new_shopify_connection.access_token = Some(sealed.ciphertext);
+        new_shopify_connection.access_token_data_key = Some(sealed.data_key);
+        new_shopify_connection.access_token_key_id = Some(sealed.key_id);
+        new_shopify_connection
+    }
+
+    // the same, but the token went to shopify_online_tokens
+    pub fn installed_online(shop: String, nonce: String, scope: String) -> Self {
+        let mut new_shopify_connection = NewShopifyConnection::new(shop, nonce);
+        new_shopify_connection.scope = Some(scope);
+        new_shopify_connection.nonce_consumed_at = Some(new_shopify_connection.created_at);
+        new_shopify_connection.installed_at = Some(new_shopify_connection.created_at);
+        new_shopify_connection
+    }
+
+    pub fn for_app(mut self, app: &str) -> Self {
+        self.app = app.to_string();
+        self
+    }
+
+    pub fn prompted(mut self, scope_prompts: i32) -> Self {
+        self.scope_prompts = scope_prompts;
+        self
+    }
+
+    pub fn expires_in(mut self, ttl: Duration) -> Self {
+        self.nonce_expires_at = self.created_at + ttl;
+        self
+    }
+
     pub fn insert(&self, conn: &PgConnection) -> ShopifyConnection {
         create(conn, self)
     }
+
+    pub fn upsert_installed(&self, conn: &PgConnection) -> QueryResult<ShopifyConnection> {
+        upsert_installed(conn, self)
+    }
 }
 
 pub fn create(
@@ -56,6 +157,40 @@ pub fn create(
         .expect("Error saving new shopify_connection")
 }
 
+// a shop has one live install per app, see the shopify_connections_installed_app_shop
+// index, so two requests racing to install it end up on the same row. diesel
+// can't name a partial index as the conflict target so this one is raw sql
+pub fn upsert_installed(
+    conn: &PgConnection,
+    new_shopify_connection: &NewShopifyConnection,
+) -> QueryResult<ShopifyConnection> {
+    diesel::sql_query(
+        "INSERT INTO shopify_connections \
+        (shop, nonce, access_token, access_token_data_key, access_token_key_id, scope, app, \
+        scope_prompts, active, created_at, nonce_expires_at, nonce_consumed_at, installed_at) \
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, TRUE, $9, $10, $11, $12) \
+        ON CONFLICT (app, shop) WHERE installed_at IS NOT NULL AND deleted_at IS NULL \
+        DO UPDATE SET access_token = excluded.access_token, \
+        access_token_data_key = excluded.access_token_data_key, \
+        access_token_key_id = excluded.access_token_key_id, \
+        scope = excluded.scope, updated_at = excluded.created_at \
+        RETURNING *",
+    )
+    .bind::<Varchar, _>(&new_shopify_connection.shop)
+    .bind::<Varchar, _>(&new_shopify_connection.nonce)
+    .bind::<Nullable<Varchar>, _>(&new_shopify_connection.access_token)
+    .bind::<Nullable<Varchar>, _>(&new_shopify_connection.access_token_data_key)
+    .bind::<Nullable<Varchar>, _>(&new_shopify_connection.access_token_key_id)
+    .bind::<Nullable<Varchar>, _>(&new_shopify_connection.scope)
+    .bind::<Varchar, _>(&new_shopify_connection.app)
+    .bind::<Int4, _>(new_shopify_connection.scope_prompts)
+    .bind::<Timestamp, _>(new_shopify_connection.created_at)
+    .bind::<Timestamp, _>(new_shopify_connection.nonce_expires_at)
+    .bind::<Nullable<Timestamp>, _>(new_shopify_connection.nonce_consumed_at)
+    .bind::<Nullable<Timestamp>, _>(new_shopify_connection.installed_at)
+    .get_result(conn)
+}
+
 pub fn read(conn: &PgConnection) -> Vec<ShopifyConnection> {
     shopify_connections::table
         .load::<ShopifyConnection>(conn)
@@ -69,6 +204,38 @@ pub fn read_by_shop(conn: &PgConnection, shop: String) -> Vec<ShopifyConnection>
         .expect("Error loading shopify_connection")
 }
 
+// the connection for a shop that finished installing one of our apps
+// and hasn't left, whichever app was installed most recently
+pub fn read_installed_by_shop(conn: &PgConnection, shop: String) -> Option<ShopifyConnection> {
+    shopify_connections::table
+        .filter(shopify_connections::shop.eq(shop))
+        .filter(shopify_connections::installed_at.is_not_null())
+        .filter(shopify_connections::active.eq(true))
+        .filter(shopify_connections::deleted_at.is_null())
+        .order(shopify_connections::updated_at.desc())
+        .first::<ShopifyConnection>(conn)
+        .optional()
+        .expect("Error loading shopify_connection")
+}
+
+// the connection for a shop that finished installing this app and hasn't left
+pub fn read_installed_by_app_and_shop(
+    conn: &PgConnection,
+    app: String,
+    shop: String,
+) -> Option<ShopifyConnection> {
+    shopify_connections::table
+        .filter(shopify_connections::app.eq(app))
+        .filter(shopify_connections::shop.eq(shop))
+        .filter(shopify_connections::installed_at.is_not_null())
+        .filter(shopify_connections::active.eq(true))
+        .filter(shopify_connections::deleted_at.is_null())
+        .order(shopify_connections::updated_at.desc())
+        .first::<ShopifyConnection>(conn)
+        .optional()
+        .expect("Error loading shopify_connection")
+}
+
 pub fn read_by_shop_and_nonce(
     conn: &PgConnection,
     shop: String,
@@ -81,22 +248,281 @@ pub fn read_by_shop_and_nonce(
         .expect("Error loading shopify_connection")
 }
 
+// marks the nonce as used and hands back the pending connection,
+// done in a single statement so two confirms racing can't both win
+pub fn consume_nonce(
+    conn: &PgConnection,
+    shop: String,
+    nonce: String,
+) -> QueryResult<Option<ShopifyConnection>> {
+    let current_time = now();
+
+    diesel::update(
+        shopify_connections::table
+            .filter(shopify_connections::shop.eq(shop))
+            .filter(shopify_connections::nonce.eq(nonce))
+            .filter(shopify_connections::nonce_consumed_at.is_null())
+            .filter(shopify_connections::nonce_expires_at.gt(current_time))
+            .filter(shopify_connections::deleted_at.is_null()),
+    )
+    .set(shopify_connections::nonce_consumed_at.eq(current_time))
+    .get_result::<ShopifyConnection>(conn)
+    .optional()
+}
+
+// hands the nonce back when shopify couldn't give us a token, e.x. it was
+// down for a moment, so the same install can be confirmed again
+pub fn release_nonce(conn: &PgConnection, shop: String, nonce: String) -> QueryResult<usize> {
+    diesel::update(
+        shopify_connections::table
+            .filter(shopify_connections::shop.eq(shop))
+            .filter(shopify_connections::nonce.eq(nonce))
+            .filter(shopify_connections::installed_at.is_null())
+            .filter(shopify_connections::deleted_at.is_null()),
+    )
+    .set(shopify_connections::nonce_consumed_at.eq(None::<NaiveDateTime>))
+    .execute(conn)
+}
+
+// installs that never came back from shopify, soft deleted so
+// the same shop can start over cleanly
+pub fn soft_delete_expired_pending(conn: &PgConnection) -> QueryResult<usize> {
+    let current_time = now();
+
+    diesel::update(
+        shopify_connections::table
+            .filter(shopify_connections::installed_at.is_null())
+            .filter(shopify_connections::deleted_at.is_null())
+            .filter(shopify_connections::nonce_expires_at.le(current_time)),
+    )
+    .set((
+        shopify_connections::active.eq(false),
+        shopify_connections::deleted_at.eq(current_time),
+        shopify_connections::updated_at.eq(current_time),
+    ))
+    .execute(conn)
+}
+
+// the shop uninstalled one of our apps, every connection it has for that app
+// goes away along with the token, a reinstall starts over with a fresh row
+pub fn deactivate_shop(conn: &PgConnection, app: String, shop: String) -> QueryResult<usize> {
+    let current_time = now();
+
+    diesel::update(
+        shopify_connections::table
+            .filter(shopify_connections::app.eq(app))
+            .filter(shopify_connections::shop.eq(shop))
+            .filter(shopify_connections::deleted_at.is_null()),
+    )
+    .set((
+        shopify_connections::access_token.eq(None::<String>),
+        shopify_connections::access_token_data_key.eq(None::<String>),
+        shopify_connections::access_token_key_id.eq(None::<String>),
+        shopify_connections::active.eq(false),
+        shopify_connections::deleted_at.eq(current_time),
+        shopify_connections::updated_at.eq(current_time),
+    ))
+    .execute(conn)
+}
+
+// for when a shop asks us to forget it entirely, soft deleting isn't enough
+pub fn delete_by_shop(conn: &PgConnection, shop: String) -> QueryResult<usize> {
+    diesel::delete(shopify_connections::table.filter(shopify_connections::shop.eq(shop)))
+        .execute(conn)
+}
+
+// the same, but leaving alone whatever the shop has with our other apps
+pub fn delete_by_app_and_shop(
+    conn: &PgConnection,
+    app: String,
+    shop: String,
+) -> QueryResult<usize> {
+    diesel::delete(
+        shopify_connections::table
+            .filter(shopify_connections::app.eq(app))
+            .filter(shopify_connections::shop.eq(shop)),
+    )
+    .execute(conn)
+}
+
 pub fn update_access_token(
     conn: &PgConnection,
+    cipher: &TokenCipher,
     shopify_connection: &ShopifyConnection,
     access_token: String,
+    scope: String,
 ) -> QueryResult<usize> {
-    diesel::update(shopify_connection)
-        .set((
-            shopify_connections::access_token.eq(access_token),
-            shopify_connections::updated_at.eq(now()),
-        ))
-        .execute(conn)
+    let sealed = cipher.seal(&shopify_connection.shop, &access_token);
+
+    conn.transaction(|| {
+        retire_other_installs(conn, shopify_connection)?;
+
+        diesel::update(shopify_connection)
+            .set((
+                shopify_connections::access_token.eq(sealed.ciphertext),
+                shopify_connections::access_token_data_key.eq(sealed.data_key),
+                shopify_connections::access_token_key_id.eq(sealed.key_id),
+                shopify_connections::scope.eq(scope),
+                shopify_connections::installed_at.eq(now()),
+                shopify_connections::updated_at.eq(now()),
+            ))
+            .execute(conn)
+    })
+}
+
+// online installs only get tokens for staff members, those live in
+// shopify_online_tokens so the row just records what the shop granted
+pub fn mark_installed(
+    conn: &PgConnection,
+    shopify_connection: &ShopifyConnection,
+    scope: String,
+) -> QueryResult<usize> {
+    conn.transaction(|| {
+        retire_other_installs(conn, shopify_connection)?;
+
+        diesel::update(shopify_connection)
+            .set((
+                shopify_connections::scope.eq(scope),
+                shopify_connections::installed_at.eq(now()),
+                shopify_connections::updated_at.eq(now()),
+            ))
+            .execute(conn)
+    })
+}
+
+// a shop going through the authorize step again replaces its earlier install
+fn retire_other_installs(
+    conn: &PgConnection,
+    shopify_connection: &ShopifyConnection,
+) -> QueryResult<usize> {
+    let current_time = now();
+
+    diesel::update(
+        shopify_connections::table
+            .filter(shopify_connections::app.eq(&shopify_connection.app))
+            .filter(shopify_connections::shop.eq(&shopify_connection.shop))
+            .filter(shopify_connections::id.ne(shopify_connection.id))
+            .filter(shopify_connections::installed_at.is_not_null())
+            .filter(shopify_connections::deleted_at.is_null()),
+    )
+    .set((
+        shopify_connections::access_token.eq(None::<String>),
+        shopify_connections::access_token_data_key.eq(None::<String>),
+        shopify_connections::access_token_key_id.eq(None::<String>),
+        shopify_connections::active.eq(false),
+        shopify_connections::deleted_at.eq(current_time),
+        shopify_connections::updated_at.eq(current_time),
+    ))
+    .execute(conn)
+}
+
+// reseal a batch of tokens that aren't under the active key, in id order
+// starting after `after_id`. the rows are locked while we work on them so a
+// token being replaced at the same time waits for us. hands back the last id
+// looked at for the next batch to pick up from, or None once there's nothing left
+pub fn rotate_access_token_keys(
+    conn: &PgConnection,
+    cipher: &TokenCipher,
+    after_id: i32,
+    batch_size: i64,
+) -> QueryResult<Option<i32>> {
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let batch = shopify_connections::table
+            .filter(shopify_connections::id.gt(after_id))
+            .filter(shopify_connections::access_token.is_not_null())
+            .filter(
+                shopify_connections::access_token_key_id
+                    .is_null()
+                    .or(shopify_connections::access_token_key_id.ne(cipher.active_key_id())),
+            )
+            .order(shopify_connections::id.asc())
+            .limit(batch_size)
+            .for_update()
+            .load::<ShopifyConnection>(conn)?;
+
+        for shopify_connection in batch.iter() {
+            let resealed = match (
+                &shopify_connection.access_token_data_key,
+                &shopify_connection.access_token_key_id,
+            ) {
+                (Some(data_key), Some(key_id)) => cipher
+                    .reseal_data_key(&shopify_connection.shop, data_key, key_id)
+                    .map(|(data_key, key_id)| (None, data_key, key_id)),
+                (_, None) => {
+                    let sealed = cipher.seal(
+                        &shopify_connection.shop,
+                        shopify_connection
+                            .access_token
+                            .as_deref()
+                            .unwrap_or_default(),
+                    );
+                    Ok((Some(sealed.ciphertext), sealed.data_key, sealed.key_id))
+                }
+                (None, Some(_)) => Err(EncryptionError::Malformed),
+            };
+
+            match resealed {
+                Ok((ciphertext, data_key, key_id)) => {
+                    diesel::update(shopify_connection)
+                        .set((
+                            shopify_connections::access_token
+                                .eq(ciphertext.or_else(|| shopify_connection.access_token.clone())),
+                            shopify_connections::access_token_data_key.eq(data_key),
+                            shopify_connections::access_token_key_id.eq(key_id),
+                        ))
+                        .execute(conn)?;
+                }
+                Err(e) => log::error!(
+                    "could not rotate the access token for connection {}: {}",
+                    shopify_connection.id,
+                    e
+                ),
+            }
+        }
+
+        Ok(batch.last().map(|shopify_connection| shopify_connection.id))
+    })
+}
+
+// tokens saved before they were sealed are still plaintext, seal them under
+// the active key. runs at startup before anything reads a token
+pub fn seal_plaintext_access_tokens(
+    conn: &PgConnection,
+    cipher: &TokenCipher,
+) -> QueryResult<usize> {
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let plaintext = shopify_connections::table
+            .filter(shopify_connections::access_token.is_not_null())
+            .filter(shopify_connections::access_token_key_id.is_null())
+            .for_update()
+            .load::<ShopifyConnection>(conn)?;
+
+        for shopify_connection in plaintext.iter() {
+            let sealed = cipher.seal(
+                &shopify_connection.shop,
+                shopify_connection
+                    .access_token
+                    .as_deref()
+                    .unwrap_or_default(),
+            );
+
+            diesel::update(shopify_connection)
+                .set((
+                    shopify_connections::access_token.eq(sealed.ciphertext),
+                    shopify_connections::access_token_data_key.eq(sealed.data_key),
+                    shopify_connections::access_token_key_id.eq(sealed.key_id),
+                ))
+                .execute(conn)?;
+        }
+
+        Ok(plaintext.len())
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::encryption::TokenKey;
     use crate::establish_connection_test;
 
     fn cleanup_table(conn: &PgConnection) {
@@ -105,6 +531,10 @@ mod tests {
             .unwrap();
     }
 
+    fn test_cipher() -> TokenCipher {
+        TokenCipher::new(vec![TokenKey::new("test-1", [7; 32])])
+    }
+
     fn mock_struct() -> NewShopifyConnection {
         NewShopifyConnection::new(
             String::from("ShopName"),
@@ -112,6 +542,32 @@ mod tests {
         )
     }
 
+    #[test]
+    fn it_upserts_a_single_installed_shopify_connection() {
+        let conn = establish_connection_test();
+
+        let first = NewShopifyConnection::installed_online(
+            String::from("ShopName"),
+            String::from("first-nonce"),
+            String::from("read_orders"),
+        )
+        .upsert_installed(&conn)
+        .unwrap();
+        let second = NewShopifyConnection::installed_online(
+            String::from("ShopName"),
+            String::from("second-nonce"),
+            String::from("write_orders"),
+        )
+        .upsert_installed(&conn)
+        .unwrap();
+
+        assert_eq!(first.id, second.id);
+        assert_eq!(second.scope.unwrap(), "write_orders");
+        assert_eq!(1, read_by_shop(&conn, String::from("ShopName")).len());
+
+        cleanup_table(&conn);
+    }
+
     #[test]
     fn it_creates_a_shopify_connection() {
         let conn = establish_connection_test();
@@ -178,6 +634,28 @@ mod tests {
         cleanup_table(&conn);
     }
 
+    #[test]
+    fn it_reads_an_installed_shopify_connection_by_shop() {
+        let conn = establish_connection_test();
+
+        let pending = create(&conn, &mock_struct());
+        assert!(read_installed_by_shop(&conn, pending.shop.clone()).is_none());
+
+        update_access_token(
+            &conn,
+            &test_cipher(),
+            &pending,
+            String::from("super ssssecret"),
+            String::from("read_orders"),
+        )
+        .unwrap();
+
+        let installed = read_installed_by_shop(&conn, pending.shop.clone());
+        assert_eq!(installed.unwrap().id, pending.id);
+
+        cleanup_table(&conn);
+    }
+
     #[test]
     fn it_reads_a_shopify_connection_by_shop_and_nonce() {
         let conn = establish_connection_test();
@@ -212,14 +690,24 @@ mod tests {
         let shopify_connection = create(&conn, &mock_struct());
         let access_token = String::from("super ssssecret");
 
-        update_access_token(&conn, &shopify_connection, access_token.clone());
+        update_access_token(
+            &conn,
+            &test_cipher(),
+            &shopify_connection,
+            access_token.clone(),
+            String::from("read_orders"),
+        );
 
         let shopify_connections = read_by_shop(&conn, shopify_connection.shop);
 
         assert_eq!(1, shopify_connections.len());
-        let my_shopify_connection = shopify_connections
-            .iter()
-            .find(|x| x.access_token.as_ref().unwrap() == &access_token);
+        assert_ne!(
+            shopify_connections[0].access_token,
+            Some(access_token.clone())
+        );
+        let my_shopify_connection = shopify_connections.iter().find(|x| {
+            x.decrypt_access_token(&test_cipher()).unwrap().as_ref() == Some(&access_token)
+        });
         assert!(
             my_shopify_connection.is_some(),
             "Could not find the created shopify_connection in the database!"
@@ -227,4 +715,265 @@ mod tests {
 
         cleanup_table(&conn);
     }
+
+    #[test]
+    fn it_consumes_a_nonce_only_once() {
+        let conn = establish_connection_test();
+        let new_shopify_connection = mock_struct();
+        create(&conn, &new_shopify_connection);
+
+        let consumed = consume_nonce(
+            &conn,
+            new_shopify_connection.shop.clone(),
+            new_shopify_connection.nonce.clone(),
+        )
+        .unwrap();
+        assert!(consumed.is_some());
+        assert!(consumed.unwrap().nonce_consumed_at.is_some());
+
+        let consumed_again = consume_nonce(
+            &conn,
+            new_shopify_connection.shop.clone(),
+            new_shopify_connection.nonce.clone(),
+        )
+        .unwrap();
+        assert!(consumed_again.is_none());
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_consumes_a_released_nonce_again() {
+        let conn = establish_connection_test();
+        let new_shopify_connection = mock_struct();
+        create(&conn, &new_shopify_connection);
+        let (shop, nonce) = (
+            new_shopify_connection.shop.clone(),
+            new_shopify_connection.nonce.clone(),
+        );
+
+        assert!(consume_nonce(&conn, shop.clone(), nonce.clone())
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            1,
+            release_nonce(&conn, shop.clone(), nonce.clone()).unwrap()
+        );
+        assert!(consume_nonce(&conn, shop, nonce).unwrap().is_some());
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_does_not_consume_an_expired_nonce() {
+        let conn = establish_connection_test();
+        let new_shopify_connection = mock_struct().expires_in(Duration::seconds(-1));
+        create(&conn, &new_shopify_connection);
+
+        let consumed = consume_nonce(
+            &conn,
+            new_shopify_connection.shop.clone(),
+            new_shopify_connection.nonce.clone(),
+        )
+        .unwrap();
+        assert!(consumed.is_none());
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_soft_deletes_expired_pending_shopify_connections() {
+        let conn = establish_connection_test();
+
+        let expired = create(&conn, &mock_struct().expires_in(Duration::seconds(-1)));
+        let pending = create(&conn, &mock_struct());
+        let installed = create(&conn, &mock_struct().expires_in(Duration::seconds(-1)));
+        update_access_token(
+            &conn,
+            &test_cipher(),
+            &installed,
+            String::from("super ssssecret"),
+            String::from("read_orders"),
+        )
+        .unwrap();
+
+        assert_eq!(1, soft_delete_expired_pending(&conn).unwrap());
+
+        let shopify_connections = read(&conn);
+        let find = |id: i32| shopify_connections.iter().find(|x| x.id == id).unwrap();
+
+        assert!(!find(expired.id).active);
+        assert!(find(expired.id).deleted_at.is_some());
+        assert!(find(pending.id).active);
+        assert!(find(installed.id).deleted_at.is_none());
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_deactivates_every_connection_for_a_shop() {
+        let conn = establish_connection_test();
+
+        let installed = create(&conn, &mock_struct());
+        update_access_token(
+            &conn,
+            &test_cipher(),
+            &installed,
+            String::from("super ssssecret"),
+            String::from("read_orders"),
+        )
+        .unwrap();
+        create(&conn, &mock_struct());
+        let mut other_shop = mock_struct();
+        other_shop.shop = String::from("OtherShopName");
+        create(&conn, &other_shop);
+        // the same shop with another of our apps installed
+        create(
+            &conn,
+            &NewShopifyConnection::installed(
+                &test_cipher(),
+                String::from("ShopName"),
+                String::from("some-nonce"),
+                String::from("staging ssssecret"),
+                String::from("read_orders"),
+            )
+            .for_app("staging"),
+        );
+
+        assert_eq!(
+            2,
+            deactivate_shop(
+                &conn,
+                String::from(DEFAULT_SHOPIFY_APP),
+                String::from("ShopName")
+            )
+            .unwrap()
+        );
+        assert!(read_installed_by_app_and_shop(
+            &conn,
+            String::from(DEFAULT_SHOPIFY_APP),
+            String::from("ShopName")
+        )
+        .is_none());
+        assert_eq!(
+            read_installed_by_shop(&conn, String::from("ShopName"))
+                .unwrap()
+                .app,
+            "staging"
+        );
+
+        for shopify_connection in read_by_shop(&conn, String::from("ShopName"))
+            .into_iter()
+            .filter(|shopify_connection| shopify_connection.app == DEFAULT_SHOPIFY_APP)
+        {
+            assert!(!shopify_connection.active);
+            assert!(shopify_connection.deleted_at.is_some());
+            assert!(shopify_connection.access_token.is_none());
+        }
+
+        let other_shop_connections = read_by_shop(&conn, String::from("OtherShopName"));
+        assert!(other_shop_connections[0].deleted_at.is_none());
+
+        // a reinstall starts over cleanly
+        let reinstall = create(&conn, &mock_struct());
+        assert!(
+            consume_nonce(&conn, reinstall.shop.clone(), reinstall.nonce.clone())
+                .unwrap()
+                .is_some()
+        );
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_seals_access_tokens_saved_as_plaintext() {
+        let conn = establish_connection_test();
+
+        // saved before tokens were sealed
+        let mut plaintext = mock_struct();
+        plaintext.access_token = Some(String::from("older ssssecret"));
+        let plaintext = create(&conn, &plaintext);
+        assert!(plaintext.decrypt_access_token(&test_cipher()).is_err());
+
+        assert_eq!(
+            1,
+            seal_plaintext_access_tokens(&conn, &test_cipher()).unwrap()
+        );
+        assert_eq!(
+            0,
+            seal_plaintext_access_tokens(&conn, &test_cipher()).unwrap()
+        );
+
+        let sealed = read(&conn).into_iter().next().unwrap();
+        assert_ne!(sealed.access_token.as_deref(), Some("older ssssecret"));
+        assert_eq!(
+            sealed
+                .decrypt_access_token(&test_cipher())
+                .unwrap()
+                .as_deref(),
+            Some("older ssssecret")
+        );
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_rotates_access_tokens_onto_the_active_key() {
+        let conn = establish_connection_test();
+        let old_key = TokenKey::new("test-1", [7; 32]);
+        let new_key = TokenKey::new("test-2", [8; 32]);
+        let rotating = TokenCipher::new(vec![new_key.clone(), old_key]);
+
+        let sealed = create(
+            &conn,
+            &NewShopifyConnection::installed(
+                &test_cipher(),
+                String::from("ShopName"),
+                String::from("some-nonce"),
+                String::from("super ssssecret"),
+                String::from("read_orders"),
+            ),
+        );
+        // saved before tokens were sealed
+        let mut plaintext = mock_struct();
+        plaintext.shop = String::from("OtherShopName");
+        plaintext.access_token = Some(String::from("older ssssecret"));
+        let plaintext = create(&conn, &plaintext);
+
+        let mut after_id = 0;
+        while let Some(last_id) = rotate_access_token_keys(&conn, &rotating, after_id, 1).unwrap() {
+            after_id = last_id;
+        }
+        assert_eq!(after_id, plaintext.id);
+
+        let new = TokenCipher::new(vec![new_key]);
+        let find = |id: i32| read(&conn).into_iter().find(|x| x.id == id).unwrap();
+
+        assert_eq!(find(sealed.id).access_token, sealed.access_token);
+        assert_eq!(
+            find(sealed.id).access_token_key_id.as_deref(),
+            Some("test-2")
+        );
+        assert_eq!(
+            find(sealed.id)
+                .decrypt_access_token(&new)
+                .unwrap()
+                .as_deref(),
+            Some("super ssssecret")
+        );
+        assert_ne!(
+            find(plaintext.id).access_token.as_deref(),
+            Some("older ssssecret")
+        );
+        assert_eq!(
+            find(plaintext.id)
+                .decrypt_access_token(&new)
+                .unwrap()
+                .as_deref(),
+            Some("older ssssecret")
+        );
+        assert_eq!(None, rotate_access_token_keys(&conn, &new, 0, 10).unwrap());
+
+        cleanup_table(&conn);
+    }
 }
@@ -0,0 +1,513 @@
+use crate::config::DEFAULT_SHOPIFY_APP;
+use crate::encryption::TokenCipher;
+use crate::errors::EncryptionError;
+use crate::schema::shopify_online_tokens;
+use crate::utils::now;
+use crate::AccessTokenResponse;
+use chrono::{naive::NaiveDateTime, Duration};
+use diesel::pg::upsert::excluded;
+use diesel::prelude::*;
+
+// a per staff member token, only good until it expires
+#[derive(Debug, Identifiable, Queryable)]
+#[table_name = "shopify_online_tokens"]
+pub struct ShopifyOnlineToken {
+    pub id: i32,
+    pub shop: String,
+    pub shopify_user_id: i64,
+    // sealed, see decrypt_access_token
+    pub access_token: String,
+    pub scope: String,
+    pub associated_user_scope: String,
+    pub expires_at: NaiveDateTime,
+    pub email: Option<String>,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub account_owner: bool,
+    pub created_at: NaiveDateTime,
+    pub updated_at: Option<NaiveDateTime>,
+    pub access_token_data_key: Option<String>,
+    pub access_token_key_id: Option<String>,
+    pub app: String,
+}
+
+impl ShopifyOnlineToken {
+    // rows saved before online tokens were sealed are sealed at startup,
+    // see seal_plaintext_access_tokens
+    pub fn decrypt_access_token(&self, cipher: &TokenCipher) -> Result<String, EncryptionError> {
+        match (&self.access_token_data_key, &self.access_token_key_id) {
+            (Some(data_key), Some(key_id)) => {
+                cipher.open(&self.shop, &self.access_token, data_key, key_id)
+            }
+            _ => Err(EncryptionError::Malformed),
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "shopify_online_tokens"]
+pub struct NewShopifyOnlineToken {
+    pub shop: String,
+    pub shopify_user_id: i64,
+    pub access_token: String,
+    pub scope: String,
+    pub associated_user_scope: String,
+    pub expires_at: NaiveDateTime,
+    pub email: Option<String>,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub account_owner: bool,
+    pub created_at: NaiveDateTime,
+    pub updated_at: Option<NaiveDateTime>,
+    pub access_token_data_key: Option<String>,
+    pub access_token_key_id: Option<String>,
+    pub app: String,
+}
+
+impl NewShopifyOnlineToken {
+    pub fn new(
+        cipher: &TokenCipher,
+        shop: String,
+        shopify_user_id: i64,
+        access_token: String,
+        scope: String,
+        associated_user_scope: String,
+        expires_in: i64,
+    ) -> Self {
+        let created_at = now();
+        let sealed = cipher.seal(&shop, &access_token);
+
+        NewShopifyOnlineToken {
+            shop,
+            shopify_user_id,
+            access_token: sealed.ciphertext,
+            scope,
+            associated_user_scope,
+            expires_at: created_at + Duration::seconds(expires_in),
+            email: None,
+            first_name: None,
+            last_name: None,
+            account_owner: false,
+            created_at,
+            updated_at: None,
+            access_token_data_key: Some(sealed.data_key),
+            access_token_key_id: Some(sealed.key_id),
+            app: String::from(DEFAULT_SHOPIFY_APP),
+        }
+    }
+
+    pub fn for_app(mut self, app: &str) -> Self {
+        self.app = app.to_string();
+        self
+    }
+
+    // online access tokens belong to the staff member who approved us,
+    // offline ones come back without an associated user
+    pub fn from_access_token_response(
+        cipher: &TokenCipher,
+        app: &str,
+        shop: &str,
+        access_token_json: &AccessTokenResponse,
+    ) -> Option<Self> {
+        let user = access_token_json.associated_user.as_ref()?;
+
+        let mut online_token = NewShopifyOnlineToken::new(
+            cipher,
+            shop.to_string(),
+            user.id,
+            access_token_json.access_token.clone(),
+            access_token_json.scope.clone(),
+            access_token_json
+                .associated_user_scope
+                .clone()
+                .unwrap_or_else(|| access_token_json.scope.clone()),
+            access_token_json.expires_in.unwrap_or(0),
+        )
+        .for_app(app);
+        online_token.email = user.email.clone();
+        online_token.first_name = user.first_name.clone();
+        online_token.last_name = user.last_name.clone();
+        online_token.account_owner = user.account_owner;
+
+        Some(online_token)
+    }
+
+    pub fn upsert(&self, conn: &PgConnection) -> QueryResult<ShopifyOnlineToken> {
+        upsert(conn, self)
+    }
+}
+
+// what we know about a staff member's token when they hit our api
+#[derive(Debug)]
+pub enum OnlineTokenLookup {
+    Valid(Box<ShopifyOnlineToken>),
+    Expired,
+    Missing,
+}
+
+// a staff member re-authorizing the same app replaces their old token
+pub fn upsert(
+    conn: &PgConnection,
+    new_shopify_online_token: &NewShopifyOnlineToken,
+) -> QueryResult<ShopifyOnlineToken> {
+    diesel::insert_into(shopify_online_tokens::table)
+        .values(new_shopify_online_token)
+        .on_conflict((
+            shopify_online_tokens::app,
+            shopify_online_tokens::shop,
+            shopify_online_tokens::shopify_user_id,
+        ))
+        .do_update()
+        .set((
+            shopify_online_tokens::access_token.eq(excluded(shopify_online_tokens::access_token)),
+            shopify_online_tokens::access_token_data_key
+                .eq(excluded(shopify_online_tokens::access_token_data_key)),
+            shopify_online_tokens::access_token_key_id
+                .eq(excluded(shopify_online_tokens::access_token_key_id)),
+            shopify_online_tokens::scope.eq(excluded(shopify_online_tokens::scope)),
+            shopify_online_tokens::associated_user_scope
+                .eq(excluded(shopify_online_tokens::associated_user_scope)),
+            shopify_online_tokens::expires_at.eq(excluded(shopify_online_tokens::expires_at)),
+            shopify_online_tokens::email.eq(excluded(shopify_online_tokens::email)),
+            shopify_online_tokens::first_name.eq(excluded(shopify_online_tokens::first_name)),
+            shopify_online_tokens::last_name.eq(excluded(shopify_online_tokens::last_name)),
+            shopify_online_tokens::account_owner.eq(excluded(shopify_online_tokens::account_owner)),
+            shopify_online_tokens::updated_at.eq(now()),
+        ))
+        .get_result(conn)
+}
+
+pub fn read_by_shop(conn: &PgConnection, shop: String) -> Vec<ShopifyOnlineToken> {
+    shopify_online_tokens::table
+        .filter(shopify_online_tokens::shop.eq(shop))
+        .load::<ShopifyOnlineToken>(conn)
+        .expect("Error loading shopify_online_token")
+}
+
+pub fn read_by_app_shop_and_user(
+    conn: &PgConnection,
+    app: String,
+    shop: String,
+    shopify_user_id: i64,
+) -> Option<ShopifyOnlineToken> {
+    shopify_online_tokens::table
+        .filter(shopify_online_tokens::app.eq(app))
+        .filter(shopify_online_tokens::shop.eq(shop))
+        .filter(shopify_online_tokens::shopify_user_id.eq(shopify_user_id))
+        .first::<ShopifyOnlineToken>(conn)
+        .optional()
+        .expect("Error loading shopify_online_token")
+}
+
+// any staff member's token that hasn't expired yet, the freshest one,
+// for work done on the shop's behalf when it only has online tokens
+pub fn read_unexpired_by_app_and_shop(
+    conn: &PgConnection,
+    app: String,
+    shop: String,
+) -> Option<ShopifyOnlineToken> {
+    shopify_online_tokens::table
+        .filter(shopify_online_tokens::app.eq(app))
+        .filter(shopify_online_tokens::shop.eq(shop))
+        .filter(shopify_online_tokens::expires_at.gt(now()))
+        .order(shopify_online_tokens::expires_at.desc())
+        .first::<ShopifyOnlineToken>(conn)
+        .optional()
+        .expect("Error loading shopify_online_token")
+}
+
+// staff sessions don't outlive the shop uninstalling the app
+pub fn delete_by_app_and_shop(
+    conn: &PgConnection,
+    app: String,
+    shop: String,
+) -> QueryResult<usize> {
+    diesel::delete(
+        shopify_online_tokens::table
+            .filter(shopify_online_tokens::app.eq(app))
+            .filter(shopify_online_tokens::shop.eq(shop)),
+    )
+    .execute(conn)
+}
+
+// hands back the token if it is still good,
+// otherwise the staff member has to go through the authorize step again
+pub fn lookup_valid(
+    conn: &PgConnection,
+    app: String,
+    shop: String,
+    shopify_user_id: i64,
+) -> OnlineTokenLookup {
+    match read_by_app_shop_and_user(conn, app, shop, shopify_user_id) {
+        Some(token) if token.expires_at > now() => OnlineTokenLookup::Valid(Box::new(token)),
+        Some(_) => OnlineTokenLookup::Expired,
+        None => OnlineTokenLookup::Missing,
+    }
+}
+
+// reseal a batch of tokens that aren't under the active key,
+// see shopify_connection::rotate_access_token_keys
+pub fn rotate_access_token_keys(
+    conn: &PgConnection,
+    cipher: &TokenCipher,
+    after_id: i32,
+    batch_size: i64,
+) -> QueryResult<Option<i32>> {
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let batch = shopify_online_tokens::table
+            .filter(shopify_online_tokens::id.gt(after_id))
+            .filter(
+                shopify_online_tokens::access_token_key_id
+                    .is_null()
+                    .or(shopify_online_tokens::access_token_key_id.ne(cipher.active_key_id())),
+            )
+            .order(shopify_online_tokens::id.asc())
+            .limit(batch_size)
+            .for_update()
+            .load::<ShopifyOnlineToken>(conn)?;
+
+        for online_token in batch.iter() {
+            let resealed = match (
+                &online_token.access_token_data_key,
+                &online_token.access_token_key_id,
+            ) {
+                (Some(data_key), Some(key_id)) => cipher
+                    .reseal_data_key(&online_token.shop, data_key, key_id)
+                    .map(|(data_key, key_id)| {
+                        (online_token.access_token.clone(), data_key, key_id)
+                    }),
+                (_, None) => {
+                    let sealed = cipher.seal(&online_token.shop, &online_token.access_token);
+                    Ok((sealed.ciphertext, sealed.data_key, sealed.key_id))
+                }
+                (None, Some(_)) => Err(EncryptionError::Malformed),
+            };
+
+            match resealed {
+                Ok((ciphertext, data_key, key_id)) => {
+                    diesel::update(online_token)
+                        .set((
+                            shopify_online_tokens::access_token.eq(ciphertext),
+                            shopify_online_tokens::access_token_data_key.eq(data_key),
+                            shopify_online_tokens::access_token_key_id.eq(key_id),
+                        ))
+                        .execute(conn)?;
+                }
+                Err(e) => log::error!(
+                    "could not rotate the online access token {}: {}",
+                    online_token.id,
+                    e
+                ),
+            }
+        }
+
+        Ok(batch.last().map(|online_token| online_token.id))
+    })
+}
+
+// see shopify_connection::seal_plaintext_access_tokens
+pub fn seal_plaintext_access_tokens(
+    conn: &PgConnection,
+    cipher: &TokenCipher,
+) -> QueryResult<usize> {
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let plaintext = shopify_online_tokens::table
+            .filter(shopify_online_tokens::access_token_key_id.is_null())
+            .for_update()
+            .load::<ShopifyOnlineToken>(conn)?;
+
+        for online_token in plaintext.iter() {
+            let sealed = cipher.seal(&online_token.shop, &online_token.access_token);
+
+            diesel::update(online_token)
+                .set((
+                    shopify_online_tokens::access_token.eq(sealed.ciphertext),
+                    shopify_online_tokens::access_token_data_key.eq(sealed.data_key),
+                    shopify_online_tokens::access_token_key_id.eq(sealed.key_id),
+                ))
+                .execute(conn)?;
+        }
+
+        Ok(plaintext.len())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::TokenKey;
+    use crate::establish_connection_test;
+
+    fn cleanup_table(conn: &PgConnection) {
+        diesel::delete(shopify_online_tokens::table)
+            .execute(conn)
+            .unwrap();
+    }
+
+    fn test_cipher() -> TokenCipher {
+        TokenCipher::new(vec![TokenKey::new("test-1", [7; 32])])
+    }
+
+    fn mock_struct(expires_in: i64) -> NewShopifyOnlineToken {
+        NewShopifyOnlineToken::new(
+            &test_cipher(),
+            String::from("ShopName"),
+            902541635,
+            String::from("f85632530bf277ec9ac6f649fc327f17"),
+            String::from("write_orders"),
+            String::from("write_orders"),
+            expires_in,
+        )
+    }
+
+    #[test]
+    fn it_replaces_a_users_shopify_online_token() {
+        let conn = establish_connection_test();
+
+        let first = mock_struct(86399).upsert(&conn).unwrap();
+
+        let second = NewShopifyOnlineToken::new(
+            &test_cipher(),
+            String::from("ShopName"),
+            902541635,
+            String::from("a fresh one"),
+            String::from("write_orders"),
+            String::from("write_orders"),
+            86399,
+        )
+        .upsert(&conn)
+        .unwrap();
+
+        assert_eq!(first.id, second.id);
+        assert_ne!(second.access_token, "a fresh one");
+        assert_eq!(
+            second.decrypt_access_token(&test_cipher()).unwrap(),
+            "a fresh one"
+        );
+        assert_eq!(1, read_by_shop(&conn, String::from("ShopName")).len());
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_seals_online_access_tokens_saved_as_plaintext() {
+        let conn = establish_connection_test();
+
+        // saved before online tokens were sealed
+        let mut plaintext = mock_struct(86399);
+        plaintext.access_token = String::from("older ssssecret");
+        plaintext.access_token_data_key = None;
+        plaintext.access_token_key_id = None;
+        let plaintext = plaintext.upsert(&conn).unwrap();
+        assert!(plaintext.decrypt_access_token(&test_cipher()).is_err());
+
+        assert_eq!(
+            1,
+            seal_plaintext_access_tokens(&conn, &test_cipher()).unwrap()
+        );
+
+        let sealed = read_by_shop(&conn, String::from("ShopName")).remove(0);
+        assert_ne!(sealed.access_token, "older ssssecret");
+        assert_eq!(
+            sealed.decrypt_access_token(&test_cipher()).unwrap(),
+            "older ssssecret"
+        );
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_rotates_online_access_tokens_onto_the_active_key() {
+        let conn = establish_connection_test();
+        let new_key = TokenKey::new("test-2", [8; 32]);
+        let rotating = TokenCipher::new(vec![new_key.clone(), TokenKey::new("test-1", [7; 32])]);
+
+        let sealed = mock_struct(86399).upsert(&conn).unwrap();
+        // saved before online tokens were sealed
+        let mut plaintext = mock_struct(86399);
+        plaintext.shopify_user_id = 1;
+        plaintext.access_token = String::from("older ssssecret");
+        plaintext.access_token_data_key = None;
+        plaintext.access_token_key_id = None;
+        let plaintext = plaintext.upsert(&conn).unwrap();
+
+        let mut after_id = 0;
+        while let Some(last_id) = rotate_access_token_keys(&conn, &rotating, after_id, 1).unwrap() {
+            after_id = last_id;
+        }
+        assert_eq!(after_id, plaintext.id);
+
+        let new = TokenCipher::new(vec![new_key]);
+        let find = |id: i32| {
+            read_by_shop(&conn, String::from("ShopName"))
+                .into_iter()
+                .find(|x| x.id == id)
+                .unwrap()
+        };
+
+        assert_eq!(find(sealed.id).access_token, sealed.access_token);
+        assert_eq!(
+            find(sealed.id).decrypt_access_token(&new).unwrap(),
+            "f85632530bf277ec9ac6f649fc327f17"
+        );
+        assert_ne!(find(plaintext.id).access_token, "older ssssecret");
+        assert_eq!(
+            find(plaintext.id).decrypt_access_token(&new).unwrap(),
+            "older ssssecret"
+        );
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_looks_up_a_valid_shopify_online_token() {
+        let conn = establish_connection_test();
+
+        mock_struct(86399).upsert(&conn).unwrap();
+
+        match lookup_valid(
+            &conn,
+            String::from("default"),
+            String::from("ShopName"),
+            902541635,
+        ) {
+            OnlineTokenLookup::Valid(token) => assert_eq!(token.shopify_user_id, 902541635),
+            other => panic!("Expected a valid token, got {:?}", other),
+        }
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_reports_expired_and_missing_shopify_online_tokens() {
+        let conn = establish_connection_test();
+
+        mock_struct(-1).upsert(&conn).unwrap();
+
+        assert!(matches!(
+            lookup_valid(
+                &conn,
+                String::from("default"),
+                String::from("ShopName"),
+                902541635
+            ),
+            OnlineTokenLookup::Expired
+        ));
+        assert!(matches!(
+            lookup_valid(&conn, String::from("default"), String::from("ShopName"), 1),
+            OnlineTokenLookup::Missing
+        ));
+        // the same staff member on another of our apps
+        assert!(matches!(
+            lookup_valid(
+                &conn,
+                String::from("staging"),
+                String::from("ShopName"),
+                902541635
+            ),
+            OnlineTokenLookup::Missing
+        ));
+
+        cleanup_table(&conn);
+    }
+}
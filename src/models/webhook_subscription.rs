@@ -0,0 +1,204 @@
+use crate::schema::webhook_subscriptions;
+use crate::utils::now;
+use chrono::naive::NaiveDateTime;
+use diesel::dsl::sql;
+use diesel::pg::upsert::excluded;
+use diesel::prelude::*;
+use diesel::sql_types::Integer;
+
+// a topic we subscribed a shop to, or tried to and failed
+#[derive(Debug, Identifiable, Queryable)]
+#[table_name = "webhook_subscriptions"]
+pub struct WebhookSubscription {
+    pub id: i32,
+    pub shop: String,
+    pub topic: String,
+    pub address: String,
+    pub shopify_webhook_id: Option<i64>,
+    pub failed_at: Option<NaiveDateTime>,
+    pub last_error: Option<String>,
+    pub attempts: i32,
+    pub created_at: NaiveDateTime,
+    pub updated_at: Option<NaiveDateTime>,
+    pub app: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "webhook_subscriptions"]
+pub struct NewWebhookSubscription {
+    pub shop: String,
+    pub topic: String,
+    pub address: String,
+    pub shopify_webhook_id: Option<i64>,
+    pub failed_at: Option<NaiveDateTime>,
+    pub last_error: Option<String>,
+    pub attempts: i32,
+    pub created_at: NaiveDateTime,
+    pub updated_at: Option<NaiveDateTime>,
+    pub app: String,
+}
+
+impl NewWebhookSubscription {
+    pub fn registered(
+        app: String,
+        shop: String,
+        topic: String,
+        address: String,
+        shopify_webhook_id: i64,
+    ) -> Self {
+        NewWebhookSubscription {
+            shop,
+            topic,
+            address,
+            shopify_webhook_id: Some(shopify_webhook_id),
+            failed_at: None,
+            last_error: None,
+            attempts: 0,
+            created_at: now(),
+            updated_at: None,
+            app,
+        }
+    }
+
+    pub fn failed(
+        app: String,
+        shop: String,
+        topic: String,
+        address: String,
+        error: String,
+    ) -> Self {
+        let created_at = now();
+
+        NewWebhookSubscription {
+            shop,
+            topic,
+            address,
+            shopify_webhook_id: None,
+            failed_at: Some(created_at),
+            last_error: Some(error),
+            attempts: 1,
+            created_at,
+            updated_at: None,
+            app,
+        }
+    }
+
+    pub fn upsert(&self, conn: &PgConnection) -> QueryResult<WebhookSubscription> {
+        upsert(conn, self)
+    }
+}
+
+// one row per app, shop and topic, a success clears out any earlier failure
+// and every failure in a row bumps the attempts
+pub fn upsert(
+    conn: &PgConnection,
+    new_webhook_subscription: &NewWebhookSubscription,
+) -> QueryResult<WebhookSubscription> {
+    diesel::insert_into(webhook_subscriptions::table)
+        .values(new_webhook_subscription)
+        .on_conflict((
+            webhook_subscriptions::app,
+            webhook_subscriptions::shop,
+            webhook_subscriptions::topic,
+        ))
+        .do_update()
+        .set((
+            webhook_subscriptions::address.eq(excluded(webhook_subscriptions::address)),
+            webhook_subscriptions::shopify_webhook_id
+                .eq(excluded(webhook_subscriptions::shopify_webhook_id)),
+            webhook_subscriptions::failed_at.eq(excluded(webhook_subscriptions::failed_at)),
+            webhook_subscriptions::last_error.eq(excluded(webhook_subscriptions::last_error)),
+            webhook_subscriptions::attempts.eq(sql::<Integer>(
+                "CASE WHEN excluded.failed_at IS NULL THEN 0 \
+                ELSE webhook_subscriptions.attempts + 1 END",
+            )),
+            webhook_subscriptions::updated_at.eq(now()),
+        ))
+        .get_result(conn)
+}
+
+pub fn read_by_shop(conn: &PgConnection, shop: String) -> Vec<WebhookSubscription> {
+    webhook_subscriptions::table
+        .filter(webhook_subscriptions::shop.eq(shop))
+        .order(webhook_subscriptions::topic.asc())
+        .load::<WebhookSubscription>(conn)
+        .expect("Error loading webhook_subscription")
+}
+
+// the app and shop of every install with a subscription that still needs retrying
+pub fn read_failed_shops(conn: &PgConnection) -> Vec<(String, String)> {
+    webhook_subscriptions::table
+        .filter(webhook_subscriptions::failed_at.is_not_null())
+        .select((webhook_subscriptions::app, webhook_subscriptions::shop))
+        .distinct()
+        .load::<(String, String)>(conn)
+        .expect("Error loading webhook_subscription")
+}
+
+// shopify drops a shop's subscriptions when it uninstalls the app
+pub fn delete_by_app_and_shop(
+    conn: &PgConnection,
+    app: String,
+    shop: String,
+) -> QueryResult<usize> {
+    diesel::delete(
+        webhook_subscriptions::table
+            .filter(webhook_subscriptions::app.eq(app))
+            .filter(webhook_subscriptions::shop.eq(shop)),
+    )
+    .execute(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::establish_connection_test;
+
+    fn cleanup_table(conn: &PgConnection) {
+        diesel::delete(webhook_subscriptions::table)
+            .execute(conn)
+            .unwrap();
+    }
+
+    fn failed(error: &str) -> NewWebhookSubscription {
+        NewWebhookSubscription::failed(
+            String::from("default"),
+            String::from("ShopName"),
+            String::from("orders/paid"),
+            String::from("https://gifts.example.com/webhooks/shopify"),
+            error.to_string(),
+        )
+    }
+
+    #[test]
+    fn it_counts_failed_attempts_until_a_webhook_subscription_is_registered() {
+        let conn = establish_connection_test();
+
+        failed("timed out").upsert(&conn).unwrap();
+        let second = failed("still timed out").upsert(&conn).unwrap();
+        assert_eq!(second.attempts, 2);
+        assert_eq!(second.last_error.as_deref(), Some("still timed out"));
+        assert_eq!(
+            read_failed_shops(&conn),
+            vec![(String::from("default"), String::from("ShopName"))]
+        );
+
+        let registered = NewWebhookSubscription::registered(
+            String::from("default"),
+            String::from("ShopName"),
+            String::from("orders/paid"),
+            String::from("https://gifts.example.com/webhooks/shopify"),
+            4759306,
+        )
+        .upsert(&conn)
+        .unwrap();
+
+        assert_eq!(registered.id, second.id);
+        assert_eq!(registered.attempts, 0);
+        assert_eq!(registered.shopify_webhook_id, Some(4759306));
+        assert!(registered.failed_at.is_none());
+        assert!(read_failed_shops(&conn).is_empty());
+
+        cleanup_table(&conn);
+    }
+}
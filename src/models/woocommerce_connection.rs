@@ -0,0 +1,511 @@
+use crate::encryption::TokenCipher;
+use crate::errors::EncryptionError;
+use crate::models::shopify_connection::DEFAULT_NONCE_TTL_SECS;
+use crate::schema::woocommerce_connections;
+use crate::utils::now;
+use chrono::{naive::NaiveDateTime, Duration};
+use diesel::prelude::*;
+
+#[derive(Debug, Identifiable, Queryable)]
+#[table_name = "woocommerce_connections"]
+pub struct WooCommerceConnection {
+    pub id: i32,
+    // e.x. https://shop.example.com, where the store's rest api lives
+    pub store_url: String,
+    pub nonce: String,
+    pub consumer_key: Option<String>,
+    // sealed, see decrypt_consumer_secret
+    pub consumer_secret: Option<String>,
+    pub consumer_secret_data_key: Option<String>,
+    pub consumer_secret_key_id: Option<String>,
+    pub key_permissions: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: Option<NaiveDateTime>,
+    pub deleted_at: Option<NaiveDateTime>,
+    pub active: bool,
+    pub nonce_expires_at: NaiveDateTime,
+    pub nonce_consumed_at: Option<NaiveDateTime>,
+    // sealed, see decrypt_webhook_secret
+    pub webhook_secret: Option<String>,
+    pub webhook_secret_data_key: Option<String>,
+    pub webhook_secret_key_id: Option<String>,
+}
+
+impl WooCommerceConnection {
+    // unlike shopify tokens these were sealed from the start
+    pub fn decrypt_consumer_secret(
+        &self,
+        cipher: &TokenCipher,
+    ) -> Result<Option<String>, EncryptionError> {
+        match (
+            &self.consumer_secret,
+            &self.consumer_secret_data_key,
+            &self.consumer_secret_key_id,
+        ) {
+            (None, _, _) => Ok(None),
+            (Some(ciphertext), Some(data_key), Some(key_id)) => cipher
+                .open(&self.store_url, ciphertext, data_key, key_id)
+                .map(Some),
+            _ => Err(EncryptionError::Malformed),
+        }
+    }
+
+    // stores installed before each got its own secret don't have one
+    pub fn decrypt_webhook_secret(
+        &self,
+        cipher: &TokenCipher,
+    ) -> Result<Option<String>, EncryptionError> {
+        match (
+            &self.webhook_secret,
+            &self.webhook_secret_data_key,
+            &self.webhook_secret_key_id,
+        ) {
+            (None, _, _) => Ok(None),
+            (Some(ciphertext), Some(data_key), Some(key_id)) => cipher
+                .open(&self.store_url, ciphertext, data_key, key_id)
+                .map(Some),
+            _ => Err(EncryptionError::Malformed),
+        }
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "woocommerce_connections"]
+pub struct NewWooCommerceConnection {
+    pub store_url: String,
+    pub nonce: String,
+    pub consumer_key: Option<String>,
+    pub consumer_secret: Option<String>,
+    pub consumer_secret_data_key: Option<String>,
+    pub consumer_secret_key_id: Option<String>,
+    pub key_permissions: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: Option<NaiveDateTime>,
+    pub deleted_at: Option<NaiveDateTime>,
+    pub active: bool,
+    pub nonce_expires_at: NaiveDateTime,
+    pub nonce_consumed_at: Option<NaiveDateTime>,
+    pub webhook_secret: Option<String>,
+    pub webhook_secret_data_key: Option<String>,
+    pub webhook_secret_key_id: Option<String>,
+}
+
+impl NewWooCommerceConnection {
+    pub fn new(store_url: String, nonce: String) -> Self {
+        NewWooCommerceConnection {
+            store_url,
+            nonce,
+            consumer_key: None,
+            consumer_secret: None,
+            consumer_secret_data_key: None,
+            consumer_secret_key_id: None,
+            key_permissions: None,
+            created_at: now(),
+            updated_at: None,
+            deleted_at: None,
+            active: true,
+            nonce_expires_at: now() + Duration::seconds(DEFAULT_NONCE_TTL_SECS),
+            nonce_consumed_at: None,
+            webhook_secret: None,
+            webhook_secret_data_key: None,
+            webhook_secret_key_id: None,
+        }
+    }
+
+    // for stores that handed us keys some other way, the nonce
+    // is never handed out so it starts off used
+    pub fn installed(
+        cipher: &TokenCipher,
+        store_url: String,
+        nonce: String,
+        consumer_key: String,
+        consumer_secret: String,
+        key_permissions: String,
+        webhook_secret: String,
+    ) -> Self {
+        let sealed = cipher.seal(&store_url, &consumer_secret);
+        let sealed_webhook_secret = cipher.seal(&store_url, &webhook_secret);
+        let mut new_connection = NewWooCommerceConnection::new(store_url, nonce);
+        new_connection.consumer_key = Some(consumer_key);
+        new_connection.consumer_secret = Some(sealed.ciphertext);
+        new_connection.consumer_secret_data_key = Some(sealed.data_key);
+        new_connection.consumer_secret_key_id = Some(sealed.key_id);
+        new_connection.key_permissions = Some(key_permissions);
+        new_connection.webhook_secret = Some(sealed_webhook_secret.ciphertext);
+        new_connection.webhook_secret_data_key = Some(sealed_webhook_secret.data_key);
+        new_connection.webhook_secret_key_id = Some(sealed_webhook_secret.key_id);
+        new_connection.nonce_consumed_at = Some(new_connection.created_at);
+        new_connection
+    }
+
+    pub fn expires_in(mut self, ttl: Duration) -> Self {
+        self.nonce_expires_at = self.created_at + ttl;
+        self
+    }
+
+    pub fn insert(&self, conn: &PgConnection) -> WooCommerceConnection {
+        create(conn, self)
+    }
+}
+
+pub fn create(
+    conn: &PgConnection,
+    new_connection: &NewWooCommerceConnection,
+) -> WooCommerceConnection {
+    diesel::insert_into(woocommerce_connections::table)
+        .values(new_connection)
+        .get_result(conn)
+        .expect("Error saving new woocommerce_connection")
+}
+
+pub fn read_by_store_url(conn: &PgConnection, store_url: String) -> Vec<WooCommerceConnection> {
+    woocommerce_connections::table
+        .filter(woocommerce_connections::store_url.eq(store_url))
+        .load::<WooCommerceConnection>(conn)
+        .expect("Error loading woocommerce_connection")
+}
+
+// woocommerce only tells us which install it's finishing by the nonce
+pub fn read_by_nonce(conn: &PgConnection, nonce: String) -> Option<WooCommerceConnection> {
+    woocommerce_connections::table
+        .filter(woocommerce_connections::nonce.eq(nonce))
+        .first::<WooCommerceConnection>(conn)
+        .optional()
+        .expect("Error loading woocommerce_connection")
+}
+
+// the connection for a store that finished installing and hasn't left
+pub fn read_installed_by_store_url(
+    conn: &PgConnection,
+    store_url: String,
+) -> Option<WooCommerceConnection> {
+    woocommerce_connections::table
+        .filter(woocommerce_connections::store_url.eq(store_url))
+        .filter(woocommerce_connections::consumer_secret.is_not_null())
+        .filter(woocommerce_connections::active.eq(true))
+        .filter(woocommerce_connections::deleted_at.is_null())
+        .first::<WooCommerceConnection>(conn)
+        .optional()
+        .expect("Error loading woocommerce_connection")
+}
+
+// marks the nonce as used and hands back the pending connection,
+// see shopify_connection::consume_nonce
+pub fn consume_nonce(
+    conn: &PgConnection,
+    nonce: String,
+) -> QueryResult<Option<WooCommerceConnection>> {
+    let current_time = now();
+
+    diesel::update(
+        woocommerce_connections::table
+            .filter(woocommerce_connections::nonce.eq(nonce))
+            .filter(woocommerce_connections::nonce_consumed_at.is_null())
+            .filter(woocommerce_connections::nonce_expires_at.gt(current_time))
+            .filter(woocommerce_connections::deleted_at.is_null()),
+    )
+    .set(woocommerce_connections::nonce_consumed_at.eq(current_time))
+    .get_result::<WooCommerceConnection>(conn)
+    .optional()
+}
+
+// installs that never came back from woocommerce
+pub fn soft_delete_expired_pending(conn: &PgConnection) -> QueryResult<usize> {
+    let current_time = now();
+
+    diesel::update(
+        woocommerce_connections::table
+            .filter(woocommerce_connections::consumer_secret.is_null())
+            .filter(woocommerce_connections::deleted_at.is_null())
+            .filter(woocommerce_connections::nonce_expires_at.le(current_time)),
+    )
+    .set((
+        woocommerce_connections::active.eq(false),
+        woocommerce_connections::deleted_at.eq(current_time),
+        woocommerce_connections::updated_at.eq(current_time),
+    ))
+    .execute(conn)
+}
+
+pub fn update_credentials(
+    conn: &PgConnection,
+    cipher: &TokenCipher,
+    connection: &WooCommerceConnection,
+    consumer_key: String,
+    consumer_secret: String,
+    key_permissions: String,
+    webhook_secret: String,
+) -> QueryResult<usize> {
+    let sealed = cipher.seal(&connection.store_url, &consumer_secret);
+    let sealed_webhook_secret = cipher.seal(&connection.store_url, &webhook_secret);
+
+    conn.transaction(|| {
+        retire_other_installs(conn, connection)?;
+
+        diesel::update(connection)
+            .set((
+                woocommerce_connections::consumer_key.eq(consumer_key),
+                woocommerce_connections::consumer_secret.eq(sealed.ciphertext),
+                woocommerce_connections::consumer_secret_data_key.eq(sealed.data_key),
+                woocommerce_connections::consumer_secret_key_id.eq(sealed.key_id),
+                woocommerce_connections::key_permissions.eq(key_permissions),
+                woocommerce_connections::webhook_secret.eq(sealed_webhook_secret.ciphertext),
+                woocommerce_connections::webhook_secret_data_key.eq(sealed_webhook_secret.data_key),
+                woocommerce_connections::webhook_secret_key_id.eq(sealed_webhook_secret.key_id),
+                woocommerce_connections::updated_at.eq(now()),
+            ))
+            .execute(conn)
+    })
+}
+
+// a store approving us again replaces its earlier install,
+// see shopify_connection::retire_other_installs
+fn retire_other_installs(
+    conn: &PgConnection,
+    connection: &WooCommerceConnection,
+) -> QueryResult<usize> {
+    let current_time = now();
+
+    diesel::update(
+        woocommerce_connections::table
+            .filter(woocommerce_connections::store_url.eq(&connection.store_url))
+            .filter(woocommerce_connections::id.ne(connection.id))
+            .filter(woocommerce_connections::consumer_secret.is_not_null())
+            .filter(woocommerce_connections::deleted_at.is_null()),
+    )
+    .set((
+        woocommerce_connections::consumer_key.eq(None::<String>),
+        woocommerce_connections::consumer_secret.eq(None::<String>),
+        woocommerce_connections::consumer_secret_data_key.eq(None::<String>),
+        woocommerce_connections::consumer_secret_key_id.eq(None::<String>),
+        woocommerce_connections::webhook_secret.eq(None::<String>),
+        woocommerce_connections::webhook_secret_data_key.eq(None::<String>),
+        woocommerce_connections::webhook_secret_key_id.eq(None::<String>),
+        woocommerce_connections::active.eq(false),
+        woocommerce_connections::deleted_at.eq(current_time),
+        woocommerce_connections::updated_at.eq(current_time),
+    ))
+    .execute(conn)
+}
+
+// reseal a batch of consumer and webhook secrets that aren't under the active key,
+// see shopify_connection::rotate_access_token_keys
+pub fn rotate_consumer_secret_keys(
+    conn: &PgConnection,
+    cipher: &TokenCipher,
+    after_id: i32,
+    batch_size: i64,
+) -> QueryResult<Option<i32>> {
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        let batch = woocommerce_connections::table
+            .filter(woocommerce_connections::id.gt(after_id))
+            .filter(woocommerce_connections::consumer_secret.is_not_null())
+            .filter(
+                woocommerce_connections::consumer_secret_key_id
+                    .ne(cipher.active_key_id())
+                    .or(woocommerce_connections::webhook_secret_key_id.ne(cipher.active_key_id())),
+            )
+            .order(woocommerce_connections::id.asc())
+            .limit(batch_size)
+            .for_update()
+            .load::<WooCommerceConnection>(conn)?;
+
+        for connection in batch.iter() {
+            let resealed = match (
+                &connection.consumer_secret_data_key,
+                &connection.consumer_secret_key_id,
+            ) {
+                (Some(data_key), Some(key_id)) => {
+                    cipher.reseal_data_key(&connection.store_url, data_key, key_id)
+                }
+                _ => Err(EncryptionError::Malformed),
+            };
+            let resealed_webhook_secret = match (
+                &connection.webhook_secret_data_key,
+                &connection.webhook_secret_key_id,
+            ) {
+                (Some(data_key), Some(key_id)) => cipher
+                    .reseal_data_key(&connection.store_url, data_key, key_id)
+                    .map(|(data_key, key_id)| (Some(data_key), Some(key_id))),
+                (None, None) => Ok((None, None)),
+                _ => Err(EncryptionError::Malformed),
+            };
+
+            match (resealed, resealed_webhook_secret) {
+                (Ok((data_key, key_id)), Ok((webhook_data_key, webhook_key_id))) => {
+                    diesel::update(connection)
+                        .set((
+                            woocommerce_connections::consumer_secret_data_key.eq(data_key),
+                            woocommerce_connections::consumer_secret_key_id.eq(key_id),
+                            woocommerce_connections::webhook_secret_data_key.eq(webhook_data_key),
+                            woocommerce_connections::webhook_secret_key_id.eq(webhook_key_id),
+                        ))
+                        .execute(conn)?;
+                }
+                (Err(e), _) | (_, Err(e)) => log::error!(
+                    "could not rotate the secrets for connection {}: {}",
+                    connection.id,
+                    e
+                ),
+            }
+        }
+
+        Ok(batch.last().map(|connection| connection.id))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::TokenKey;
+    use crate::establish_connection_test;
+
+    fn cleanup_table(conn: &PgConnection) {
+        diesel::delete(woocommerce_connections::table)
+            .execute(conn)
+            .unwrap();
+    }
+
+    fn test_cipher() -> TokenCipher {
+        TokenCipher::new(vec![TokenKey::new("test-1", [7; 32])])
+    }
+
+    fn mock_struct() -> NewWooCommerceConnection {
+        NewWooCommerceConnection::new(
+            String::from("https://shop.example.com"),
+            String::from("8c3f2c6f2f5b4e3c9a1d7e6b5a4c3d2e"),
+        )
+    }
+
+    #[test]
+    fn it_consumes_a_nonce_only_once() {
+        let conn = establish_connection_test();
+        let new_connection = mock_struct();
+        create(&conn, &new_connection);
+
+        let consumed = consume_nonce(&conn, new_connection.nonce.clone()).unwrap();
+        assert!(consumed.is_some());
+        assert!(consumed.unwrap().nonce_consumed_at.is_some());
+
+        let consumed_again = consume_nonce(&conn, new_connection.nonce.clone()).unwrap();
+        assert!(consumed_again.is_none());
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_reads_an_installed_woocommerce_connection_with_its_secret() {
+        let conn = establish_connection_test();
+
+        let pending = create(&conn, &mock_struct());
+        assert!(read_installed_by_store_url(&conn, pending.store_url.clone()).is_none());
+
+        update_credentials(
+            &conn,
+            &test_cipher(),
+            &pending,
+            String::from("ck_4b3a2c1d"),
+            String::from("cs_9f8e7d6c"),
+            String::from("read_write"),
+            String::from("wh_5e4d3c2b"),
+        )
+        .unwrap();
+
+        let installed = read_installed_by_store_url(&conn, pending.store_url.clone()).unwrap();
+        assert_eq!(installed.id, pending.id);
+        assert_ne!(installed.consumer_secret.as_deref(), Some("cs_9f8e7d6c"));
+        assert_eq!(
+            installed.decrypt_consumer_secret(&test_cipher()).unwrap(),
+            Some(String::from("cs_9f8e7d6c"))
+        );
+        assert_eq!(
+            installed.decrypt_webhook_secret(&test_cipher()).unwrap(),
+            Some(String::from("wh_5e4d3c2b"))
+        );
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_retires_the_earlier_install_when_a_store_installs_again() {
+        let conn = establish_connection_test();
+        let earlier = NewWooCommerceConnection::installed(
+            &test_cipher(),
+            String::from("https://shop.example.com"),
+            String::from("earlier-nonce"),
+            String::from("ck_4b3a2c1d"),
+            String::from("cs_9f8e7d6c"),
+            String::from("read_write"),
+            String::from("wh_5e4d3c2b"),
+        )
+        .insert(&conn);
+        let pending = create(&conn, &mock_struct());
+
+        update_credentials(
+            &conn,
+            &test_cipher(),
+            &pending,
+            String::from("ck_1a2b3c4d"),
+            String::from("cs_5e6f7a8b"),
+            String::from("read_write"),
+            String::from("wh_9c8d7e6f"),
+        )
+        .unwrap();
+
+        let installed = read_installed_by_store_url(&conn, pending.store_url.clone()).unwrap();
+        assert_eq!(installed.id, pending.id);
+        let retired = read_by_store_url(&conn, earlier.store_url.clone())
+            .into_iter()
+            .find(|connection| connection.id == earlier.id)
+            .unwrap();
+        assert!(!retired.active);
+        assert!(retired.deleted_at.is_some());
+        assert!(retired.consumer_secret.is_none());
+        assert!(retired.webhook_secret.is_none());
+
+        cleanup_table(&conn);
+    }
+
+    #[test]
+    fn it_rotates_consumer_secrets_to_the_active_key() {
+        let conn = establish_connection_test();
+        let old = TokenCipher::new(vec![TokenKey::new("v1", [1; 32])]);
+        let rotated = TokenCipher::new(vec![
+            TokenKey::new("v2", [2; 32]),
+            TokenKey::new("v1", [1; 32]),
+        ]);
+
+        let installed = NewWooCommerceConnection::installed(
+            &old,
+            String::from("https://shop.example.com"),
+            String::from("some-nonce"),
+            String::from("ck_4b3a2c1d"),
+            String::from("cs_9f8e7d6c"),
+            String::from("read_write"),
+            String::from("wh_5e4d3c2b"),
+        )
+        .insert(&conn);
+
+        assert_eq!(
+            rotate_consumer_secret_keys(&conn, &rotated, 0, 10).unwrap(),
+            Some(installed.id)
+        );
+        assert_eq!(
+            rotate_consumer_secret_keys(&conn, &rotated, installed.id, 10).unwrap(),
+            None
+        );
+
+        let new = TokenCipher::new(vec![TokenKey::new("v2", [2; 32])]);
+        let connection = read_by_store_url(&conn, installed.store_url.clone()).remove(0);
+        assert_eq!(
+            connection.decrypt_consumer_secret(&new).unwrap(),
+            Some(String::from("cs_9f8e7d6c"))
+        );
+        assert_eq!(
+            connection.decrypt_webhook_secret(&new).unwrap(),
+            Some(String::from("wh_5e4d3c2b"))
+        );
+
+        cleanup_table(&conn);
+    }
+}
@@ -0,0 +1,149 @@
+pub mod shopify;
+pub mod woocommerce;
+
+use crate::{
+    errors::{AdminApiError, OAuthError, WebhookError},
+    webhooks::Webhook,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use warp::{
+    http::{HeaderMap, Uri},
+    hyper::body::Bytes,
+};
+
+// whatever the platform sent us once the merchant approved the install,
+// a signed redirect for some, a request with credentials in it for others
+pub struct Callback {
+    pub query: String,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+// a callback that checked out, ready to be traded for credentials.
+// the grant is whatever the platform trades, e.x. shopify's authorization code
+// or woocommerce's consumer key and secret as key:secret
+#[derive(Debug)]
+pub struct Authorization {
+    pub store: String,
+    pub nonce: String,
+    pub grant: String,
+}
+
+// how we answer the callback once the store is installed
+pub enum CallbackReply {
+    Redirect(Uri),
+    Accepted,
+}
+
+// a gift card as the rest of the service sees it, ids are strings
+// since every platform numbers them its own way
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct GiftCard {
+    pub id: String,
+    // the full code is only ever known when the card is created
+    pub code: Option<String>,
+    pub last_characters: String,
+    pub balance: String,
+    pub initial_value: String,
+    pub currency: Option<String>,
+    pub note: Option<String>,
+    pub expires_on: Option<String>,
+    pub disabled: bool,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct NewGiftCard {
+    pub initial_value: String,
+    // left out and the platform makes one up
+    pub code: Option<String>,
+    pub note: Option<String>,
+    pub expires_on: Option<String>,
+}
+
+// everything we need from an e-commerce platform. `app` is the route's
+// app segment, see routes::app_segment, platforms with only the one app ignore it
+#[async_trait]
+pub trait CommercePlatform: Send + Sync {
+    // routes are mounted under it, e.x. /shopify_install and /webhooks/shopify
+    fn name(&self) -> &'static str;
+
+    // the kind of job its webhooks wait in the queue as, see webhooks::WebhookJobs
+    fn webhook_job(&self) -> &'static str;
+
+    // checks the merchant's install request and hands back their store
+    fn verify_install(&self, app: Option<&str>, query: &str) -> Result<String, OAuthError>;
+
+    // saves the pending install and builds where the merchant goes to approve us
+    fn authorize_url(&self, app: Option<&str>, store: &str) -> Result<Uri, OAuthError>;
+
+    // checks the platform sent the callback, each nonce only gets through once
+    fn verify_callback(
+        &self,
+        app: Option<&str>,
+        callback: &Callback,
+    ) -> Result<Authorization, OAuthError>;
+
+    // trades the authorization for credentials and saves the store as installed
+    async fn exchange_token(
+        &self,
+        app: Option<&str>,
+        authorization: Authorization,
+    ) -> Result<CallbackReply, OAuthError>;
+
+    // checks the webhook came from the platform, nothing is parsed before then
+    fn verify_webhook(
+        &self,
+        app: Option<&str>,
+        headers: &HeaderMap,
+        body: Bytes,
+    ) -> Result<Webhook, WebhookError>;
+
+    async fn create_gift_card(
+        &self,
+        app: Option<&str>,
+        store: &str,
+        gift_card: &NewGiftCard,
+    ) -> Result<GiftCard, AdminApiError>;
+
+    async fn gift_card(
+        &self,
+        app: Option<&str>,
+        store: &str,
+        id: &str,
+    ) -> Result<GiftCard, AdminApiError>;
+
+    async fn disable_gift_card(
+        &self,
+        app: Option<&str>,
+        store: &str,
+        id: &str,
+    ) -> Result<GiftCard, AdminApiError>;
+}
+
+// every platform we integrate with, each one gets its own set of routes
+#[derive(Default)]
+pub struct PlatformRegistry {
+    platforms: HashMap<&'static str, Arc<dyn CommercePlatform>>,
+}
+
+impl PlatformRegistry {
+    pub fn new() -> Self {
+        PlatformRegistry::default()
+    }
+
+    pub fn register(mut self, platform: Arc<dyn CommercePlatform>) -> Self {
+        self.platforms.insert(platform.name(), platform);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn CommercePlatform>> {
+        self.platforms.get(name).cloned()
+    }
+
+    pub fn platforms(&self) -> impl Iterator<Item = Arc<dyn CommercePlatform>> + '_ {
+        self.platforms.values().cloned()
+    }
+}
@@ -0,0 +1,464 @@
+use super::{Authorization, Callback, CallbackReply, CommercePlatform, GiftCard, NewGiftCard};
+use crate::{
+    config::{AccessMode, Config, ShopifyApp},
+    db_conn::DbConn,
+    errors::{AdminApiError, OAuthError, WebhookError},
+    models::{
+        shopify_connection::{self, NewShopifyConnection},
+        shopify_online_token::NewShopifyOnlineToken,
+    },
+    services::{
+        rate_limiter::RateLimiter,
+        shopify_admin::{resources, AdminClient},
+        shopify_service, webhook_subscription_service,
+    },
+    utils::{gen_uuid, now_timestamp},
+    verification::{
+        matching_secret, missing_scopes, verify_query_hmac, verify_timestamp, verify_webhook_hmac,
+    },
+    webhooks::{
+        Webhook, HMAC_HEADER, SHOP_DOMAIN_HEADER, TOPIC_HEADER, WEBHOOK_ID_HEADER, WEBHOOK_JOB,
+    },
+    ConfirmQueryParams, InstallQueryParams,
+};
+use async_trait::async_trait;
+use chrono::Duration;
+use diesel::PgConnection;
+use lazy_regex::regex;
+use reqwest::Client;
+use std::sync::Arc;
+use warp::{
+    http::{HeaderMap, Uri},
+    hyper::body::Bytes,
+};
+
+pub const NAME: &str = "shopify";
+
+// how many times we send a shop back to grant scopes it left out before giving up
+pub const MAX_SCOPE_PROMPTS: i32 = 2;
+
+pub struct ShopifyPlatform {
+    config: Arc<Config>,
+    db_conn: Arc<DbConn>,
+    client: Arc<Client>,
+    rate_limiter: Arc<RateLimiter>,
+}
+
+impl ShopifyPlatform {
+    pub fn new(
+        config: Arc<Config>,
+        db_conn: Arc<DbConn>,
+        client: Arc<Client>,
+        rate_limiter: Arc<RateLimiter>,
+    ) -> Self {
+        ShopifyPlatform {
+            config,
+            db_conn,
+            client,
+            rate_limiter,
+        }
+    }
+
+    fn app(&self, app: Option<&str>) -> Option<&ShopifyApp> {
+        self.config.shopify_app(app)
+    }
+
+    // save install request in db to verify later,
+    // then send the shop off to approve our scopes
+    fn request_authorization(
+        &self,
+        app: &ShopifyApp,
+        conn: &PgConnection,
+        shop: &str,
+        scope_prompts: i32,
+    ) -> Uri {
+        let nonce = gen_uuid();
+
+        NewShopifyConnection::new(shop.to_string(), nonce.clone())
+            .for_app(&app.handle)
+            .prompted(scope_prompts)
+            .expires_in(Duration::seconds(self.config.nonce_ttl_secs))
+            .insert(conn);
+
+        authorize_uri(&self.config, app, shop, &nonce)
+            .parse::<Uri>()
+            .unwrap()
+    }
+
+    // a shop can have more than one of our apps installed, each with its own token
+    fn admin_client(&self, app: Option<&str>, shop: &str) -> Result<AdminClient, AdminApiError> {
+        let app = self.app(app).ok_or(AdminApiError::UnknownApp)?;
+        let installed = shopify_connection::read_installed_by_app_and_shop(
+            &self.db_conn.get_conn(),
+            app.handle.clone(),
+            shop.to_string(),
+        )
+        .ok_or(AdminApiError::MissingAccessToken)?;
+
+        AdminClient::new(
+            self.client.clone(),
+            self.rate_limiter.clone(),
+            &self.config,
+            &installed,
+        )
+    }
+}
+
+#[async_trait]
+impl CommercePlatform for ShopifyPlatform {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn webhook_job(&self) -> &'static str {
+        WEBHOOK_JOB
+    }
+
+    // when shopkeep requests to install our app,
+    // they will click a link taking them here
+    fn verify_install(&self, app: Option<&str>, query: &str) -> Result<String, OAuthError> {
+        let app = self.app(app).ok_or(OAuthError::UnknownApp)?;
+        let params: InstallQueryParams =
+            serde_urlencoded::from_str(query).map_err(|_| OAuthError::InvalidRequest)?;
+
+        validate_shop(&params.shop)?;
+
+        // don't touch the db until we know shopify sent this
+        if matching_secret(&app.secrets(), "install", |secret| {
+            verify_query_hmac(secret, query)
+        })
+        .is_none()
+        {
+            return Err(OAuthError::InvalidHmac);
+        }
+
+        if !verify_timestamp(
+            &params.timestamp,
+            now_timestamp(),
+            self.config.timestamp_skew_secs,
+        ) {
+            return Err(OAuthError::StaleTimestamp);
+        }
+
+        Ok(params.shop)
+    }
+
+    // We redirect them back to their store's domain
+    // to request access to x,y,z scope/permissions.
+    //
+    // e.x. https://{shop}.myshopify.com/admin/oauth/authorize
+    //          ?client_id={api_key}
+    //          &scope={scopes}
+    //          &redirect_uri={redirect_uri}
+    //          &state={nonce}
+    //          &grant_options[]={access_mode}
+    fn authorize_url(&self, app: Option<&str>, shop: &str) -> Result<Uri, OAuthError> {
+        let app = self.app(app).ok_or(OAuthError::UnknownApp)?;
+
+        Ok(self.request_authorization(app, &self.db_conn.get_conn(), shop, 0))
+    }
+
+    // https://example.org/some/redirect/uri?code={authorization_code}&hmac=da9d83c171400a41f8db91a950508985&host={base64_encoded_hostname}&timestamp=1409617544&state={nonce}&shop={shop_origin}
+    fn verify_callback(
+        &self,
+        app: Option<&str>,
+        callback: &Callback,
+    ) -> Result<Authorization, OAuthError> {
+        let app = self.app(app).ok_or(OAuthError::UnknownApp)?;
+        let params: ConfirmQueryParams =
+            serde_urlencoded::from_str(&callback.query).map_err(|_| OAuthError::InvalidRequest)?;
+
+        validate_shop(&params.shop)?;
+
+        if matching_secret(&app.secrets(), "confirm", |secret| {
+            verify_query_hmac(secret, &callback.query)
+        })
+        .is_none()
+        {
+            return Err(OAuthError::InvalidHmac);
+        }
+
+        if !verify_timestamp(
+            &params.timestamp,
+            now_timestamp(),
+            self.config.timestamp_skew_secs,
+        ) {
+            return Err(OAuthError::StaleTimestamp);
+        }
+
+        // try and claim the shop without the completed request,
+        // each nonce can only be traded for an access token once
+        let conn = self.db_conn.get_conn();
+        shopify_connection::consume_nonce(&conn, params.shop.clone(), params.state.clone())?
+            .ok_or_else(|| {
+                unconsumable_nonce_error(&conn, params.shop.clone(), params.state.clone())
+            })?;
+
+        Ok(Authorization {
+            store: params.shop,
+            nonce: params.state,
+            grant: params.code,
+        })
+    }
+
+    // POST https://{shop}.myshopify.com/admin/oauth/access_token
+    async fn exchange_token(
+        &self,
+        app: Option<&str>,
+        authorization: Authorization,
+    ) -> Result<CallbackReply, OAuthError> {
+        let app = self.app(app).ok_or(OAuthError::UnknownApp)?;
+        let shop = &authorization.store;
+
+        let form_body = form_body_from_args(
+            app.api_key.clone(),
+            app.api_secret.clone(),
+            authorization.grant.clone(),
+        );
+
+        let access_token_json = match shopify_service::get_access_token(
+            self.client.clone(),
+            &self.rate_limiter,
+            &self.config,
+            shop,
+            form_body,
+        )
+        .await
+        {
+            Ok(access_token_json) => access_token_json,
+            Err(e) => {
+                // the shop did nothing wrong, let it confirm again
+                if let Err(e) = shopify_connection::release_nonce(
+                    &self.db_conn.get_conn(),
+                    shop.clone(),
+                    authorization.nonce.clone(),
+                ) {
+                    log::error!("could not release the nonce for {}: {:?}", shop, e);
+                }
+                return Err(e.into());
+            }
+        };
+
+        let conn = self.db_conn.get_conn();
+
+        let shop_conn = shopify_connection::read_by_shop_and_nonce(
+            &conn,
+            shop.clone(),
+            authorization.nonce.clone(),
+        )
+        .into_iter()
+        .next()
+        .ok_or(OAuthError::UnknownNonce)?;
+
+        // shopify counts us as installed with whatever the shop granted,
+        // so the token is kept even when it's missing scopes. online access
+        // tokens belong to the staff member who approved us and expire,
+        // only offline ones are the shop's
+        match NewShopifyOnlineToken::from_access_token_response(
+            &self.config.token_cipher(),
+            &app.handle,
+            shop,
+            &access_token_json,
+        ) {
+            Some(online_token) => {
+                online_token.upsert(&conn)?;
+                shopify_connection::mark_installed(
+                    &conn,
+                    &shop_conn,
+                    access_token_json.scope.clone(),
+                )?;
+            }
+            None => {
+                shopify_connection::update_access_token(
+                    &conn,
+                    &self.config.token_cipher(),
+                    &shop_conn,
+                    access_token_json.access_token.clone(),
+                    access_token_json.scope.clone(),
+                )?;
+            }
+        }
+
+        let missing = missing_scopes(&self.config.shopify_scopes, &access_token_json.scope);
+        if !missing.is_empty() {
+            if shop_conn.scope_prompts >= MAX_SCOPE_PROMPTS {
+                log::warn!(
+                    "{} still did not grant {}, giving up",
+                    shop,
+                    missing.join(",")
+                );
+                return Err(OAuthError::MissingScopes);
+            }
+
+            log::warn!("{} did not grant {}, asking again", shop, missing.join(","));
+            return Ok(CallbackReply::Redirect(self.request_authorization(
+                app,
+                &conn,
+                shop,
+                shop_conn.scope_prompts + 1,
+            )));
+        }
+
+        // the install stands even if some of these fail, they get retried later
+        webhook_subscription_service::sync_subscriptions(
+            self.client.clone(),
+            self.rate_limiter.clone(),
+            &self.config,
+            app,
+            &self.db_conn,
+            shop,
+            &access_token_json.access_token,
+        )
+        .await;
+
+        // gotta figure out the reply later
+        Ok(CallbackReply::Redirect(
+            String::from("/").parse::<Uri>().unwrap(),
+        ))
+    }
+
+    fn verify_webhook(
+        &self,
+        app: Option<&str>,
+        headers: &HeaderMap,
+        body: Bytes,
+    ) -> Result<Webhook, WebhookError> {
+        let app = self.app(app).ok_or(WebhookError::UnknownApp)?;
+
+        let verified = header(headers, HMAC_HEADER)
+            .map(|hmac| {
+                matching_secret(&app.secrets(), "webhook", |secret| {
+                    verify_webhook_hmac(secret, &body, hmac)
+                })
+                .is_some()
+            })
+            .unwrap_or(false);
+        if !verified {
+            return Err(WebhookError::InvalidHmac);
+        }
+
+        Ok(Webhook {
+            app: app.handle.clone(),
+            topic: header(headers, TOPIC_HEADER)?.to_string(),
+            shop_domain: header(headers, SHOP_DOMAIN_HEADER)?.to_string(),
+            webhook_id: header(headers, WEBHOOK_ID_HEADER)?.to_string(),
+            body,
+        })
+    }
+
+    async fn create_gift_card(
+        &self,
+        app: Option<&str>,
+        shop: &str,
+        gift_card: &NewGiftCard,
+    ) -> Result<GiftCard, AdminApiError> {
+        let new_gift_card = resources::NewGiftCard {
+            initial_value: gift_card.initial_value.clone(),
+            code: gift_card.code.clone(),
+            note: gift_card.note.clone(),
+            expires_on: gift_card.expires_on.clone(),
+            ..Default::default()
+        };
+
+        self.admin_client(app, shop)?
+            .create_gift_card(&new_gift_card)
+            .await
+            .map(GiftCard::from)
+    }
+
+    async fn gift_card(
+        &self,
+        app: Option<&str>,
+        shop: &str,
+        id: &str,
+    ) -> Result<GiftCard, AdminApiError> {
+        self.admin_client(app, shop)?
+            .gift_card(gift_card_id(id)?)
+            .await
+            .map(GiftCard::from)
+    }
+
+    async fn disable_gift_card(
+        &self,
+        app: Option<&str>,
+        shop: &str,
+        id: &str,
+    ) -> Result<GiftCard, AdminApiError> {
+        self.admin_client(app, shop)?
+            .disable_gift_card(gift_card_id(id)?)
+            .await
+            .map(GiftCard::from)
+    }
+}
+
+impl From<resources::GiftCard> for GiftCard {
+    fn from(gift_card: resources::GiftCard) -> Self {
+        GiftCard {
+            id: gift_card.id.to_string(),
+            code: gift_card.code,
+            last_characters: gift_card.last_characters,
+            balance: gift_card.balance,
+            initial_value: gift_card.initial_value,
+            currency: gift_card.currency,
+            note: gift_card.note,
+            expires_on: gift_card.expires_on,
+            disabled: gift_card.disabled_at.is_some(),
+        }
+    }
+}
+
+// shopify's ids are numbers, anything else can't be one of its cards
+fn gift_card_id(id: &str) -> Result<i64, AdminApiError> {
+    id.parse().map_err(|_| AdminApiError::NotFound)
+}
+
+// uri for the confirm install page
+fn authorize_uri(config: &Config, app: &ShopifyApp, shop: &str, nonce: &str) -> String {
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    query
+        .append_pair("client_id", &app.api_key)
+        .append_pair("scope", &config.shopify_scopes.join(","))
+        .append_pair("redirect_uri", &config.app_url_for(app, "shopify_confirm"))
+        .append_pair("state", nonce);
+
+    if config.shopify_access_mode == AccessMode::Online {
+        query.append_pair("grant_options[]", "per-user");
+    }
+
+    format!("https://{}/admin/oauth/authorize?{}", shop, query.finish())
+}
+
+// figure out why we couldn't claim the nonce so the shop gets a useful error
+fn unconsumable_nonce_error(conn: &PgConnection, shop: String, nonce: String) -> OAuthError {
+    match shopify_connection::read_by_shop_and_nonce(conn, shop, nonce).first() {
+        Some(shop_conn) if shop_conn.nonce_consumed_at.is_some() => OAuthError::NonceAlreadyUsed,
+        Some(_) => OAuthError::NonceExpired,
+        None => OAuthError::UnknownNonce,
+    }
+}
+
+// only ever redirect to or talk with a real shopify store
+pub fn validate_shop(shop: &str) -> Result<(), OAuthError> {
+    let r = regex!("^[a-zA-Z0-9][a-zA-Z0-9\\-]*\\.myshopify\\.com$");
+    if r.is_match(shop) {
+        Ok(())
+    } else {
+        Err(OAuthError::InvalidShop)
+    }
+}
+
+// setup the form body to request the access token from shopify api
+fn form_body_from_args(api_key: String, api_secret: String, code: String) -> Vec<(String, String)> {
+    vec![
+        (String::from("client_id"), api_key),
+        (String::from("client_secret"), api_secret),
+        (String::from("code"), code),
+    ]
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &'static str) -> Result<&'a str, WebhookError> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(WebhookError::MissingHeader(name))
+}
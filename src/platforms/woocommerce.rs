@@ -0,0 +1,501 @@
+use super::{Authorization, Callback, CallbackReply, CommercePlatform, GiftCard, NewGiftCard};
+use crate::{
+    config::{Config, WOOCOMMERCE_APP},
+    db_conn::DbConn,
+    errors::{AdminApiError, OAuthError, WebhookError},
+    models::woocommerce_connection::{self, NewWooCommerceConnection},
+    services::woocommerce_api::{Coupon, NewCoupon, NewWebhook, WooCommerceClient},
+    utils::{gen_uuid, now},
+    verification::verify_webhook_hmac,
+    webhooks::Webhook,
+};
+use async_trait::async_trait;
+use chrono::{Duration, NaiveDateTime};
+use diesel::PgConnection;
+use lazy_regex::regex;
+use rand::{rngs::OsRng, RngCore};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::json;
+use std::net::IpAddr;
+use std::sync::Arc;
+use warp::{
+    http::{HeaderMap, Uri},
+    hyper::body::Bytes,
+};
+
+pub const NAME: &str = "woocommerce";
+
+pub const SIGNATURE_HEADER: &str = "x-wc-webhook-signature";
+pub const TOPIC_HEADER: &str = "x-wc-webhook-topic";
+pub const SOURCE_HEADER: &str = "x-wc-webhook-source";
+pub const DELIVERY_ID_HEADER: &str = "x-wc-webhook-delivery-id";
+
+// the kind of job our webhooks are queued up as, see webhooks::WEBHOOK_JOB
+pub const WEBHOOK_JOB: &str = "woocommerce_webhook";
+
+// sent unsigned when a webhook is first set up, nothing handles it
+pub const PING_TOPIC: &str = "woocommerce/ping";
+
+const DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+#[derive(Deserialize)]
+struct InstallQueryParams {
+    store_url: String,
+}
+
+// what woocommerce posts to our callback once the merchant approves us,
+// user_id is whatever we sent along, our nonce
+#[derive(Deserialize)]
+struct KeyDelivery {
+    user_id: String,
+    consumer_key: String,
+    consumer_secret: String,
+    key_permissions: String,
+}
+
+pub struct WooCommercePlatform {
+    config: Arc<Config>,
+    db_conn: Arc<DbConn>,
+    client: Arc<Client>,
+}
+
+impl WooCommercePlatform {
+    pub fn new(config: Arc<Config>, db_conn: Arc<DbConn>, client: Arc<Client>) -> Self {
+        WooCommercePlatform {
+            config,
+            db_conn,
+            client,
+        }
+    }
+
+    fn api_client(&self, store_url: &str) -> Result<WooCommerceClient, AdminApiError> {
+        let installed = woocommerce_connection::read_installed_by_store_url(
+            &self.db_conn.get_conn(),
+            store_url.to_string(),
+        )
+        .ok_or(AdminApiError::MissingAccessToken)?;
+
+        WooCommerceClient::new(self.client.clone(), &self.config, &installed)
+    }
+
+    // anyone can say a webhook came from any store, so only the secret
+    // we gave that store will do
+    fn webhook_secret(&self, store_url: &str) -> Result<String, WebhookError> {
+        let installed = woocommerce_connection::read_installed_by_store_url(
+            &self.db_conn.get_conn(),
+            store_url.to_string(),
+        )
+        .ok_or(WebhookError::InvalidHmac)?;
+
+        match installed.decrypt_webhook_secret(&self.config.token_cipher()) {
+            Ok(Some(secret)) => Ok(secret),
+            // installed before stores got their own, it has to install again
+            Ok(None) => Err(WebhookError::InvalidHmac),
+            Err(e) => {
+                log::error!("could not open the webhook secret for {}: {}", store_url, e);
+                Err(WebhookError::InvalidHmac)
+            }
+        }
+    }
+
+    // POST https://{store}/wp-json/wc/v3/webhooks for each topic we want,
+    // the install stands even if some of these fail
+    async fn create_webhooks(
+        &self,
+        store_url: &str,
+        consumer_key: &str,
+        consumer_secret: &str,
+        secret: &str,
+    ) {
+        let api = WooCommerceClient::with_keys(
+            self.client.clone(),
+            store_url,
+            consumer_key.to_string(),
+            consumer_secret.to_string(),
+        );
+        let delivery_url = format!(
+            "{}/webhooks/{}",
+            self.config.app_url.trim_end_matches('/'),
+            NAME
+        );
+
+        for topic in self.config.woocommerce_webhook_topics.iter() {
+            let webhook = NewWebhook {
+                name: format!("{} {}", self.config.woocommerce_app_name, topic),
+                topic: topic.clone(),
+                delivery_url: delivery_url.clone(),
+                secret: secret.to_string(),
+            };
+
+            if let Err(e) = api.create_webhook(&webhook).await {
+                log::warn!("could not subscribe {} to {}: {:?}", store_url, topic, e);
+            }
+        }
+    }
+}
+
+// woocommerce has no app listings of its own, there's only ever the one
+fn single_app<E>(app: Option<&str>, unknown: E) -> Result<(), E> {
+    match app {
+        None => Ok(()),
+        Some(_) => Err(unknown),
+    }
+}
+
+#[async_trait]
+impl CommercePlatform for WooCommercePlatform {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn webhook_job(&self) -> &'static str {
+        WEBHOOK_JOB
+    }
+
+    // merchants start from our site with their store's address,
+    // e.x. /woocommerce_install?store_url=https://shop.example.com
+    fn verify_install(&self, app: Option<&str>, query: &str) -> Result<String, OAuthError> {
+        single_app(app, OAuthError::UnknownApp)?;
+        let params: InstallQueryParams =
+            serde_urlencoded::from_str(query).map_err(|_| OAuthError::InvalidRequest)?;
+
+        validate_store_url(&params.store_url, self.config.is_mocking)
+    }
+
+    // e.x. https://shop.example.com/wc-auth/v1/authorize
+    //          ?app_name={app_name}
+    //          &scope={scope}
+    //          &user_id={nonce}
+    //          &return_url={app_url}
+    //          &callback_url={app_url}/woocommerce_confirm
+    fn authorize_url(&self, app: Option<&str>, store_url: &str) -> Result<Uri, OAuthError> {
+        single_app(app, OAuthError::UnknownApp)?;
+        let nonce = gen_uuid();
+
+        NewWooCommerceConnection::new(store_url.to_string(), nonce.clone())
+            .expires_in(Duration::seconds(self.config.nonce_ttl_secs))
+            .insert(&self.db_conn.get_conn());
+
+        authorize_uri(&self.config, store_url, &nonce)
+            .parse::<Uri>()
+            .map_err(|_| OAuthError::InvalidShop)
+    }
+
+    // woocommerce doesn't sign the key delivery, the nonce we handed out
+    // ties it to an install we started and exchange_token tries the keys
+    fn verify_callback(
+        &self,
+        app: Option<&str>,
+        callback: &Callback,
+    ) -> Result<Authorization, OAuthError> {
+        single_app(app, OAuthError::UnknownApp)?;
+        let delivery: KeyDelivery =
+            serde_json::from_slice(&callback.body).map_err(|_| OAuthError::InvalidRequest)?;
+
+        if delivery.key_permissions != self.config.woocommerce_scope {
+            return Err(OAuthError::InvalidRequest);
+        }
+
+        let conn = self.db_conn.get_conn();
+        let connection = woocommerce_connection::consume_nonce(&conn, delivery.user_id.clone())?
+            .ok_or_else(|| unconsumable_nonce_error(&conn, delivery.user_id.clone()))?;
+
+        Ok(Authorization {
+            store: connection.store_url,
+            nonce: delivery.user_id,
+            grant: format!("{}:{}", delivery.consumer_key, delivery.consumer_secret),
+        })
+    }
+
+    // the keys came with the callback, there's nothing left to trade them for
+    async fn exchange_token(
+        &self,
+        app: Option<&str>,
+        authorization: Authorization,
+    ) -> Result<CallbackReply, OAuthError> {
+        single_app(app, OAuthError::UnknownApp)?;
+        let (consumer_key, consumer_secret) = authorization
+            .grant
+            .split_once(':')
+            .ok_or(OAuthError::InvalidRequest)?;
+
+        let conn = self.db_conn.get_conn();
+        let connection = woocommerce_connection::read_by_nonce(&conn, authorization.nonce)
+            .ok_or(OAuthError::UnknownNonce)?;
+
+        // the delivery isn't signed and whoever started the install has the
+        // nonce, so the keys only count if the store itself takes them
+        let api = WooCommerceClient::with_keys(
+            self.client.clone(),
+            &connection.store_url,
+            consumer_key.to_string(),
+            consumer_secret.to_string(),
+        );
+        if let Err(e) = api.system_status().await {
+            log::warn!(
+                "{} would not take the keys delivered for it: {:?}",
+                connection.store_url,
+                e
+            );
+            return Err(OAuthError::InvalidHmac);
+        }
+
+        let webhook_secret = gen_webhook_secret();
+
+        woocommerce_connection::update_credentials(
+            &conn,
+            &self.config.token_cipher(),
+            &connection,
+            consumer_key.to_string(),
+            consumer_secret.to_string(),
+            self.config.woocommerce_scope.clone(),
+            webhook_secret.clone(),
+        )?;
+
+        self.create_webhooks(
+            &connection.store_url,
+            consumer_key,
+            consumer_secret,
+            &webhook_secret,
+        )
+        .await;
+
+        // anything but a 200 and woocommerce revokes the keys it just made
+        Ok(CallbackReply::Accepted)
+    }
+
+    fn verify_webhook(
+        &self,
+        app: Option<&str>,
+        headers: &HeaderMap,
+        body: Bytes,
+    ) -> Result<Webhook, WebhookError> {
+        single_app(app, WebhookError::UnknownApp)?;
+
+        // signed the same way shopify signs its webhooks
+        let signature = match header(headers, SIGNATURE_HEADER) {
+            Ok(signature) => signature,
+            Err(_) => return ping(headers, body),
+        };
+        // e.x. https://shop.example.com/, the same address validate_store_url saved
+        let store_url = Url::parse(header(headers, SOURCE_HEADER)?)
+            .map(|url| url.as_str().trim_end_matches('/').to_string())
+            .map_err(|_| WebhookError::InvalidHmac)?;
+        if !verify_webhook_hmac(&self.webhook_secret(&store_url)?, &body, signature) {
+            return Err(WebhookError::InvalidHmac);
+        }
+
+        Ok(Webhook {
+            app: String::from(WOOCOMMERCE_APP),
+            topic: header(headers, TOPIC_HEADER)?.to_string(),
+            shop_domain: store_url,
+            webhook_id: header(headers, DELIVERY_ID_HEADER)?.to_string(),
+            body,
+        })
+    }
+
+    async fn create_gift_card(
+        &self,
+        app: Option<&str>,
+        store_url: &str,
+        gift_card: &NewGiftCard,
+    ) -> Result<GiftCard, AdminApiError> {
+        single_app(app, AdminApiError::UnknownApp)?;
+        let coupon = NewCoupon {
+            code: gift_card
+                .code
+                .clone()
+                .unwrap_or_else(|| gen_uuid().chars().take(16).collect()),
+            discount_type: String::from("fixed_cart"),
+            amount: gift_card.initial_value.clone(),
+            description: gift_card.note.clone(),
+            date_expires: gift_card.expires_on.clone(),
+            usage_limit: Some(1),
+        };
+
+        self.api_client(store_url)?
+            .create_coupon(&coupon)
+            .await
+            .map(GiftCard::from)
+    }
+
+    async fn gift_card(
+        &self,
+        app: Option<&str>,
+        store_url: &str,
+        id: &str,
+    ) -> Result<GiftCard, AdminApiError> {
+        single_app(app, AdminApiError::UnknownApp)?;
+        self.api_client(store_url)?
+            .coupon(coupon_id(id)?)
+            .await
+            .map(GiftCard::from)
+    }
+
+    // coupons can't be switched off, so it expires right away instead
+    async fn disable_gift_card(
+        &self,
+        app: Option<&str>,
+        store_url: &str,
+        id: &str,
+    ) -> Result<GiftCard, AdminApiError> {
+        single_app(app, AdminApiError::UnknownApp)?;
+        self.api_client(store_url)?
+            .update_coupon(
+                coupon_id(id)?,
+                &json!({ "date_expires_gmt": now().format(DATE_FORMAT).to_string() }),
+            )
+            .await
+            .map(GiftCard::from)
+    }
+}
+
+// woocommerce has no gift cards without an extension, a single use fixed cart
+// coupon is the closest thing. coupons don't keep a balance, so once it's
+// been used there's nothing left on it
+impl From<Coupon> for GiftCard {
+    fn from(coupon: Coupon) -> Self {
+        let used_up = coupon
+            .usage_limit
+            .map(|limit| coupon.usage_count >= limit)
+            .unwrap_or(false);
+        let expired = coupon
+            .date_expires_gmt
+            .as_deref()
+            .and_then(|date| NaiveDateTime::parse_from_str(date, DATE_FORMAT).ok())
+            .map(|date| date <= now())
+            .unwrap_or(false);
+        let last_characters = coupon
+            .code
+            .chars()
+            .rev()
+            .take(4)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect();
+
+        GiftCard {
+            id: coupon.id.to_string(),
+            last_characters,
+            balance: if used_up {
+                String::from("0.00")
+            } else {
+                coupon.amount.clone()
+            },
+            initial_value: coupon.amount,
+            currency: None,
+            note: Some(coupon.description).filter(|description| !description.is_empty()),
+            expires_on: coupon
+                .date_expires
+                .map(|date| date.chars().take(10).collect()),
+            disabled: expired,
+            code: Some(coupon.code),
+        }
+    }
+}
+
+// woocommerce's ids are numbers, anything else can't be one of its coupons
+fn coupon_id(id: &str) -> Result<i64, AdminApiError> {
+    id.parse().map_err(|_| AdminApiError::NotFound)
+}
+
+// uri for the approve page on the merchant's own store
+fn authorize_uri(config: &Config, store_url: &str, nonce: &str) -> String {
+    let app_url = config.app_url.trim_end_matches('/');
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("app_name", &config.woocommerce_app_name)
+        .append_pair("scope", &config.woocommerce_scope)
+        .append_pair("user_id", nonce)
+        .append_pair("return_url", app_url)
+        .append_pair("callback_url", &format!("{}/{}_confirm", app_url, NAME))
+        .finish();
+
+    format!("{}/wc-auth/v1/authorize?{}", store_url, query)
+}
+
+// figure out why we couldn't claim the nonce so the store gets a useful error
+fn unconsumable_nonce_error(conn: &PgConnection, nonce: String) -> OAuthError {
+    match woocommerce_connection::read_by_nonce(conn, nonce) {
+        Some(connection) if connection.nonce_consumed_at.is_some() => OAuthError::NonceAlreadyUsed,
+        Some(_) => OAuthError::NonceExpired,
+        None => OAuthError::UnknownNonce,
+    }
+}
+
+// stores live wherever the merchant hosts wordpress, so all we can check is
+// that it's a plain https address out on the internet. woocommerce only takes
+// basic auth over https, we'd be sending the keys in the clear otherwise, and
+// we call whatever address we're given so it can't be one of our own machines.
+// hands back the address without a trailing slash, e.x. https://example.com/shop
+pub fn validate_store_url(store_url: &str, mocking: bool) -> Result<String, OAuthError> {
+    let url = Url::parse(store_url).map_err(|_| OAuthError::InvalidShop)?;
+    let host = url.host_str().ok_or(OAuthError::InvalidShop)?;
+
+    let secure = url.scheme() == "https" || (mocking && url.scheme() == "http");
+    if !secure
+        || is_internal_host(host, mocking)
+        || !url.username().is_empty()
+        || url.password().is_some()
+        || url.query().is_some()
+        || url.fragment().is_some()
+    {
+        return Err(OAuthError::InvalidShop);
+    }
+
+    Ok(url.as_str().trim_end_matches('/').to_string())
+}
+
+// a real store has a public domain name, so every ip literal is turned away
+// along with loopback, link-local and private names. the mock server is at 127.0.0.1
+fn is_internal_host(host: &str, mocking: bool) -> bool {
+    match host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        Ok(ip) => !(mocking && ip.is_loopback()),
+        Err(_) => {
+            let host = host.trim_end_matches('.');
+            !host.contains('.')
+                || [".localhost", ".local", ".internal", ".lan", ".home.arpa"]
+                    .iter()
+                    .any(|suffix| host.ends_with(suffix))
+        }
+    }
+}
+
+// as hard to guess as the data keys we seal it with
+fn gen_webhook_secret() -> String {
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    hex::encode(secret)
+}
+
+// woocommerce checks a new webhook's delivery url with an unsigned
+// webhook_id={id} post, and won't save the webhook unless we take it
+fn ping(headers: &HeaderMap, body: Bytes) -> Result<Webhook, WebhookError> {
+    let ping = regex!("^webhook_id=[0-9]+$");
+    if !ping.is_match(&String::from_utf8_lossy(&body)) {
+        return Err(WebhookError::InvalidHmac);
+    }
+
+    Ok(Webhook {
+        app: String::from(WOOCOMMERCE_APP),
+        topic: String::from(PING_TOPIC),
+        shop_domain: header(headers, SOURCE_HEADER)
+            .unwrap_or_default()
+            .trim_end_matches('/')
+            .to_string(),
+        webhook_id: String::from_utf8_lossy(&body).into_owned(),
+        body,
+    })
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &'static str) -> Result<&'a str, WebhookError> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(WebhookError::MissingHeader(name))
+}
@@ -1 +1,15 @@
-pub mod shopify_route;
+pub mod platform_route;
+pub mod session_route;
+
+use warp::{filters::BoxedFilter, Filter};
+
+// the app a route is for, e.x. /shopify_install/staging.
+// routes without one are for the default app
+pub fn app_segment() -> BoxedFilter<(Option<String>,)> {
+    warp::path::param::<String>()
+        .map(Some)
+        .or(warp::any().map(|| None))
+        .unify()
+        .and(warp::path::end())
+        .boxed()
+}
@@ -0,0 +1,130 @@
+use crate::{
+    db_conn::DbConn,
+    handlers::platform_handler,
+    platforms::{Callback, CommercePlatform, PlatformRegistry},
+    routes::app_segment,
+    webhooks::{WebhookJobs, WebhookRegistry},
+    with_db_conn, with_platform, with_webhook_registry,
+};
+use std::sync::Arc;
+use warp::{filters::BoxedFilter, http::HeaderMap, hyper::body::Bytes, Filter, Reply};
+
+// platforms cap webhook and callback payloads well below this
+const BODY_LIMIT: u64 = 1024 * 1024 * 4;
+
+type PlatformFilter = BoxedFilter<(Box<dyn Reply>,)>;
+
+type WebhookFilter = BoxedFilter<(
+    Arc<dyn CommercePlatform>,
+    Option<String>,
+    HeaderMap,
+    Bytes,
+    Arc<DbConn>,
+    Arc<WebhookRegistry>,
+)>;
+
+// the routes for every registered platform, each with the handlers for its own webhooks
+pub fn platform_routes(
+    platforms: &PlatformRegistry,
+    db_conn: Arc<DbConn>,
+    webhook_jobs: &WebhookJobs,
+) -> PlatformFilter {
+    let none = warp::any()
+        .and_then(|| async { Err::<Box<dyn Reply>, _>(warp::reject::not_found()) })
+        .boxed();
+
+    platforms.platforms().fold(none, |routes, platform| {
+        routes
+            .or(routes_for(
+                platform.clone(),
+                db_conn.clone(),
+                webhook_jobs
+                    .registry(platform.webhook_job())
+                    .unwrap_or_default(),
+            ))
+            .unify()
+            .boxed()
+    })
+}
+
+// every platform gets the same routes under its name, e.x.
+// GET /shopify_install, GET or POST /shopify_confirm and POST /webhooks/shopify
+pub fn routes_for(
+    platform: Arc<dyn CommercePlatform>,
+    db_conn: Arc<DbConn>,
+    webhook_registry: Arc<WebhookRegistry>,
+) -> PlatformFilter {
+    install(platform.clone())
+        .and_then(platform_handler::install)
+        .map(|reply| Box::new(reply) as Box<dyn Reply>)
+        .or(confirm(platform.clone()).and_then(platform_handler::confirm))
+        .unify()
+        .or(webhook(platform, db_conn, webhook_registry)
+            .and_then(platform_handler::webhook)
+            .map(|reply| Box::new(reply) as Box<dyn Reply>))
+        .unify()
+        .boxed()
+}
+
+pub fn install(
+    platform: Arc<dyn CommercePlatform>,
+) -> BoxedFilter<(Arc<dyn CommercePlatform>, Option<String>, String)> {
+    warp::get()
+        .and(warp::path(format!("{}_install", platform.name())))
+        .and(with_platform(platform))
+        .and(app_segment())
+        .and(warp::query::raw())
+        .boxed()
+}
+
+// some platforms send the merchant back with a signed query,
+// others post us the credentials directly
+pub fn confirm(
+    platform: Arc<dyn CommercePlatform>,
+) -> BoxedFilter<(Arc<dyn CommercePlatform>, Option<String>, Callback)> {
+    let body = warp::get()
+        .map(Bytes::new)
+        .or(warp::post()
+            .and(warp::body::content_length_limit(BODY_LIMIT))
+            .and(warp::body::bytes()))
+        .unify();
+
+    warp::path(format!("{}_confirm", platform.name()))
+        .and(with_platform(platform))
+        .and(app_segment())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::headers_cloned())
+        .and(body)
+        .map(|platform, app, query, headers, body| {
+            (
+                platform,
+                app,
+                Callback {
+                    query,
+                    headers,
+                    body,
+                },
+            )
+        })
+        .untuple_one()
+        .boxed()
+}
+
+// hands over the body untouched, signatures are computed over the exact bytes
+pub fn webhook(
+    platform: Arc<dyn CommercePlatform>,
+    db_conn: Arc<DbConn>,
+    registry: Arc<WebhookRegistry>,
+) -> WebhookFilter {
+    warp::post()
+        .and(warp::path("webhooks"))
+        .and(warp::path(platform.name()))
+        .and(with_platform(platform))
+        .and(app_segment())
+        .and(warp::header::headers_cloned())
+        .and(warp::body::content_length_limit(BODY_LIMIT))
+        .and(warp::body::bytes())
+        .and(with_db_conn(db_conn))
+        .and(with_webhook_registry(registry))
+        .boxed()
+}
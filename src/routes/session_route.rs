@@ -0,0 +1,24 @@
+use crate::{
+    config::Config, db_conn::DbConn, handlers::session_handler,
+    services::rate_limiter::RateLimiter, session_token::ShopSession, with_config, with_db_conn,
+    with_rate_limiter, with_reqwest_client,
+};
+use reqwest::Client;
+use std::sync::Arc;
+use warp::{filters::BoxedFilter, Filter};
+
+// put this in front of any api route that needs to know which shop is calling
+pub fn with_shop_session(
+    config: Arc<Config>,
+    db_conn: Arc<DbConn>,
+    client: Arc<Client>,
+    rate_limiter: Arc<RateLimiter>,
+) -> BoxedFilter<(ShopSession,)> {
+    warp::header::optional::<String>("authorization")
+        .and(with_config(config))
+        .and(with_db_conn(db_conn))
+        .and(with_reqwest_client(client))
+        .and(with_rate_limiter(rate_limiter))
+        .and_then(session_handler::authenticate)
+        .boxed()
+}
@@ -1,3 +1,44 @@
+table! {
+    compliance_requests (id) {
+        id -> Int4,
+        shop -> Varchar,
+        topic -> Varchar,
+        webhook_id -> Varchar,
+        customer_id -> Nullable<Int8>,
+        payload -> Text,
+        result -> Nullable<Text>,
+        received_at -> Timestamp,
+        completed_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    jobs (id) {
+        id -> Int4,
+        kind -> Varchar,
+        shop -> Varchar,
+        payload -> Text,
+        status -> Varchar,
+        attempts -> Int4,
+        run_at -> Timestamp,
+        locked_at -> Nullable<Timestamp>,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamp,
+        updated_at -> Nullable<Timestamp>,
+        app -> Varchar,
+    }
+}
+
+table! {
+    processed_webhooks (id) {
+        id -> Int4,
+        shop -> Varchar,
+        webhook_id -> Varchar,
+        topic -> Varchar,
+        received_at -> Timestamp,
+    }
+}
+
 table! {
     shopify_connections (id) {
         id -> Int4,
@@ -8,5 +49,82 @@ table! {
         updated_at -> Nullable<Timestamp>,
         deleted_at -> Nullable<Timestamp>,
         active -> Bool,
+        nonce_expires_at -> Timestamp,
+        nonce_consumed_at -> Nullable<Timestamp>,
+        scope -> Nullable<Varchar>,
+        access_token_data_key -> Nullable<Varchar>,
+        access_token_key_id -> Nullable<Varchar>,
+        app -> Varchar,
+        scope_prompts -> Int4,
+        installed_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    shopify_online_tokens (id) {
+        id -> Int4,
+        shop -> Varchar,
+        shopify_user_id -> Int8,
+        access_token -> Varchar,
+        scope -> Varchar,
+        associated_user_scope -> Varchar,
+        expires_at -> Timestamp,
+        email -> Nullable<Varchar>,
+        first_name -> Nullable<Varchar>,
+        last_name -> Nullable<Varchar>,
+        account_owner -> Bool,
+        created_at -> Timestamp,
+        updated_at -> Nullable<Timestamp>,
+        access_token_data_key -> Nullable<Varchar>,
+        access_token_key_id -> Nullable<Varchar>,
+        app -> Varchar,
+    }
+}
+
+table! {
+    webhook_subscriptions (id) {
+        id -> Int4,
+        shop -> Varchar,
+        topic -> Varchar,
+        address -> Varchar,
+        shopify_webhook_id -> Nullable<Int8>,
+        failed_at -> Nullable<Timestamp>,
+        last_error -> Nullable<Text>,
+        attempts -> Int4,
+        created_at -> Timestamp,
+        updated_at -> Nullable<Timestamp>,
+        app -> Varchar,
+    }
+}
+
+table! {
+    woocommerce_connections (id) {
+        id -> Int4,
+        store_url -> Varchar,
+        nonce -> Varchar,
+        consumer_key -> Nullable<Varchar>,
+        consumer_secret -> Nullable<Varchar>,
+        consumer_secret_data_key -> Nullable<Varchar>,
+        consumer_secret_key_id -> Nullable<Varchar>,
+        key_permissions -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        updated_at -> Nullable<Timestamp>,
+        deleted_at -> Nullable<Timestamp>,
+        active -> Bool,
+        nonce_expires_at -> Timestamp,
+        nonce_consumed_at -> Nullable<Timestamp>,
+        webhook_secret -> Nullable<Varchar>,
+        webhook_secret_data_key -> Nullable<Varchar>,
+        webhook_secret_key_id -> Nullable<Varchar>,
     }
 }
+
+allow_tables_to_appear_in_same_query!(
+    compliance_requests,
+    jobs,
+    processed_webhooks,
+    shopify_connections,
+    shopify_online_tokens,
+    webhook_subscriptions,
+    woocommerce_connections,
+);
@@ -1 +1,5 @@
+pub mod rate_limiter;
+pub mod shopify_admin;
 pub mod shopify_service;
+pub mod webhook_subscription_service;
+pub mod woocommerce_api;
@@ -0,0 +1,290 @@
+use crate::{config::Config, services::shopify_admin::graphql::ThrottleStatus};
+use rand::Rng;
+use reqwest::{header::HeaderMap, Method, RequestBuilder, Response, StatusCode};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// e.x. "32/40", calls used out of the shop's bucket
+const CALL_LIMIT_HEADER: &str = "X-Shopify-Shop-Api-Call-Limit";
+const RETRY_AFTER_HEADER: &str = "Retry-After";
+// most a retry gets pushed back on top of what shopify asked for
+const MAX_JITTER_MS: u64 = 250;
+// longest we'll take shopify's word on how long to stay away
+const MAX_RETRY_AFTER_SECS: f64 = 60.0;
+// longest we'll wait for room in a bucket before trying anyway,
+// whatever shopify says about how fast it drains
+const MAX_BUCKET_WAIT_SECS: f64 = 60.0;
+
+// how much of a shop's bucket is left
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Budget {
+    pub used: f64,
+    pub capacity: f64,
+}
+
+impl Budget {
+    pub fn remaining(&self) -> f64 {
+        (self.capacity - self.used).max(0.0)
+    }
+}
+
+struct Bucket {
+    used: f64,
+    capacity: f64,
+    updated_at: Instant,
+    // shopify told us to stay away until then
+    retry_at: Option<Instant>,
+}
+
+impl Bucket {
+    fn leak(&mut self, leak_rate: f64) {
+        let now = Instant::now();
+        let leaked = leak_rate * now.duration_since(self.updated_at).as_secs_f64();
+        self.used = (self.used - leaked).max(0.0);
+        self.updated_at = now;
+    }
+}
+
+// what the last graphql response told us about the shop's cost bucket,
+// shopify keeps it apart from the rest one
+struct CostBucket {
+    status: ThrottleStatus,
+    requested_cost: f64,
+    seen_at: Instant,
+}
+
+impl CostBucket {
+    // points refill at restore_rate per second since we last heard from shopify
+    fn wait_for(&self, cost: f64) -> Option<Duration> {
+        let refilled = self.status.restore_rate * self.seen_at.elapsed().as_secs_f64();
+        let available =
+            (self.status.currently_available + refilled).min(self.status.maximum_available);
+
+        if available >= cost || self.status.restore_rate <= 0.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64(
+                ((cost - available) / self.status.restore_rate).min(MAX_BUCKET_WAIT_SECS),
+            ))
+        }
+    }
+}
+
+// one leaky bucket per shop, shared by every call we make to shopify.
+// calls wait for room instead of finding out the hard way with a 429
+pub struct RateLimiter {
+    bucket_size: f64,
+    leak_rate: f64,
+    max_retries: usize,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    cost_buckets: Mutex<HashMap<String, CostBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> Self {
+        RateLimiter {
+            bucket_size: config.shopify_api_bucket_size,
+            leak_rate: config.shopify_api_leak_rate,
+            max_retries: config.shopify_api_max_retries,
+            buckets: Mutex::new(HashMap::new()),
+            cost_buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // so background work can hold off on shops that are close to the limit
+    pub fn budget(&self, shop: &str) -> Budget {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        match buckets.get_mut(shop) {
+            Some(bucket) => {
+                bucket.leak(self.leak_rate);
+                Budget {
+                    used: bucket.used,
+                    capacity: bucket.capacity,
+                }
+            }
+            None => Budget {
+                used: 0.0,
+                capacity: self.bucket_size,
+            },
+        }
+    }
+
+    // sends the request once there's room in the shop's bucket.
+    // a 429 on anything safe to repeat is tried again after Retry-After
+    pub async fn send(&self, shop: &str, request: RequestBuilder) -> reqwest::Result<Response> {
+        let idempotent = is_idempotent(&request);
+        let mut request = request;
+        let mut retries = 0;
+
+        loop {
+            self.acquire(shop).await;
+
+            let next = if idempotent && retries < self.max_retries {
+                request.try_clone()
+            } else {
+                None
+            };
+
+            let res = request.send().await?;
+            self.record(shop, res.status(), res.headers());
+
+            match next {
+                Some(next) if res.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    retries += 1;
+                    log::info!(
+                        "{} is throttled, retry {} of {}",
+                        shop,
+                        retries,
+                        self.max_retries
+                    );
+                    tokio::time::sleep(jitter(retries)).await;
+                    request = next;
+                }
+                _ => return Ok(res),
+            }
+        }
+    }
+
+    // graphql goes by query cost instead of taking a slot from the rest bucket,
+    // so this waits until the shop's cost bucket should hold another query like
+    // the last one. throttled queries come back as errors in the body, the
+    // caller retries those after record_cost
+    pub async fn send_graphql(
+        &self,
+        shop: &str,
+        request: RequestBuilder,
+    ) -> reqwest::Result<Response> {
+        let wait = self
+            .cost_buckets
+            .lock()
+            .unwrap()
+            .get(shop)
+            .and_then(|bucket| bucket.wait_for(bucket.requested_cost));
+
+        if let Some(wait) = wait {
+            log::debug!("waiting {:?} for {}'s graphql cost bucket", wait, shop);
+            tokio::time::sleep(wait).await;
+        }
+
+        request.send().await
+    }
+
+    // every graphql response says how full the cost bucket is
+    pub fn record_cost(&self, shop: &str, status: ThrottleStatus, requested_cost: f64) {
+        self.cost_buckets.lock().unwrap().insert(
+            shop.to_string(),
+            CostBucket {
+                status,
+                requested_cost,
+                seen_at: Instant::now(),
+            },
+        );
+    }
+
+    pub fn throttle_status(&self, shop: &str) -> Option<ThrottleStatus> {
+        self.cost_buckets
+            .lock()
+            .unwrap()
+            .get(shop)
+            .map(|bucket| bucket.status)
+    }
+
+    // take a slot from the bucket, waiting for it to drain if need be
+    async fn acquire(&self, shop: &str) {
+        loop {
+            let wait = {
+                let mut buckets = self.buckets.lock().unwrap();
+                let bucket = buckets.entry(shop.to_string()).or_insert_with(|| Bucket {
+                    used: 0.0,
+                    capacity: self.bucket_size,
+                    updated_at: Instant::now(),
+                    retry_at: None,
+                });
+                bucket.leak(self.leak_rate);
+
+                match bucket.retry_at {
+                    Some(retry_at) if retry_at > Instant::now() => retry_at - Instant::now(),
+                    _ if bucket.used + 1.0 <= bucket.capacity => {
+                        bucket.used += 1.0;
+                        bucket.retry_at = None;
+                        return;
+                    }
+                    _ => Duration::from_secs_f64(
+                        ((bucket.used + 1.0 - bucket.capacity) / self.leak_rate)
+                            .clamp(0.0, MAX_BUCKET_WAIT_SECS),
+                    ),
+                }
+            };
+
+            log::debug!("waiting {:?} for room in {}'s bucket", wait, shop);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // shopify's count is the real one, ours is only a guess in between
+    fn record(&self, shop: &str, status: StatusCode, headers: &HeaderMap) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = match buckets.get_mut(shop) {
+            Some(bucket) => bucket,
+            None => return,
+        };
+
+        if let Some((used, capacity)) =
+            header(headers, CALL_LIMIT_HEADER).and_then(parse_call_limit)
+        {
+            bucket.used = used;
+            bucket.capacity = capacity;
+            bucket.updated_at = Instant::now();
+        }
+
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = header(headers, RETRY_AFTER_HEADER)
+                .and_then(|value| value.parse::<f64>().ok())
+                .filter(|retry_after| retry_after.is_finite())
+                .unwrap_or(1.0 / self.leak_rate)
+                .clamp(0.0, MAX_RETRY_AFTER_SECS);
+            bucket.retry_at = Some(Instant::now() + Duration::from_secs_f64(retry_after));
+        }
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+fn parse_call_limit(value: &str) -> Option<(f64, f64)> {
+    let mut parts = value.split('/');
+    let used: f64 = parts.next()?.trim().parse().ok()?;
+    let capacity: f64 = parts.next()?.trim().parse().ok()?;
+
+    // anything else would have us waiting forever, or panic working out how long
+    if !used.is_finite() || !capacity.is_finite() || capacity < 1.0 {
+        return None;
+    }
+
+    // e.x. "1e300/40" is a full bucket, not one that takes ages to drain
+    Some((used.clamp(0.0, capacity), capacity))
+}
+
+// a POST could have gone through before shopify throttled the response
+fn is_idempotent(request: &RequestBuilder) -> bool {
+    request
+        .try_clone()
+        .and_then(|request| request.build().ok())
+        .map(|request| {
+            matches!(
+                *request.method(),
+                Method::GET | Method::HEAD | Method::PUT | Method::DELETE
+            )
+        })
+        .unwrap_or(false)
+}
+
+// spread out retries so a burst of throttled calls doesn't come back all at once
+fn jitter(retries: usize) -> Duration {
+    Duration::from_millis(rand::thread_rng().gen_range(0..=MAX_JITTER_MS * retries as u64))
+}
@@ -0,0 +1,112 @@
+use super::AdminClient;
+use crate::errors::{AdminApiError, GraphqlError, UserError};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+// how many times we'll sit out a THROTTLED response before giving up
+const MAX_THROTTLED_RETRIES: usize = 3;
+// used when shopify throttles us without saying how full the bucket is
+const DEFAULT_THROTTLE_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize)]
+struct GraphqlResponse {
+    data: Option<Value>,
+    #[serde(default)]
+    errors: Vec<GraphqlError>,
+    extensions: Option<Extensions>,
+}
+
+#[derive(Deserialize)]
+struct Extensions {
+    cost: Option<QueryCost>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QueryCost {
+    requested_query_cost: f64,
+    throttle_status: ThrottleStatus,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThrottleStatus {
+    pub maximum_available: f64,
+    pub currently_available: f64,
+    pub restore_rate: f64,
+}
+
+impl AdminClient {
+    // runs a query and returns its `data` as T,
+    // e.x. POST https://{shop}/admin/api/{version}/graphql.json
+    pub async fn graphql<T: DeserializeOwned>(
+        &self,
+        query: &str,
+        variables: Value,
+    ) -> Result<T, AdminApiError> {
+        let mut throttled = 0;
+
+        loop {
+            let request = self
+                .client
+                .post(self.url("graphql"))
+                .json(&json!({ "query": query, "variables": variables }));
+            let res: GraphqlResponse = serde_json::from_value(self.send_graphql(request).await?)?;
+
+            let cost = res.extensions.and_then(|extensions| extensions.cost);
+            let had_cost = cost.is_some();
+            if let Some(cost) = cost {
+                self.rate_limiter.record_cost(
+                    &self.shop,
+                    cost.throttle_status,
+                    cost.requested_query_cost,
+                );
+            }
+
+            if res.errors.iter().any(GraphqlError::is_throttled) {
+                if throttled == MAX_THROTTLED_RETRIES {
+                    return Err(AdminApiError::Throttled(None));
+                }
+                throttled += 1;
+                log::info!("graphql query throttled, waiting on the bucket");
+                if !had_cost {
+                    tokio::time::sleep(DEFAULT_THROTTLE_WAIT).await;
+                }
+                continue;
+            }
+
+            if !res.errors.is_empty() {
+                return Err(AdminApiError::Graphql(res.errors));
+            }
+
+            return Ok(serde_json::from_value(res.data.unwrap_or(Value::Null))?);
+        }
+    }
+
+    // runs a mutation and returns the payload under `key`, failing on any userErrors
+    pub async fn graphql_mutation<T: DeserializeOwned>(
+        &self,
+        query: &str,
+        variables: Value,
+        key: &str,
+    ) -> Result<T, AdminApiError> {
+        let mut data: Value = self.graphql(query, variables).await?;
+        let payload = data.get_mut(key).map(Value::take).unwrap_or(Value::Null);
+
+        let user_errors: Vec<UserError> = match payload.get("userErrors") {
+            Some(user_errors) => serde_json::from_value(user_errors.clone())?,
+            None => Vec::new(),
+        };
+        if !user_errors.is_empty() {
+            return Err(AdminApiError::UserErrors(user_errors));
+        }
+
+        Ok(serde_json::from_value(payload)?)
+    }
+
+    // the most recent throttle status shopify sent back for the shop, if any
+    pub fn throttle_status(&self) -> Option<ThrottleStatus> {
+        self.rate_limiter.throttle_status(&self.shop)
+    }
+}
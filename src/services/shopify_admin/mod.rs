@@ -0,0 +1,273 @@
+pub mod graphql;
+pub mod pagination;
+pub mod resources;
+
+use crate::{
+    config::Config, errors::AdminApiError, models::shopify_connection::ShopifyConnection,
+    services::rate_limiter::RateLimiter,
+};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use resources::{
+    Customer, GiftCard, NewGiftCard, NewPriceRule, Order, PriceRule, Product, Shop, Webhook,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+const ACCESS_TOKEN_HEADER: &str = "X-Shopify-Access-Token";
+const LINK_HEADER: &str = "Link";
+
+// talks to one shop's admin api with the token it gave us,
+// e.x. GET https://{shop}/admin/api/{version}/orders.json
+pub struct AdminClient {
+    client: Arc<Client>,
+    rate_limiter: Arc<RateLimiter>,
+    shop: String,
+    base_url: String,
+    access_token: String,
+}
+
+impl AdminClient {
+    pub fn new(
+        client: Arc<Client>,
+        rate_limiter: Arc<RateLimiter>,
+        config: &Config,
+        shopify_connection: &ShopifyConnection,
+    ) -> Result<Self, AdminApiError> {
+        let access_token = shopify_connection
+            .decrypt_access_token(&config.token_cipher())?
+            .ok_or(AdminApiError::MissingAccessToken)?;
+
+        Ok(AdminClient::with_token(
+            client,
+            rate_limiter,
+            config,
+            &shopify_connection.shop,
+            access_token,
+        ))
+    }
+
+    // for when we have the token in hand before it's been saved
+    pub fn with_token(
+        client: Arc<Client>,
+        rate_limiter: Arc<RateLimiter>,
+        config: &Config,
+        shop: &str,
+        access_token: String,
+    ) -> Self {
+        AdminClient {
+            client,
+            rate_limiter,
+            shop: shop.to_string(),
+            base_url: format!(
+                "{}/admin/api/{}",
+                config.shopify_shop_uri(shop),
+                config.shopify_api_version
+            ),
+            access_token,
+        }
+    }
+
+    pub async fn shop(&self) -> Result<Shop, AdminApiError> {
+        self.get("shop", &[], "shop").await
+    }
+
+    pub async fn orders(&self, query: &[(&str, &str)]) -> Result<Vec<Order>, AdminApiError> {
+        self.get("orders", query, "orders").await
+    }
+
+    pub async fn order(&self, id: i64) -> Result<Order, AdminApiError> {
+        self.get(&format!("orders/{}", id), &[], "order").await
+    }
+
+    pub async fn products(&self, query: &[(&str, &str)]) -> Result<Vec<Product>, AdminApiError> {
+        self.get("products", query, "products").await
+    }
+
+    pub async fn product(&self, id: i64) -> Result<Product, AdminApiError> {
+        self.get(&format!("products/{}", id), &[], "product").await
+    }
+
+    pub async fn customers(&self, query: &[(&str, &str)]) -> Result<Vec<Customer>, AdminApiError> {
+        self.get("customers", query, "customers").await
+    }
+
+    pub async fn customer(&self, id: i64) -> Result<Customer, AdminApiError> {
+        self.get(&format!("customers/{}", id), &[], "customer")
+            .await
+    }
+
+    pub async fn gift_cards(&self, query: &[(&str, &str)]) -> Result<Vec<GiftCard>, AdminApiError> {
+        self.get("gift_cards", query, "gift_cards").await
+    }
+
+    pub async fn gift_card(&self, id: i64) -> Result<GiftCard, AdminApiError> {
+        self.get(&format!("gift_cards/{}", id), &[], "gift_card")
+            .await
+    }
+
+    pub async fn create_gift_card(
+        &self,
+        gift_card: &NewGiftCard,
+    ) -> Result<GiftCard, AdminApiError> {
+        self.post("gift_cards", "gift_card", gift_card).await
+    }
+
+    // a disabled card can't be spent, there's no turning it back on
+    pub async fn disable_gift_card(&self, id: i64) -> Result<GiftCard, AdminApiError> {
+        self.post(
+            &format!("gift_cards/{}/disable", id),
+            "gift_card",
+            &json!({ "id": id }),
+        )
+        .await
+    }
+
+    pub async fn price_rules(
+        &self,
+        query: &[(&str, &str)],
+    ) -> Result<Vec<PriceRule>, AdminApiError> {
+        self.get("price_rules", query, "price_rules").await
+    }
+
+    pub async fn price_rule(&self, id: i64) -> Result<PriceRule, AdminApiError> {
+        self.get(&format!("price_rules/{}", id), &[], "price_rule")
+            .await
+    }
+
+    pub async fn create_price_rule(
+        &self,
+        price_rule: &NewPriceRule,
+    ) -> Result<PriceRule, AdminApiError> {
+        self.post("price_rules", "price_rule", price_rule).await
+    }
+
+    pub async fn webhooks(&self) -> Result<Vec<Webhook>, AdminApiError> {
+        self.get("webhooks", &[], "webhooks").await
+    }
+
+    pub async fn create_webhook(
+        &self,
+        topic: &str,
+        address: &str,
+    ) -> Result<Webhook, AdminApiError> {
+        self.post(
+            "webhooks",
+            "webhook",
+            &json!({ "topic": topic, "address": address, "format": "json" }),
+        )
+        .await
+    }
+
+    pub async fn update_webhook_address(
+        &self,
+        id: i64,
+        address: &str,
+    ) -> Result<Webhook, AdminApiError> {
+        let request = self
+            .client
+            .put(self.url(&format!("webhooks/{}", id)))
+            .json(&json!({ "webhook": { "id": id, "address": address } }));
+
+        unwrap(self.send(request).await?, "webhook")
+    }
+
+    pub async fn delete_webhook(&self, id: i64) -> Result<(), AdminApiError> {
+        let request = self.client.delete(self.url(&format!("webhooks/{}", id)));
+
+        self.send(request).await.map(|_| ())
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}.json", self.base_url, path)
+    }
+
+    async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+        key: &str,
+    ) -> Result<T, AdminApiError> {
+        let request = self.client.get(self.url(path)).query(query);
+
+        unwrap(self.send(request).await?, key)
+    }
+
+    async fn post<B: Serialize, T: DeserializeOwned>(
+        &self,
+        path: &str,
+        key: &str,
+        body: &B,
+    ) -> Result<T, AdminApiError> {
+        let request = self.client.post(self.url(path)).json(&json!({ key: body }));
+
+        unwrap(self.send(request).await?, key)
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<Value, AdminApiError> {
+        self.send_page(request).await.map(|(body, _)| body)
+    }
+
+    // the body along with where the next page is, if there is one
+    async fn send_page(
+        &self,
+        request: RequestBuilder,
+    ) -> Result<(Value, Option<String>), AdminApiError> {
+        let res = self
+            .rate_limiter
+            .send(
+                &self.shop,
+                request.header(ACCESS_TOKEN_HEADER, &self.access_token),
+            )
+            .await?;
+
+        read_page(res).await
+    }
+
+    // graphql is limited by query cost instead, see RateLimiter::send_graphql
+    async fn send_graphql(&self, request: RequestBuilder) -> Result<Value, AdminApiError> {
+        let res = self
+            .rate_limiter
+            .send_graphql(
+                &self.shop,
+                request.header(ACCESS_TOKEN_HEADER, &self.access_token),
+            )
+            .await?;
+
+        read_page(res).await.map(|(body, _)| body)
+    }
+}
+
+async fn read_page(res: Response) -> Result<(Value, Option<String>), AdminApiError> {
+    match res.status() {
+        status if status.is_success() => {
+            let next = res
+                .headers()
+                .get(LINK_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(pagination::next_link);
+            let body = res.text().await?;
+            if body.trim().is_empty() {
+                Ok((Value::Null, next))
+            } else {
+                Ok((serde_json::from_str(&body)?, next))
+            }
+        }
+        StatusCode::UNAUTHORIZED => Err(AdminApiError::Unauthorized),
+        StatusCode::NOT_FOUND => Err(AdminApiError::NotFound),
+        StatusCode::TOO_MANY_REQUESTS => Err(AdminApiError::Throttled(
+            res.headers()
+                .get("retry-after")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok()),
+        )),
+        status => Err(AdminApiError::Status(status, res.text().await?)),
+    }
+}
+
+// shopify wraps every resource, e.x. { "order": { ... } } or { "orders": [ ... ] }
+fn unwrap<T: DeserializeOwned>(mut body: Value, key: &str) -> Result<T, AdminApiError> {
+    Ok(serde_json::from_value(
+        body.get_mut(key).map(Value::take).unwrap_or(Value::Null),
+    )?)
+}
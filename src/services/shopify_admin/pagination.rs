@@ -0,0 +1,253 @@
+use super::{
+    resources::{Customer, GiftCard, Order, PriceRule, Product},
+    unwrap, AdminClient,
+};
+use crate::errors::AdminApiError;
+use futures::stream::{self, Stream};
+use reqwest::StatusCode;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+
+// an expired cursor restarts the walk this many times before we give up
+const MAX_RESTARTS: usize = 3;
+// picking up after the last id we saw only works walking ids upwards
+const RESTARTABLE_ORDER: &str = "id asc";
+
+// anything the admin api hands back as a list,
+// e.x. GET orders.json comes back as { "orders": [ ... ] }
+pub trait ListResource: DeserializeOwned {
+    const PATH: &'static str;
+
+    fn id(&self) -> i64;
+}
+
+impl ListResource for Order {
+    const PATH: &'static str = "orders";
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+impl ListResource for Product {
+    const PATH: &'static str = "products";
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+impl ListResource for Customer {
+    const PATH: &'static str = "customers";
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+impl ListResource for GiftCard {
+    const PATH: &'static str = "gift_cards";
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+impl ListResource for PriceRule {
+    const PATH: &'static str = "price_rules";
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+enum Page {
+    First,
+    Next(String),
+    Done,
+}
+
+struct RestPages<T> {
+    query: Vec<(String, String)>,
+    page: Page,
+    items: VecDeque<T>,
+    last_id: Option<i64>,
+    restarts: usize,
+    restartable: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PageInfo {
+    has_next_page: bool,
+    end_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Connection<T> {
+    nodes: Vec<T>,
+    page_info: PageInfo,
+}
+
+struct GraphqlPages<T> {
+    variables: Value,
+    after: Option<String>,
+    has_next_page: bool,
+    items: VecDeque<T>,
+}
+
+impl AdminClient {
+    // every item of a list, a page at a time as the stream is read.
+    // when shopify forgets a cursor we pick up again after the last id we saw,
+    // unless the caller asked for some other order than by id
+    pub fn list<'a, T: ListResource + 'a>(
+        &'a self,
+        query: &[(&str, &str)],
+    ) -> impl Stream<Item = Result<T, AdminApiError>> + 'a {
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let restartable = match query.iter().find(|(key, _)| key == "order") {
+            Some((_, order)) => order == RESTARTABLE_ORDER,
+            None => {
+                query.push((String::from("order"), String::from(RESTARTABLE_ORDER)));
+                true
+            }
+        };
+
+        let pages: RestPages<T> = RestPages {
+            query,
+            page: Page::First,
+            items: VecDeque::new(),
+            last_id: None,
+            restarts: 0,
+            restartable,
+        };
+
+        stream::unfold(pages, move |mut pages| async move {
+            loop {
+                if let Some(item) = pages.items.pop_front() {
+                    pages.last_id = Some(item.id());
+                    return Some((Ok(item), pages));
+                }
+
+                let request = match &pages.page {
+                    Page::First => self.client.get(self.url(T::PATH)).query(&pages.query),
+                    Page::Next(url) => self.client.get(url),
+                    Page::Done => return None,
+                };
+
+                match self.send_page(request).await {
+                    Ok((body, next)) => match unwrap::<Vec<T>>(body, T::PATH) {
+                        Ok(items) => {
+                            pages.items.extend(items);
+                            pages.page = next.map(Page::Next).unwrap_or(Page::Done);
+                        }
+                        Err(e) => {
+                            pages.page = Page::Done;
+                            return Some((Err(e), pages));
+                        }
+                    },
+                    Err(e)
+                        if is_expired_cursor(&e)
+                            && pages.restartable
+                            && pages.restarts < MAX_RESTARTS =>
+                    {
+                        pages.restarts += 1;
+                        log::info!(
+                            "{} cursor expired, picking up after {:?}",
+                            T::PATH,
+                            pages.last_id
+                        );
+                        pages.query.retain(|(key, _)| key != "since_id");
+                        if let Some(last_id) = pages.last_id {
+                            pages
+                                .query
+                                .push((String::from("since_id"), last_id.to_string()));
+                        }
+                        pages.page = Page::First;
+                    }
+                    Err(e) => {
+                        pages.page = Page::Done;
+                        return Some((Err(e), pages));
+                    }
+                }
+            }
+        })
+    }
+
+    // every node of a connection at the top of `data`. the query takes an
+    // `$after: String` and selects `nodes` and `pageInfo { hasNextPage endCursor }`
+    pub fn graphql_list<'a, T: DeserializeOwned + 'a>(
+        &'a self,
+        query: &'a str,
+        variables: Value,
+        connection: &'a str,
+    ) -> impl Stream<Item = Result<T, AdminApiError>> + 'a {
+        let pages: GraphqlPages<T> = GraphqlPages {
+            variables,
+            after: None,
+            has_next_page: true,
+            items: VecDeque::new(),
+        };
+
+        stream::unfold(pages, move |mut pages| async move {
+            loop {
+                if let Some(item) = pages.items.pop_front() {
+                    return Some((Ok(item), pages));
+                }
+                if !pages.has_next_page {
+                    return None;
+                }
+
+                let mut variables = pages.variables.clone();
+                variables["after"] = pages
+                    .after
+                    .clone()
+                    .map(Value::String)
+                    .unwrap_or(Value::Null);
+
+                let page = self
+                    .graphql::<Value>(query, variables)
+                    .await
+                    .and_then(|data| unwrap::<Connection<T>>(data, connection));
+
+                match page {
+                    Ok(page) => {
+                        pages.items.extend(page.nodes);
+                        pages.has_next_page =
+                            page.page_info.has_next_page && page.page_info.end_cursor.is_some();
+                        pages.after = page.page_info.end_cursor;
+                    }
+                    Err(e) => {
+                        pages.has_next_page = false;
+                        return Some((Err(e), pages));
+                    }
+                }
+            }
+        })
+    }
+}
+
+// e.x. <https://{shop}/admin/api/{version}/orders.json?limit=50&page_info=abc>; rel="next"
+pub(super) fn next_link(header: &str) -> Option<String> {
+    header.split(',').find_map(|link| {
+        let mut parts = link.split(';');
+        let url = parts.next()?.trim();
+        let is_next = parts.any(|part| part.trim() == r#"rel="next""#);
+
+        if is_next && url.starts_with('<') && url.ends_with('>') {
+            Some(url[1..url.len() - 1].to_string())
+        } else {
+            None
+        }
+    })
+}
+
+// shopify only keeps page_info around for a while
+fn is_expired_cursor(e: &AdminApiError) -> bool {
+    matches!(e, AdminApiError::Status(StatusCode::BAD_REQUEST, body) if body.contains("page_info"))
+}
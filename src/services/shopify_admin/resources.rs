@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+
+// only the fields we use, shopify sends plenty more.
+// money comes back as strings, e.x. "25.00", and is left that way
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Shop {
+    pub id: i64,
+    pub name: String,
+    pub email: Option<String>,
+    pub domain: Option<String>,
+    pub myshopify_domain: String,
+    pub currency: String,
+    pub plan_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Order {
+    pub id: i64,
+    pub name: String,
+    pub email: Option<String>,
+    pub financial_status: Option<String>,
+    pub currency: String,
+    pub total_price: String,
+    pub created_at: Option<String>,
+    pub customer: Option<Customer>,
+    #[serde(default)]
+    pub line_items: Vec<LineItem>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LineItem {
+    pub id: i64,
+    pub product_id: Option<i64>,
+    pub variant_id: Option<i64>,
+    pub title: String,
+    pub quantity: i64,
+    pub price: String,
+    #[serde(default)]
+    pub gift_card: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Product {
+    pub id: i64,
+    pub title: String,
+    pub product_type: Option<String>,
+    pub vendor: Option<String>,
+    pub status: Option<String>,
+    #[serde(default)]
+    pub variants: Vec<ProductVariant>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProductVariant {
+    pub id: i64,
+    pub product_id: i64,
+    pub title: String,
+    pub price: String,
+    pub sku: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Customer {
+    pub id: i64,
+    pub email: Option<String>,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub phone: Option<String>,
+}
+
+// the full code is only ever sent back when the card is created
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GiftCard {
+    pub id: i64,
+    pub balance: String,
+    pub initial_value: String,
+    pub currency: Option<String>,
+    pub code: Option<String>,
+    pub last_characters: String,
+    pub customer_id: Option<i64>,
+    pub note: Option<String>,
+    pub expires_on: Option<String>,
+    pub disabled_at: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct NewGiftCard {
+    pub initial_value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub customer_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_on: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PriceRule {
+    pub id: i64,
+    pub title: String,
+    pub value_type: String,
+    pub value: String,
+    pub target_type: String,
+    pub target_selection: String,
+    pub allocation_method: String,
+    pub customer_selection: String,
+    pub starts_at: String,
+    pub ends_at: Option<String>,
+    pub usage_limit: Option<i64>,
+    #[serde(default)]
+    pub once_per_customer: bool,
+}
+
+// e.x. a fixed $10 off the whole order
+// {
+//   "title": "STORECREDIT10",
+//   "value_type": "fixed_amount",
+//   "value": "-10.0",
+//   "target_type": "line_item",
+//   "target_selection": "all",
+//   "allocation_method": "across",
+//   "customer_selection": "all",
+//   "starts_at": "2021-07-01T00:00:00Z"
+// }
+#[derive(Debug, Serialize)]
+pub struct NewPriceRule {
+    pub title: String,
+    pub value_type: String,
+    pub value: String,
+    pub target_type: String,
+    pub target_selection: String,
+    pub allocation_method: String,
+    pub customer_selection: String,
+    pub starts_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ends_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage_limit: Option<i64>,
+    pub once_per_customer: bool,
+}
+
+// e.x. {
+//   "id": 4759306,
+//   "address": "https://gifts.example.com/webhooks/shopify",
+//   "topic": "app/uninstalled",
+//   "format": "json"
+// }
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Webhook {
+    pub id: i64,
+    pub address: String,
+    pub topic: String,
+    pub format: Option<String>,
+}
@@ -1,19 +1,72 @@
-use crate::AccessTokenResponse;
+use crate::{
+    config::{AccessMode, Config, ShopifyApp},
+    services::rate_limiter::RateLimiter,
+    AccessTokenResponse,
+};
 use reqwest::Client;
 use std::sync::Arc;
 
+const TOKEN_EXCHANGE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const ID_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:id_token";
+const OFFLINE_ACCESS_TOKEN_TYPE: &str = "urn:shopify:params:oauth:token-type:offline-access-token";
+const ONLINE_ACCESS_TOKEN_TYPE: &str = "urn:shopify:params:oauth:token-type:online-access-token";
+
 pub async fn get_access_token(
     client: Arc<Client>,
+    rate_limiter: &RateLimiter,
+    config: &Config,
+    shop: &str,
     form_body: Vec<(String, String)>,
-    shopify_url: String,
 ) -> Result<AccessTokenResponse, reqwest::Error> {
-    let access_token_json: AccessTokenResponse = client
-        .post(format!("{}/admin/oauth/access_token", shopify_url))
-        .form(&form_body)
-        .send()
+    let request = client
+        .post(format!(
+            "{}/admin/oauth/access_token",
+            config.shopify_shop_uri(shop)
+        ))
+        .form(&form_body);
+
+    let access_token_json: AccessTokenResponse = rate_limiter
+        .send(shop, request)
         .await?
+        .error_for_status()?
         .json()
         .await?;
 
     Ok(access_token_json)
 }
+
+// trade an app bridge session token for an access token,
+// no trip through the authorize redirect needed
+pub async fn exchange_session_token(
+    client: Arc<Client>,
+    rate_limiter: &RateLimiter,
+    config: &Config,
+    app: &ShopifyApp,
+    shop: &str,
+    session_token: String,
+) -> Result<AccessTokenResponse, reqwest::Error> {
+    let requested_token_type = match config.shopify_access_mode {
+        AccessMode::Offline => OFFLINE_ACCESS_TOKEN_TYPE,
+        AccessMode::Online => ONLINE_ACCESS_TOKEN_TYPE,
+    };
+
+    let form_body = vec![
+        (String::from("client_id"), app.api_key.clone()),
+        (String::from("client_secret"), app.api_secret.clone()),
+        (
+            String::from("grant_type"),
+            String::from(TOKEN_EXCHANGE_GRANT_TYPE),
+        ),
+        (String::from("subject_token"), session_token),
+        (
+            String::from("subject_token_type"),
+            String::from(ID_TOKEN_TYPE),
+        ),
+        (
+            String::from("requested_token_type"),
+            String::from(requested_token_type),
+        ),
+    ];
+
+    get_access_token(client, rate_limiter, config, shop, form_body).await
+}
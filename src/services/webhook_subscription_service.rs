@@ -0,0 +1,247 @@
+use crate::{
+    config::{Config, ShopifyApp},
+    db_conn::DbConn,
+    encryption::TokenCipher,
+    errors::{AdminApiError, EncryptionError},
+    models::{
+        shopify_connection::{self, ShopifyConnection},
+        shopify_online_token,
+        webhook_subscription::{self, NewWebhookSubscription},
+    },
+    services::{
+        rate_limiter::RateLimiter,
+        shopify_admin::{resources::Webhook, AdminClient},
+    },
+};
+use reqwest::Client;
+use std::sync::Arc;
+
+// bring a shop's subscriptions in line with the topics we're configured for,
+// whatever is already there is reused so a reinstall doesn't double up.
+// anything that fails is recorded for the retrier, returns how many did
+pub async fn sync_subscriptions(
+    client: Arc<Client>,
+    rate_limiter: Arc<RateLimiter>,
+    config: &Config,
+    app: &ShopifyApp,
+    db_conn: &DbConn,
+    shop: &str,
+    access_token: &str,
+) -> usize {
+    if config.shopify_webhook_topics.is_empty() {
+        return 0;
+    }
+
+    let admin =
+        AdminClient::with_token(client, rate_limiter, config, shop, access_token.to_string());
+    let address = config.webhook_address(app);
+
+    let existing = match admin.webhooks().await {
+        Ok(existing) => existing,
+        Err(e) => {
+            for topic in config.shopify_webhook_topics.iter() {
+                record_failure(db_conn, app, shop, topic, &address, &e);
+            }
+            return config.shopify_webhook_topics.len();
+        }
+    };
+
+    let mut failures = 0;
+
+    for topic in config.shopify_webhook_topics.iter() {
+        let subscribed = match existing.iter().find(|webhook| &webhook.topic == topic) {
+            Some(webhook) if webhook.address == address => Ok(webhook.id),
+            Some(webhook) => admin
+                .update_webhook_address(webhook.id, &address)
+                .await
+                .map(|webhook| webhook.id),
+            None => admin
+                .create_webhook(topic, &address)
+                .await
+                .map(|webhook| webhook.id),
+        };
+
+        match subscribed {
+            Ok(shopify_webhook_id) => {
+                let saved = NewWebhookSubscription::registered(
+                    app.handle.clone(),
+                    shop.to_string(),
+                    topic.clone(),
+                    address.clone(),
+                    shopify_webhook_id,
+                )
+                .upsert(&db_conn.get_conn());
+                if let Err(e) = saved {
+                    log::error!(
+                        "could not save {} subscription for {}: {:?}",
+                        topic,
+                        shop,
+                        e
+                    );
+                }
+            }
+            Err(e) => {
+                failures += 1;
+                record_failure(db_conn, app, shop, topic, &address, &e);
+            }
+        }
+    }
+
+    // topics we've since stopped caring about
+    for webhook in existing
+        .iter()
+        .filter(|webhook| is_stale(config, &address, webhook))
+    {
+        if let Err(e) = admin.delete_webhook(webhook.id).await {
+            log::warn!(
+                "could not remove {} webhook for {}: {:?}",
+                webhook.topic,
+                shop,
+                e
+            );
+        }
+    }
+
+    failures
+}
+
+// try again for every install left with a failed subscription,
+// shops that have uninstalled the app since don't need them anymore.
+// shops without enough api calls to spare are left for the next round
+pub async fn retry_failed(
+    client: Arc<Client>,
+    rate_limiter: Arc<RateLimiter>,
+    config: &Config,
+    db_conn: &DbConn,
+) -> usize {
+    let mut failures = 0;
+    let cipher = config.token_cipher();
+    // listing what's there plus one call per topic
+    let calls_needed = (config.shopify_webhook_topics.len() + 1) as f64;
+
+    for (handle, shop) in webhook_subscription::read_failed_shops(&db_conn.get_conn()) {
+        if rate_limiter.budget(&shop).remaining() < calls_needed {
+            log::info!(
+                "{} is short on api calls, retrying its webhooks later",
+                shop
+            );
+            failures += 1;
+            continue;
+        }
+
+        let shop_conn = match shopify_connection::read_installed_by_app_and_shop(
+            &db_conn.get_conn(),
+            handle.clone(),
+            shop.clone(),
+        ) {
+            Some(shop_conn) => shop_conn,
+            None => {
+                if let Err(e) = webhook_subscription::delete_by_app_and_shop(
+                    &db_conn.get_conn(),
+                    handle.clone(),
+                    shop.clone(),
+                ) {
+                    log::error!(
+                        "could not drop {} subscriptions for {}: {:?}",
+                        handle,
+                        shop,
+                        e
+                    );
+                }
+                continue;
+            }
+        };
+
+        let access_token = match usable_access_token(db_conn, &cipher, &shop_conn) {
+            Ok(Some(access_token)) => access_token,
+            Ok(None) => {
+                log::info!(
+                    "{} has no unexpired access token, retrying its webhooks later",
+                    shop
+                );
+                failures += 1;
+                continue;
+            }
+            Err(e) => {
+                log::error!("could not open the access token for {}: {}", shop, e);
+                failures += 1;
+                continue;
+            }
+        };
+
+        // the webhooks go to the address of the app the shop installed
+        let app = match config.shopify_app(Some(&shop_conn.app)) {
+            Some(app) => app,
+            None => {
+                log::error!("{} is installed on unknown app {}", shop, shop_conn.app);
+                failures += 1;
+                continue;
+            }
+        };
+
+        failures += sync_subscriptions(
+            client.clone(),
+            rate_limiter.clone(),
+            config,
+            app,
+            db_conn,
+            &shop,
+            &access_token,
+        )
+        .await
+    }
+
+    failures
+}
+
+// offline installs keep their token on the connection, online ones
+// borrow whichever staff member's token is still good
+fn usable_access_token(
+    db_conn: &DbConn,
+    cipher: &TokenCipher,
+    shop_conn: &ShopifyConnection,
+) -> Result<Option<String>, EncryptionError> {
+    if let Some(access_token) = shop_conn.decrypt_access_token(cipher)? {
+        return Ok(Some(access_token));
+    }
+
+    shopify_online_token::read_unexpired_by_app_and_shop(
+        &db_conn.get_conn(),
+        shop_conn.app.clone(),
+        shop_conn.shop.clone(),
+    )
+    .map(|online_token| online_token.decrypt_access_token(cipher))
+    .transpose()
+}
+
+fn is_stale(config: &Config, address: &str, webhook: &Webhook) -> bool {
+    webhook.address == address && !config.shopify_webhook_topics.contains(&webhook.topic)
+}
+
+fn record_failure(
+    db_conn: &DbConn,
+    app: &ShopifyApp,
+    shop: &str,
+    topic: &str,
+    address: &str,
+    e: &AdminApiError,
+) {
+    log::warn!("could not subscribe {} to {}: {:?}", shop, topic, e);
+
+    let saved = NewWebhookSubscription::failed(
+        app.handle.clone(),
+        shop.to_string(),
+        topic.to_string(),
+        address.to_string(),
+        e.to_string(),
+    )
+    .upsert(&db_conn.get_conn());
+    if let Err(e) = saved {
+        log::error!(
+            "could not record failed {} subscription for {}: {:?}",
+            topic,
+            shop,
+            e
+        );
+    }
+}
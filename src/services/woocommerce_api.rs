@@ -0,0 +1,154 @@
+use crate::{
+    config::Config, errors::AdminApiError, models::woocommerce_connection::WooCommerceConnection,
+};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::sync::Arc;
+
+// woocommerce only does basic auth over https, see platforms::woocommerce::validate_store_url
+const API_PATH: &str = "wp-json/wc/v3";
+
+#[derive(Debug, Deserialize)]
+pub struct Coupon {
+    pub id: i64,
+    pub code: String,
+    pub amount: String,
+    pub discount_type: String,
+    pub description: String,
+    pub date_expires: Option<String>,
+    pub date_expires_gmt: Option<String>,
+    pub usage_count: i64,
+    pub usage_limit: Option<i64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct NewCoupon {
+    pub code: String,
+    pub discount_type: String,
+    pub amount: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_expires: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage_limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Webhook {
+    pub id: i64,
+    pub topic: String,
+    pub delivery_url: String,
+    pub status: String,
+}
+
+// the secret is what the store signs every delivery with
+#[derive(Debug, Serialize)]
+pub struct NewWebhook {
+    pub name: String,
+    pub topic: String,
+    pub delivery_url: String,
+    pub secret: String,
+}
+
+// talks to one store's rest api with the keys it gave us,
+// e.x. GET https://{store}/wp-json/wc/v3/coupons/719
+pub struct WooCommerceClient {
+    client: Arc<Client>,
+    base_url: String,
+    consumer_key: String,
+    consumer_secret: String,
+}
+
+impl WooCommerceClient {
+    pub fn new(
+        client: Arc<Client>,
+        config: &Config,
+        connection: &WooCommerceConnection,
+    ) -> Result<Self, AdminApiError> {
+        let consumer_secret = connection
+            .decrypt_consumer_secret(&config.token_cipher())?
+            .ok_or(AdminApiError::MissingAccessToken)?;
+        let consumer_key = connection
+            .consumer_key
+            .clone()
+            .ok_or(AdminApiError::MissingAccessToken)?;
+
+        Ok(WooCommerceClient::with_keys(
+            client,
+            &connection.store_url,
+            consumer_key,
+            consumer_secret,
+        ))
+    }
+
+    // for keys we have in hand, e.x. the ones woocommerce just delivered
+    pub fn with_keys(
+        client: Arc<Client>,
+        store_url: &str,
+        consumer_key: String,
+        consumer_secret: String,
+    ) -> Self {
+        WooCommerceClient {
+            client,
+            base_url: format!("{}/{}", store_url, API_PATH),
+            consumer_key,
+            consumer_secret,
+        }
+    }
+
+    pub async fn coupon(&self, id: i64) -> Result<Coupon, AdminApiError> {
+        self.send(self.client.get(self.url(&format!("coupons/{}", id))))
+            .await
+    }
+
+    pub async fn create_coupon(&self, coupon: &NewCoupon) -> Result<Coupon, AdminApiError> {
+        self.send(self.client.post(self.url("coupons")).json(coupon))
+            .await
+    }
+
+    // anything left off the body stays as it was
+    pub async fn update_coupon<B: Serialize>(
+        &self,
+        id: i64,
+        body: &B,
+    ) -> Result<Coupon, AdminApiError> {
+        self.send(
+            self.client
+                .put(self.url(&format!("coupons/{}", id)))
+                .json(body),
+        )
+        .await
+    }
+
+    // needs nothing but working keys, so it's how we check a delivery's keys
+    // really belong to the store before we keep them
+    pub async fn system_status(&self) -> Result<serde_json::Value, AdminApiError> {
+        self.send(self.client.get(self.url("system_status"))).await
+    }
+
+    pub async fn create_webhook(&self, webhook: &NewWebhook) -> Result<Webhook, AdminApiError> {
+        self.send(self.client.post(self.url("webhooks")).json(webhook))
+            .await
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url, path)
+    }
+
+    // unlike shopify nothing comes back wrapped
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, AdminApiError> {
+        let res = request
+            .basic_auth(&self.consumer_key, Some(&self.consumer_secret))
+            .send()
+            .await?;
+
+        match res.status() {
+            status if status.is_success() => Ok(serde_json::from_str(&res.text().await?)?),
+            StatusCode::UNAUTHORIZED => Err(AdminApiError::Unauthorized),
+            StatusCode::NOT_FOUND => Err(AdminApiError::NotFound),
+            StatusCode::TOO_MANY_REQUESTS => Err(AdminApiError::Throttled(None)),
+            status => Err(AdminApiError::Status(status, res.text().await?)),
+        }
+    }
+}
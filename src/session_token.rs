@@ -0,0 +1,310 @@
+use crate::{
+    errors::SessionError, models::shopify_connection::ShopifyConnection,
+    platforms::shopify::validate_shop, verification::matching_secret,
+};
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+// app bridge session tokens live for a minute,
+// give a little room for our clocks to disagree
+pub const LEEWAY_SECS: i64 = 5;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Header {
+    alg: String,
+    typ: Option<String>,
+}
+
+// e.x. {
+//   "iss": "https://some-shop.myshopify.com/admin",
+//   "dest": "https://some-shop.myshopify.com",
+//   "aud": "{api_key}",
+//   "sub": "902541635",
+//   "exp": 1591765058,
+//   "nbf": 1591764998,
+//   "iat": 1591764998,
+//   "jti": "f8912129-1af6-4cad-9ca3-76b0f7621087",
+//   "sid": "aaea182f2732d44c23057c0fea584021a4485b2bd25d3eb7fd349313ad24c685"
+// }
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Claims {
+    pub iss: String,
+    pub dest: String,
+    pub aud: String,
+    pub sub: Option<String>,
+    pub exp: i64,
+    pub nbf: i64,
+    pub iat: i64,
+    pub jti: String,
+    pub sid: Option<String>,
+}
+
+impl Claims {
+    // the shop's domain without the scheme, e.x. some-shop.myshopify.com,
+    // decode has already made sure it's a real shop domain
+    pub fn shop(&self) -> &str {
+        self.dest.trim_start_matches("https://")
+    }
+
+    // the token is only for a shop if the shop's own admin issued it
+    fn verify_shop(&self) -> Result<(), SessionError> {
+        let shop = self
+            .dest
+            .strip_prefix("https://")
+            .ok_or(SessionError::InvalidShop)?;
+        validate_shop(shop).map_err(|_| SessionError::InvalidShop)?;
+
+        let issuer = self
+            .iss
+            .strip_prefix("https://")
+            .and_then(|iss| iss.split('/').next());
+        if issuer != Some(shop) {
+            return Err(SessionError::WrongIssuer);
+        }
+
+        Ok(())
+    }
+
+    // the staff member the token was issued to
+    pub fn user_id(&self) -> Option<i64> {
+        self.sub.as_ref().and_then(|sub| sub.parse().ok())
+    }
+}
+
+// who is calling us, handed to any handler behind the session filter
+#[derive(Debug)]
+pub struct ShopSession {
+    pub shop: String,
+    pub user_id: Option<i64>,
+    pub session_id: Option<String>,
+    pub connection: ShopifyConnection,
+}
+
+// checks the HS256 signature, the time and audience claims and that the
+// shop issued the token for itself, whether it's installed is up to the caller
+pub fn decode(token: &str, secret: &str, api_key: &str, now: i64) -> Result<Claims, SessionError> {
+    let parts = token.split('.').collect::<Vec<&str>>();
+    if parts.len() != 3 {
+        return Err(SessionError::Malformed);
+    }
+
+    let header: Header = decode_segment(parts[0])?;
+    if header.alg != "HS256" {
+        return Err(SessionError::Malformed);
+    }
+
+    let signature = base64::decode_config(parts[2], base64::URL_SAFE_NO_PAD)
+        .map_err(|_| SessionError::Malformed)?;
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(format!("{}.{}", parts[0], parts[1]).as_bytes());
+    mac.verify(&signature)
+        .map_err(|_| SessionError::InvalidSignature)?;
+
+    let claims: Claims = decode_segment(parts[1])?;
+
+    if claims.exp + LEEWAY_SECS < now {
+        return Err(SessionError::Expired);
+    }
+
+    if claims.nbf - LEEWAY_SECS > now {
+        return Err(SessionError::NotYetValid);
+    }
+
+    if claims.aud != api_key {
+        return Err(SessionError::WrongAudience);
+    }
+
+    claims.verify_shop()?;
+
+    Ok(claims)
+}
+
+// decode with whichever secret signed the token, see verification::matching_secret
+pub fn decode_with_secrets(
+    token: &str,
+    secrets: &[&str],
+    api_key: &str,
+    now: i64,
+) -> Result<Claims, SessionError> {
+    let mut decoded = Err(SessionError::InvalidSignature);
+
+    matching_secret(secrets, "session token", |secret| {
+        decoded = decode(token, secret, api_key, now);
+        !matches!(decoded, Err(SessionError::InvalidSignature))
+    });
+
+    decoded
+}
+
+// the api key the token was issued for, read before the signature is
+// checked so we know which app's secret to check it with
+pub fn audience(token: &str) -> Result<String, SessionError> {
+    #[derive(Deserialize)]
+    struct Audience {
+        aud: String,
+    }
+
+    let payload = token.split('.').nth(1).ok_or(SessionError::Malformed)?;
+    let audience: Audience = decode_segment(payload)?;
+
+    Ok(audience.aud)
+}
+
+fn decode_segment<T: serde::de::DeserializeOwned>(segment: &str) -> Result<T, SessionError> {
+    let bytes = base64::decode_config(segment, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| SessionError::Malformed)?;
+    serde_json::from_slice(&bytes).map_err(|_| SessionError::Malformed)
+}
+
+// mostly for tests, shopify is the one handing these out
+pub fn encode(claims: &Claims, secret: &str) -> String {
+    let header = Header {
+        alg: String::from("HS256"),
+        typ: Some(String::from("JWT")),
+    };
+
+    let signing_input = format!("{}.{}", encode_segment(&header), encode_segment(claims));
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(signing_input.as_bytes());
+    let signature = base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+
+    format!("{}.{}", signing_input, signature)
+}
+
+fn encode_segment<T: Serialize>(value: &T) -> String {
+    base64::encode_config(
+        serde_json::to_vec(value).expect("Could not serialize session token"),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1591764998;
+
+    fn mock_claims() -> Claims {
+        Claims {
+            iss: String::from("https://some-shop.myshopify.com/admin"),
+            dest: String::from("https://some-shop.myshopify.com"),
+            aud: String::from("api-key"),
+            sub: Some(String::from("902541635")),
+            exp: NOW + 60,
+            nbf: NOW,
+            iat: NOW,
+            jti: String::from("f8912129-1af6-4cad-9ca3-76b0f7621087"),
+            sid: None,
+        }
+    }
+
+    #[test]
+    fn it_decodes_a_valid_session_token() {
+        let token = encode(&mock_claims(), "hush");
+
+        let claims = decode(&token, "hush", "api-key", NOW).unwrap();
+
+        assert_eq!(claims.shop(), "some-shop.myshopify.com");
+        assert_eq!(claims.user_id(), Some(902541635));
+    }
+
+    #[test]
+    fn it_rejects_a_bad_signature() {
+        let token = encode(&mock_claims(), "not-hush");
+
+        assert_eq!(
+            decode(&token, "hush", "api-key", NOW).unwrap_err(),
+            SessionError::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn it_rejects_expired_and_early_session_tokens() {
+        let token = encode(&mock_claims(), "hush");
+
+        assert_eq!(
+            decode(&token, "hush", "api-key", NOW + 120).unwrap_err(),
+            SessionError::Expired
+        );
+        assert_eq!(
+            decode(&token, "hush", "api-key", NOW - 60).unwrap_err(),
+            SessionError::NotYetValid
+        );
+    }
+
+    #[test]
+    fn it_decodes_a_session_token_signed_with_a_previous_secret() {
+        let token = encode(&mock_claims(), "old-hush");
+
+        let claims = decode_with_secrets(&token, &["hush", "old-hush"], "api-key", NOW).unwrap();
+        assert_eq!(claims.shop(), "some-shop.myshopify.com");
+
+        assert_eq!(
+            decode_with_secrets(&token, &["hush"], "api-key", NOW).unwrap_err(),
+            SessionError::InvalidSignature
+        );
+        assert_eq!(
+            decode_with_secrets(&token, &["hush", "old-hush"], "api-key", NOW + 120).unwrap_err(),
+            SessionError::Expired
+        );
+    }
+
+    #[test]
+    fn it_rejects_a_session_token_for_another_app() {
+        let token = encode(&mock_claims(), "hush");
+
+        assert_eq!(
+            decode(&token, "hush", "other-api-key", NOW).unwrap_err(),
+            SessionError::WrongAudience
+        );
+    }
+
+    #[test]
+    fn it_rejects_a_session_token_for_a_bad_shop() {
+        let mut claims = mock_claims();
+        claims.dest = String::from("https://evil.example.com");
+        claims.iss = String::from("https://evil.example.com/admin");
+        let token = encode(&claims, "hush");
+
+        assert_eq!(
+            decode(&token, "hush", "api-key", NOW).unwrap_err(),
+            SessionError::InvalidShop
+        );
+    }
+
+    #[test]
+    fn it_rejects_a_session_token_issued_by_another_shop() {
+        let mut claims = mock_claims();
+        claims.iss = String::from("https://other-shop.myshopify.com/admin");
+        let token = encode(&claims, "hush");
+
+        assert_eq!(
+            decode(&token, "hush", "api-key", NOW).unwrap_err(),
+            SessionError::WrongIssuer
+        );
+    }
+
+    #[test]
+    fn it_reads_the_audience_before_checking_the_signature() {
+        let token = encode(&mock_claims(), "anything");
+
+        assert_eq!(audience(&token).unwrap(), "api-key");
+        assert_eq!(audience("nope").unwrap_err(), SessionError::Malformed);
+    }
+
+    #[test]
+    fn it_rejects_garbage() {
+        assert_eq!(
+            decode("not.a.jwt", "hush", "api-key", NOW).unwrap_err(),
+            SessionError::Malformed
+        );
+        assert_eq!(
+            decode("nope", "hush", "api-key", NOW).unwrap_err(),
+            SessionError::Malformed
+        );
+    }
+}
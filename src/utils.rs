@@ -10,6 +10,10 @@ pub fn now() -> chrono::naive::NaiveDateTime {
     Utc::now().naive_local()
 }
 
+pub fn now_timestamp() -> i64 {
+    Utc::now().timestamp()
+}
+
 pub fn read_file_to_string(path: &String) -> std::io::Result<String> {
     let mut file = File::open(path)?;
     let mut contents = String::new();
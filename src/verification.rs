@@ -0,0 +1,326 @@
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+
+// shopify signs every query param except the hmac itself,
+// sorted by key and joined into a query string, e.x.
+// "code=0907a61c0c8d55e99db179b68161bc00&shop=some-shop.myshopify.com&state=0.6784241404160823&timestamp=1337178173"
+//
+// array params like `ids[]=1&ids[]=2` are collapsed into `ids=["1", "2"]`
+// and any `%`, `&` (or `=` in keys) left after decoding are escaped again
+pub fn hmac_message_from_params<I, K, V>(params: I) -> String
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let mut sorted: BTreeMap<String, (bool, Vec<String>)> = BTreeMap::new();
+
+    for (key, value) in params {
+        let key = key.as_ref();
+        if key == "hmac" {
+            continue;
+        }
+
+        let (key, is_array) = match key.strip_suffix("[]") {
+            Some(stripped) => (stripped, true),
+            None => (key, false),
+        };
+
+        let entry = sorted
+            .entry(escape_key(key))
+            .or_insert_with(|| (is_array, Vec::new()));
+
+        if is_array {
+            entry.0 = true;
+        } else {
+            entry.1.clear();
+        }
+        entry.1.push(escape_value(value.as_ref()));
+    }
+
+    sorted
+        .iter()
+        .map(|(key, (is_array, values))| {
+            if *is_array {
+                let quoted = values
+                    .iter()
+                    .map(|value| format!("\"{}\"", value))
+                    .collect::<Vec<String>>();
+                format!("{}=[{}]", key, quoted.join(", "))
+            } else {
+                format!("{}={}", key, values.join(""))
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+// same as above, but starting from the percent-encoded query string
+pub fn hmac_message_from_query(query: &str) -> String {
+    hmac_message_from_params(form_urlencoded::parse(query.as_bytes()))
+}
+
+// checks the hex encoded hmac shopify sent against our own signature,
+// `verify` does the comparison in constant time
+pub fn verify_hmac(secret: &str, message: &str, hmac: &str) -> bool {
+    let hmac_bytes = match hex::decode(hmac) {
+        Ok(bytes) => bytes,
+        Err(_) => return false,
+    };
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(message.as_bytes());
+    mac.verify(&hmac_bytes).is_ok()
+}
+
+pub fn verify_params_hmac<I, K, V>(secret: &str, params: I) -> bool
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let params = params
+        .into_iter()
+        .map(|(key, value)| (key.as_ref().to_string(), value.as_ref().to_string()))
+        .collect::<Vec<(String, String)>>();
+
+    match params.iter().find(|(key, _)| key == "hmac") {
+        Some((_, hmac)) => verify_hmac(
+            secret,
+            &hmac_message_from_params(params.iter().map(|(key, value)| (key, value))),
+            hmac,
+        ),
+        None => false,
+    }
+}
+
+pub fn verify_query_hmac(secret: &str, query: &str) -> bool {
+    verify_params_hmac(secret, form_urlencoded::parse(query.as_bytes()))
+}
+
+// webhooks are signed over the raw request body and the hmac comes
+// base64 encoded in the X-Shopify-Hmac-Sha256 header
+pub fn verify_webhook_hmac(secret: &str, body: &[u8], hmac: &str) -> bool {
+    let hmac_bytes = match base64::decode(hmac) {
+        Ok(bytes) => bytes,
+        Err(_) => return false,
+    };
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(body);
+    mac.verify(&hmac_bytes).is_ok()
+}
+
+// tries each secret in turn, the current one first, so rotating the secret
+// doesn't break anything shopify signed before the switch. hands back which
+// one matched, 0 being the current secret. once nothing is logged against
+// an old secret for a while it's safe to drop it
+pub fn matching_secret<F>(secrets: &[&str], signed: &str, mut verify: F) -> Option<usize>
+where
+    F: FnMut(&str) -> bool,
+{
+    let matched = secrets.iter().position(|secret| verify(secret));
+
+    match matched {
+        Some(0) => log::debug!("{} verified with the current api secret", signed),
+        Some(index) => log::info!("{} verified with previous api secret #{}", signed, index),
+        None => log::debug!("{} didn't verify with any api secret", signed),
+    }
+
+    matched
+}
+
+// shopify sends the unix time it signed the request at,
+// anything too far in the past or future may be a replay. the timestamp is
+// whatever the caller sent, one far enough out to overflow is just as stale
+pub fn verify_timestamp(timestamp: &str, now: i64, skew_secs: i64) -> bool {
+    match timestamp.parse::<i64>() {
+        Ok(timestamp) => matches!(
+            now.checked_sub(timestamp).and_then(|drift| drift.checked_abs()),
+            Some(drift) if drift <= skew_secs
+        ),
+        Err(_) => false,
+    }
+}
+
+// shopify can grant fewer scopes than we asked for, a write scope
+// implies the matching read scope so it won't be listed on its own
+pub fn missing_scopes(required: &[String], granted: &str) -> Vec<String> {
+    let granted = granted
+        .split(',')
+        .map(|scope| scope.trim())
+        .collect::<Vec<&str>>();
+
+    required
+        .iter()
+        .filter(|scope| {
+            let implied_by = scope.replacen("read_", "write_", 1);
+            !granted.contains(&scope.as_str()) && !granted.contains(&implied_by.as_str())
+        })
+        .cloned()
+        .collect()
+}
+
+fn escape_value(value: &str) -> String {
+    value.replace('%', "%25").replace('&', "%26")
+}
+
+fn escape_key(key: &str) -> String {
+    escape_value(key).replace('=', "%3D")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn sign(secret: &str, message: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(message.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn it_matches_the_secret_that_signed_the_message() {
+        let query = "code=0907a61c0c8d55e99db179b68161bc00\
+            &hmac=700e2dadb827fcc8609e9d5ce208b2e9cdaab9df07390d2cbca10d7c328fc4bf\
+            &shop=some-shop.myshopify.com\
+            &state=0.6784241404160823\
+            &timestamp=1337178173";
+        let verify = |secret: &str| verify_query_hmac(secret, query);
+
+        assert_eq!(
+            matching_secret(&["hush", "old-hush"], "install", verify),
+            Some(0)
+        );
+        assert_eq!(
+            matching_secret(&["new-hush", "hush"], "install", verify),
+            Some(1)
+        );
+        assert_eq!(
+            matching_secret(&["new-hush", "old-hush"], "install", verify),
+            None
+        );
+    }
+
+    #[test]
+    fn it_verifies_the_documented_shopify_example() {
+        let query = "code=0907a61c0c8d55e99db179b68161bc00\
+            &hmac=700e2dadb827fcc8609e9d5ce208b2e9cdaab9df07390d2cbca10d7c328fc4bf\
+            &shop=some-shop.myshopify.com\
+            &state=0.6784241404160823\
+            &timestamp=1337178173";
+
+        assert!(verify_query_hmac("hush", query));
+        assert!(!verify_query_hmac("not-hush", query));
+    }
+
+    #[test]
+    fn it_sorts_params_and_drops_the_hmac() {
+        let query = "timestamp=1337178173&hmac=abc&shop=some-shop.myshopify.com&host=aG9zdA%3D%3D";
+
+        assert_eq!(
+            hmac_message_from_query(query),
+            "host=aG9zdA==&shop=some-shop.myshopify.com&timestamp=1337178173"
+        );
+    }
+
+    #[test]
+    fn it_collapses_array_params() {
+        let query = "shop=some-shop.myshopify.com&ids%5B%5D=2&ids[]=1";
+
+        assert_eq!(
+            hmac_message_from_query(query),
+            "ids=[\"2\", \"1\"]&shop=some-shop.myshopify.com"
+        );
+    }
+
+    #[test]
+    fn it_escapes_reserved_characters() {
+        let query = "a%3Db=c%26d&percent=100%25";
+
+        assert_eq!(hmac_message_from_query(query), "a%3Db=c%26d&percent=100%25");
+    }
+
+    #[test]
+    fn it_verifies_a_map_of_params() {
+        let mut params = HashMap::new();
+        params.insert("shop", "some-shop.myshopify.com");
+        params.insert("timestamp", "1337178173");
+
+        let hmac = sign("hush", &hmac_message_from_params(&params));
+        params.insert("hmac", &hmac);
+
+        assert!(verify_params_hmac("hush", &params));
+
+        params.insert("timestamp", "1337178174");
+        assert!(!verify_params_hmac("hush", &params));
+    }
+
+    #[test]
+    fn it_rejects_a_missing_or_malformed_hmac() {
+        assert!(!verify_query_hmac("hush", "shop=some-shop.myshopify.com"));
+        assert!(!verify_query_hmac(
+            "hush",
+            "shop=some-shop.myshopify.com&hmac=not-hex"
+        ));
+    }
+
+    #[test]
+    fn it_verifies_a_webhook_body() {
+        let body = br#"{"id":820982911946154508,"domain":"some-shop.myshopify.com"}"#;
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"hush").unwrap();
+        mac.update(body);
+        let hmac = base64::encode(mac.finalize().into_bytes());
+
+        assert!(verify_webhook_hmac("hush", body, &hmac));
+        assert!(!verify_webhook_hmac("not-hush", body, &hmac));
+        assert!(!verify_webhook_hmac(
+            "hush",
+            br#"{"id":820982911946154508,"domain":"other-shop.myshopify.com"}"#,
+            &hmac
+        ));
+        assert!(!verify_webhook_hmac("hush", body, "not base64!"));
+    }
+
+    #[test]
+    fn it_verifies_fresh_timestamps() {
+        let now = 1623154978;
+
+        assert!(verify_timestamp("1623154978", now, 300));
+        assert!(verify_timestamp("1623154700", now, 300));
+        assert!(verify_timestamp("1623155200", now, 300));
+    }
+
+    #[test]
+    fn it_rejects_stale_future_and_malformed_timestamps() {
+        let now = 1623154978;
+
+        assert!(!verify_timestamp("1337178173", now, 300));
+        assert!(!verify_timestamp("1623155300", now, 300));
+        assert!(!verify_timestamp("yesterday", now, 300));
+        assert!(!verify_timestamp("-9223372036854775808", now, 300));
+        assert!(!verify_timestamp("9223372036854775807", -now, 300));
+    }
+
+    #[test]
+    fn it_finds_missing_scopes() {
+        let required = vec![
+            String::from("read_orders"),
+            String::from("write_products"),
+            String::from("read_customers"),
+        ];
+
+        assert!(missing_scopes(&required, "write_orders,write_products,read_customers").is_empty());
+        assert_eq!(
+            missing_scopes(&required, "read_orders,read_products"),
+            vec![
+                String::from("write_products"),
+                String::from("read_customers")
+            ]
+        );
+    }
+}
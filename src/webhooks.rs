@@ -0,0 +1,137 @@
+use crate::{config::DEFAULT_SHOPIFY_APP, db_conn::DbConn, errors::WebhookError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use warp::hyper::body::Bytes;
+
+pub const HMAC_HEADER: &str = "x-shopify-hmac-sha256";
+pub const TOPIC_HEADER: &str = "x-shopify-topic";
+pub const SHOP_DOMAIN_HEADER: &str = "x-shopify-shop-domain";
+pub const WEBHOOK_ID_HEADER: &str = "x-shopify-webhook-id";
+
+pub const APP_UNINSTALLED: &str = "app/uninstalled";
+pub const CUSTOMERS_DATA_REQUEST: &str = "customers/data_request";
+pub const CUSTOMERS_REDACT: &str = "customers/redact";
+pub const SHOP_REDACT: &str = "shop/redact";
+
+// the kind of job a shopify webhook is queued up as,
+// other platforms have their own, e.x. woocommerce::WEBHOOK_JOB
+pub const WEBHOOK_JOB: &str = "shopify_webhook";
+
+// a webhook that has already passed hmac verification,
+// the body is left as shopify sent it for the handler to parse
+#[derive(Debug)]
+pub struct Webhook {
+    // handle of the app the webhook was sent to
+    pub app: String,
+    pub topic: String,
+    pub shop_domain: String,
+    pub webhook_id: String,
+    pub body: Bytes,
+}
+
+// how a webhook waits in the job queue, shopify only ever sends json
+#[derive(Deserialize, Serialize)]
+struct WebhookPayload {
+    // jobs queued before we ran more than one app don't have it
+    #[serde(default = "default_app")]
+    app: String,
+    topic: String,
+    shop_domain: String,
+    webhook_id: String,
+    body: String,
+}
+
+impl Webhook {
+    pub fn to_payload(&self) -> String {
+        serde_json::to_string(&WebhookPayload {
+            app: self.app.clone(),
+            topic: self.topic.clone(),
+            shop_domain: self.shop_domain.clone(),
+            webhook_id: self.webhook_id.clone(),
+            body: String::from_utf8_lossy(&self.body).into_owned(),
+        })
+        .expect("Could not serialize webhook")
+    }
+
+    pub fn from_payload(payload: &str) -> serde_json::Result<Self> {
+        let payload: WebhookPayload = serde_json::from_str(payload)?;
+
+        Ok(Webhook {
+            app: payload.app,
+            topic: payload.topic,
+            shop_domain: payload.shop_domain,
+            webhook_id: payload.webhook_id,
+            body: Bytes::from(payload.body),
+        })
+    }
+}
+
+fn default_app() -> String {
+    String::from(DEFAULT_SHOPIFY_APP)
+}
+
+pub type WebhookHandler = fn(&Webhook, &DbConn) -> Result<(), WebhookError>;
+
+// which handler takes care of which topic, e.x. "app/uninstalled"
+#[derive(Default)]
+pub struct WebhookRegistry {
+    handlers: HashMap<String, WebhookHandler>,
+}
+
+impl WebhookRegistry {
+    pub fn new() -> Self {
+        WebhookRegistry::default()
+    }
+
+    pub fn register(mut self, topic: &str, handler: WebhookHandler) -> Self {
+        self.handlers.insert(topic.to_string(), handler);
+        self
+    }
+
+    pub fn handles(&self, topic: &str) -> bool {
+        self.handlers.contains_key(topic)
+    }
+
+    pub fn topics(&self) -> Vec<&str> {
+        self.handlers.keys().map(|topic| topic.as_str()).collect()
+    }
+
+    // topics nobody registered for are acknowledged and dropped,
+    // failing them would only have shopify retry them
+    pub fn dispatch(&self, webhook: &Webhook, db_conn: &DbConn) -> Result<(), WebhookError> {
+        match self.handlers.get(&webhook.topic) {
+            Some(handler) => handler(webhook, db_conn),
+            None => {
+                log::info!(
+                    "no handler for {} webhook from {}",
+                    webhook.topic,
+                    webhook.shop_domain
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+// which registry runs each kind of webhook job, so a webhook only ever
+// reaches the handlers of the platform it came from
+#[derive(Default)]
+pub struct WebhookJobs {
+    registries: HashMap<&'static str, Arc<WebhookRegistry>>,
+}
+
+impl WebhookJobs {
+    pub fn new() -> Self {
+        WebhookJobs::default()
+    }
+
+    pub fn register(mut self, kind: &'static str, registry: WebhookRegistry) -> Self {
+        self.registries.insert(kind, Arc::new(registry));
+        self
+    }
+
+    pub fn registry(&self, kind: &str) -> Option<Arc<WebhookRegistry>> {
+        self.registries.get(kind).cloned()
+    }
+}
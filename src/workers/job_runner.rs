@@ -0,0 +1,91 @@
+use crate::{
+    config::Config,
+    db_conn::DbConn,
+    errors::WebhookError,
+    models::job::{self, Job},
+    webhooks::{Webhook, WebhookJobs},
+};
+use chrono::Duration;
+use diesel::QueryResult;
+use std::sync::Arc;
+
+// keeps taking jobs off the queue, naps whenever there's nothing due.
+// jobs and the pool are all blocking diesel, so they run on tokio's
+// blocking threads and leave the ones serving requests alone
+pub async fn run(config: Arc<Config>, db_conn: Arc<DbConn>, webhook_jobs: Arc<WebhookJobs>) {
+    let poll_interval = std::time::Duration::from_millis(config.job_poll_interval_ms);
+
+    loop {
+        let (next_config, next_db_conn, next_webhook_jobs) =
+            (config.clone(), db_conn.clone(), webhook_jobs.clone());
+        let ran = tokio::task::spawn_blocking(move || {
+            run_next(&next_config, &next_db_conn, &next_webhook_jobs)
+        })
+        .await;
+
+        match ran {
+            Ok(Ok(true)) => {}
+            Ok(Ok(false)) => tokio::time::sleep(poll_interval).await,
+            Ok(Err(e)) => {
+                log::error!("could not take a job off the queue: {:?}", e);
+                tokio::time::sleep(poll_interval).await;
+            }
+            Err(e) => {
+                log::error!("job runner stopped partway through a job: {:?}", e);
+                tokio::time::sleep(poll_interval).await;
+            }
+        }
+    }
+}
+
+// runs the next due job if there is one, true if there was
+pub fn run_next(
+    config: &Config,
+    db_conn: &DbConn,
+    webhook_jobs: &WebhookJobs,
+) -> QueryResult<bool> {
+    let claimed = job::claim_next(
+        &db_conn.get_conn(),
+        Duration::seconds(config.job_lease_secs),
+        config.job_max_attempts,
+    )?;
+    let job = match claimed {
+        Some(job) => job,
+        None => return Ok(false),
+    };
+
+    match perform(&job, db_conn, webhook_jobs) {
+        Ok(()) => {
+            job::complete(&db_conn.get_conn(), &job)?;
+        }
+        Err(e) => {
+            log::warn!(
+                "{} job {} for {} failed on attempt {}: {}",
+                job.kind,
+                job.id,
+                job.shop,
+                job.attempts,
+                e
+            );
+            job::fail(
+                &db_conn.get_conn(),
+                &job,
+                e,
+                config.job_max_attempts,
+                config.job_retry_base_secs,
+            )?;
+        }
+    }
+
+    Ok(true)
+}
+
+fn perform(job: &Job, db_conn: &DbConn, webhook_jobs: &WebhookJobs) -> Result<(), String> {
+    match webhook_jobs.registry(&job.kind) {
+        Some(registry) => Webhook::from_payload(&job.payload)
+            .map_err(WebhookError::from)
+            .and_then(|webhook| registry.dispatch(&webhook, db_conn))
+            .map_err(|e| format!("{:?}", e)),
+        None => Err(format!("no runner for {} jobs", job.kind)),
+    }
+}
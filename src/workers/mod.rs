@@ -0,0 +1,4 @@
+pub mod job_runner;
+pub mod nonce_sweeper;
+pub mod processed_webhook_sweeper;
+pub mod webhook_subscription_retrier;
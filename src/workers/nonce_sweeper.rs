@@ -0,0 +1,28 @@
+use crate::{
+    db_conn::DbConn,
+    models::{shopify_connection, woocommerce_connection},
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+// every so often, clean up the installs that were started
+// but never confirmed so they don't pile up forever
+pub async fn run(db_conn: Arc<DbConn>, interval_secs: u64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+
+    loop {
+        interval.tick().await;
+
+        match shopify_connection::soft_delete_expired_pending(&db_conn.get_conn()) {
+            Ok(0) => {}
+            Ok(count) => log::info!("swept {} abandoned shopify installs", count),
+            Err(e) => log::error!("could not sweep abandoned shopify installs: {:?}", e),
+        }
+
+        match woocommerce_connection::soft_delete_expired_pending(&db_conn.get_conn()) {
+            Ok(0) => {}
+            Ok(count) => log::info!("swept {} abandoned woocommerce installs", count),
+            Err(e) => log::error!("could not sweep abandoned woocommerce installs: {:?}", e),
+        }
+    }
+}
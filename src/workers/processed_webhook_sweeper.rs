@@ -0,0 +1,19 @@
+use crate::{db_conn::DbConn, models::processed_webhook, utils::now};
+use chrono::Duration;
+use std::sync::Arc;
+
+// forget about webhooks old enough that shopify won't send them again
+pub async fn run(db_conn: Arc<DbConn>, interval_secs: u64, retention_secs: i64) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_secs));
+
+    loop {
+        interval.tick().await;
+
+        let cutoff = now() - Duration::seconds(retention_secs);
+        match processed_webhook::delete_received_before(&db_conn.get_conn(), cutoff) {
+            Ok(0) => {}
+            Ok(count) => log::info!("swept {} processed webhooks", count),
+            Err(e) => log::error!("could not sweep processed webhooks: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use crate::{
+    config::Config,
+    db_conn::DbConn,
+    services::{rate_limiter::RateLimiter, webhook_subscription_service},
+};
+use reqwest::Client;
+use std::sync::Arc;
+use std::time::Duration;
+
+// subscriptions that failed after an install get another go every so often
+pub async fn run(
+    config: Arc<Config>,
+    db_conn: Arc<DbConn>,
+    client: Arc<Client>,
+    rate_limiter: Arc<RateLimiter>,
+) {
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(config.webhook_retry_interval_secs));
+
+    loop {
+        interval.tick().await;
+
+        match webhook_subscription_service::retry_failed(
+            client.clone(),
+            rate_limiter.clone(),
+            &config,
+            &db_conn,
+        )
+        .await
+        {
+            0 => {}
+            count => log::warn!("{} webhook subscriptions are still failing", count),
+        }
+    }
+}
@@ -0,0 +1,1024 @@
+mod shopify_admin_tests {
+
+    use diesel::prelude::*;
+    use futures::{StreamExt, TryStreamExt};
+    use mockito::{mock, Matcher};
+    use reqwest::StatusCode;
+    use rust_oauth2_study::{
+        config::{Config, ShopifyApp},
+        db_conn::DbConn,
+        db_test_url,
+        errors::{AdminApiError, UserError},
+        models::shopify_connection::{self, NewShopifyConnection, ShopifyConnection},
+        platforms::{self, shopify::ShopifyPlatform, CommercePlatform},
+        schema::shopify_connections,
+        services::{
+            rate_limiter::RateLimiter,
+            shopify_admin::{
+                resources::{NewGiftCard, Order},
+                AdminClient,
+            },
+        },
+    };
+    use serde::Deserialize;
+    use serde_json::json;
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    fn cleanup_table(conn: &PgConnection) {
+        diesel::delete(shopify_connections::table)
+            .execute(conn)
+            .unwrap();
+    }
+
+    fn admin_config() -> Config {
+        let mut config = Config::new(true);
+        config.set_shopify_api_uri(mockito::server_url());
+        config
+    }
+
+    fn api_path(config: &Config, path: &str) -> String {
+        format!("/admin/api/{}/{}.json", config.shopify_api_version, path)
+    }
+
+    fn installed_connection(conn: &PgConnection) -> ShopifyConnection {
+        NewShopifyConnection::installed(
+            &Config::new(true).token_cipher(),
+            String::from("some-shop.myshopify.com"),
+            String::from("some-nonce"),
+            String::from("f85632530bf277ec9ac6f649fc327f17"),
+            String::from("read_orders,write_gift_cards"),
+        )
+        .insert(conn)
+    }
+
+    fn admin_client(config: &Config, shopify_connection: &ShopifyConnection) -> AdminClient {
+        AdminClient::new(
+            Arc::new(reqwest::Client::new()),
+            Arc::new(RateLimiter::new(config)),
+            config,
+            shopify_connection,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_reads_the_shop_with_the_stored_access_token() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let admin = admin_client(&config, &installed_connection(&db_conn.get_conn()));
+
+        let _m = mock("GET", api_path(&config, "shop").as_str())
+            .match_header("x-shopify-access-token", "f85632530bf277ec9ac6f649fc327f17")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"shop": {
+                    "id": 690933842,
+                    "name": "Some Shop",
+                    "email": "owner@example.com",
+                    "domain": "shop.example.com",
+                    "myshopify_domain": "some-shop.myshopify.com",
+                    "currency": "USD",
+                    "plan_name": "basic"
+                }}"#,
+            )
+            .create();
+
+        let shop = admin.shop().await.unwrap();
+
+        assert_eq!(shop.id, 690933842);
+        assert_eq!(shop.myshopify_domain, "some-shop.myshopify.com");
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_fails_to_decode_an_unwrapped_body_instead_of_panicking() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let admin = admin_client(&config, &installed_connection(&db_conn.get_conn()));
+
+        let _m = mock("GET", api_path(&config, "shop").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body("[]")
+            .create();
+
+        assert!(matches!(admin.shop().await, Err(AdminApiError::Decode(_))));
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_lists_orders_with_a_query() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let admin = admin_client(&config, &installed_connection(&db_conn.get_conn()));
+
+        let _m = mock("GET", api_path(&config, "orders").as_str())
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded(String::from("status"), String::from("any")),
+                Matcher::UrlEncoded(String::from("limit"), String::from("50")),
+            ]))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"orders": [{
+                    "id": 450789469,
+                    "name": "1001",
+                    "email": "bob@example.com",
+                    "financial_status": "paid",
+                    "currency": "USD",
+                    "total_price": "25.00",
+                    "line_items": [{
+                        "id": 466157049,
+                        "product_id": 632910392,
+                        "variant_id": 39072856,
+                        "title": "Gift Card",
+                        "quantity": 1,
+                        "price": "25.00",
+                        "gift_card": true
+                    }]
+                }]}"#,
+            )
+            .create();
+
+        let orders = admin
+            .orders(&[("status", "any"), ("limit", "50")])
+            .await
+            .unwrap();
+
+        assert_eq!(1, orders.len());
+        assert_eq!(orders[0].total_price, "25.00");
+        assert!(orders[0].line_items[0].gift_card);
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_creates_a_gift_card() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let admin = admin_client(&config, &installed_connection(&db_conn.get_conn()));
+
+        let m = mock("POST", api_path(&config, "gift_cards").as_str())
+            .match_body(Matcher::Json(serde_json::json!({
+                "gift_card": { "initial_value": "25.00", "note": "store credit" }
+            })))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"gift_card": {
+                    "id": 1063936316,
+                    "balance": "25.00",
+                    "initial_value": "25.00",
+                    "currency": "USD",
+                    "code": "d7a2bcggda89c293",
+                    "last_characters": "c293",
+                    "customer_id": null,
+                    "note": "store credit",
+                    "expires_on": null,
+                    "disabled_at": null
+                }}"#,
+            )
+            .create();
+
+        let gift_card = admin
+            .create_gift_card(&NewGiftCard {
+                initial_value: String::from("25.00"),
+                note: Some(String::from("store credit")),
+                ..NewGiftCard::default()
+            })
+            .await
+            .unwrap();
+
+        m.assert();
+        assert_eq!(gift_card.code.as_deref(), Some("d7a2bcggda89c293"));
+        assert_eq!(gift_card.last_characters, "c293");
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    fn platform_gift_card(id: i64, disabled_at: Option<&str>) -> String {
+        json!({"gift_card": {
+            "id": id,
+            "balance": "25.00",
+            "initial_value": "25.00",
+            "currency": "USD",
+            "code": null,
+            "last_characters": "c293",
+            "customer_id": null,
+            "note": "store credit",
+            "expires_on": null,
+            "disabled_at": disabled_at
+        }})
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn it_manages_gift_cards_through_the_platform() {
+        let mut config = admin_config();
+        config.add_shopify_app(ShopifyApp::new(
+            "staging",
+            String::from("staging-key"),
+            String::from("staging-hush"),
+        ));
+        let config = Arc::new(config);
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        installed_connection(&db_conn.get_conn());
+        let platform = ShopifyPlatform::new(
+            config.clone(),
+            db_conn.clone(),
+            Arc::new(reqwest::Client::new()),
+            Arc::new(RateLimiter::new(&config)),
+        );
+
+        let created = mock("POST", api_path(&config, "gift_cards").as_str())
+            .match_body(Matcher::Json(json!({
+                "gift_card": { "initial_value": "25.00", "note": "store credit" }
+            })))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(platform_gift_card(1063936316, None))
+            .create();
+        let disabled = mock(
+            "POST",
+            api_path(&config, "gift_cards/1063936316/disable").as_str(),
+        )
+        .match_header("x-shopify-access-token", "f85632530bf277ec9ac6f649fc327f17")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(platform_gift_card(
+            1063936316,
+            Some("2021-07-15T10:00:00-04:00"),
+        ))
+        .create();
+
+        let gift_card = platform
+            .create_gift_card(
+                None,
+                "some-shop.myshopify.com",
+                &platforms::NewGiftCard {
+                    initial_value: String::from("25.00"),
+                    note: Some(String::from("store credit")),
+                    ..platforms::NewGiftCard::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(gift_card.id, "1063936316");
+        assert!(!gift_card.disabled);
+
+        let gift_card = platform
+            .disable_gift_card(None, "some-shop.myshopify.com", &gift_card.id)
+            .await
+            .unwrap();
+
+        created.assert();
+        disabled.assert();
+        assert!(gift_card.disabled);
+        assert!(matches!(
+            platform
+                .gift_card(None, "some-shop.myshopify.com", "not-a-number")
+                .await,
+            Err(AdminApiError::NotFound)
+        ));
+        // only the default app is installed on the shop
+        assert!(matches!(
+            platform
+                .gift_card(Some("staging"), "some-shop.myshopify.com", "1063936316")
+                .await,
+            Err(AdminApiError::MissingAccessToken)
+        ));
+        assert!(matches!(
+            platform
+                .gift_card(Some("nope"), "some-shop.myshopify.com", "1063936316")
+                .await,
+            Err(AdminApiError::UnknownApp)
+        ));
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_types_admin_api_errors() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let admin = admin_client(&config, &installed_connection(&db_conn.get_conn()));
+
+        let _not_found = mock("GET", api_path(&config, "price_rules/1").as_str())
+            .with_status(404)
+            .with_body(r#"{"errors": "Not Found"}"#)
+            .create();
+        let _throttled = mock("GET", api_path(&config, "customers").as_str())
+            .with_status(429)
+            .with_header("retry-after", "0.1")
+            .with_body(r#"{"errors": "Exceeded 2 calls per second for api client."}"#)
+            .create();
+        let _unprocessable = mock("GET", api_path(&config, "products").as_str())
+            .match_query(Matcher::Any)
+            .with_status(422)
+            .with_body(r#"{"errors": {"limit": ["is invalid"]}}"#)
+            .create();
+
+        assert!(matches!(
+            admin.price_rule(1).await,
+            Err(AdminApiError::NotFound)
+        ));
+        assert!(matches!(
+            admin.customers(&[]).await,
+            Err(AdminApiError::Throttled(Some(secs))) if (secs - 0.1).abs() < f64::EPSILON
+        ));
+        match admin.products(&[("limit", "nope")]).await {
+            Err(AdminApiError::Status(status, body)) => {
+                assert_eq!(status.as_u16(), 422);
+                assert!(body.contains("is invalid"));
+            }
+            other => panic!("Expected a 422, got {:?}", other),
+        }
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_needs_an_access_token() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let pending = NewShopifyConnection::new(
+            String::from("some-shop.myshopify.com"),
+            String::from("some-nonce"),
+        )
+        .insert(&db_conn.get_conn());
+
+        assert!(matches!(
+            AdminClient::new(
+                Arc::new(reqwest::Client::new()),
+                Arc::new(RateLimiter::new(&config)),
+                &config,
+                &pending
+            ),
+            Err(AdminApiError::MissingAccessToken)
+        ));
+        assert!(
+            shopify_connection::read_installed_by_shop(&db_conn.get_conn(), pending.shop).is_none()
+        );
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct ShopQuery {
+        shop: GraphqlShop,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct GraphqlShop {
+        name: String,
+        currency_code: String,
+    }
+
+    fn cost(requested: f64, available: f64, restore_rate: f64) -> serde_json::Value {
+        json!({
+            "cost": {
+                "requestedQueryCost": requested,
+                "actualQueryCost": requested,
+                "throttleStatus": {
+                    "maximumAvailable": 1000.0,
+                    "currentlyAvailable": available,
+                    "restoreRate": restore_rate
+                }
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn it_runs_a_typed_graphql_query() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let admin = admin_client(&config, &installed_connection(&db_conn.get_conn()));
+
+        let m = mock("POST", api_path(&config, "graphql").as_str())
+            .match_header("x-shopify-access-token", "f85632530bf277ec9ac6f649fc327f17")
+            .match_body(Matcher::PartialJsonString(String::from(
+                r#"{"variables": {"first": 1}}"#,
+            )))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "data": { "shop": { "name": "Some Shop", "currencyCode": "USD" } },
+                    "extensions": cost(1.0, 999.0, 50.0)
+                })
+                .to_string(),
+            )
+            .create();
+
+        let res: ShopQuery = admin
+            .graphql(
+                "query { shop { name currencyCode } }",
+                json!({ "first": 1 }),
+            )
+            .await
+            .unwrap();
+
+        m.assert();
+        assert_eq!(res.shop.name, "Some Shop");
+        assert_eq!(res.shop.currency_code, "USD");
+        let throttle_status = admin.throttle_status().unwrap();
+        assert!((throttle_status.currently_available - 999.0).abs() < f64::EPSILON);
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_returns_graphql_user_errors() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let admin = admin_client(&config, &installed_connection(&db_conn.get_conn()));
+
+        let _m = mock("POST", api_path(&config, "graphql").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "data": {
+                        "giftCardCreate": {
+                            "giftCard": null,
+                            "userErrors": [{ "field": ["input", "initialValue"], "message": "Initial value must be greater than 0" }]
+                        }
+                    },
+                    "extensions": cost(10.0, 990.0, 50.0)
+                })
+                .to_string(),
+            )
+            .create();
+
+        let res = admin
+            .graphql_mutation::<serde_json::Value>(
+                "mutation giftCardCreate($input: GiftCardCreateInput!) { giftCardCreate(input: $input) { giftCard { id } userErrors { field message } } }",
+                json!({ "input": { "initialValue": "0.00" } }),
+                "giftCardCreate",
+            )
+            .await;
+
+        match res {
+            Err(AdminApiError::UserErrors(user_errors)) => assert_eq!(
+                user_errors,
+                vec![UserError {
+                    field: Some(vec![String::from("input"), String::from("initialValue")]),
+                    message: String::from("Initial value must be greater than 0"),
+                }]
+            ),
+            other => panic!("Expected user errors, got {:?}", other),
+        }
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_reads_a_missing_mutation_payload_as_null() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let admin = admin_client(&config, &installed_connection(&db_conn.get_conn()));
+
+        let _m = mock("POST", api_path(&config, "graphql").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(json!({ "data": [] }).to_string())
+            .create();
+
+        let res = admin
+            .graphql_mutation::<serde_json::Value>(
+                "mutation giftCardCreate($input: GiftCardCreateInput!) { giftCardCreate(input: $input) { giftCard { id } } }",
+                json!({ "input": { "initialValue": "10.00" } }),
+                "giftCardCreate",
+            )
+            .await;
+
+        assert_eq!(res.unwrap(), serde_json::Value::Null);
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_waits_out_a_throttled_graphql_query() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let admin = admin_client(&config, &installed_connection(&db_conn.get_conn()));
+
+        // 20 points short at 100 a second, so about 200ms before the retry
+        let throttled = mock("POST", api_path(&config, "graphql").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "errors": [{ "message": "Throttled", "extensions": { "code": "THROTTLED" } }],
+                    "extensions": cost(20.0, 0.0, 100.0)
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create();
+        let ok = mock("POST", api_path(&config, "graphql").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "data": { "shop": { "name": "Some Shop", "currencyCode": "USD" } },
+                    "extensions": cost(20.0, 0.0, 100.0)
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create();
+
+        let started = Instant::now();
+        let res: ShopQuery = admin
+            .graphql("query { shop { name currencyCode } }", json!({}))
+            .await
+            .unwrap();
+
+        throttled.assert();
+        ok.assert();
+        assert_eq!(res.shop.name, "Some Shop");
+        assert!(started.elapsed() >= Duration::from_millis(150));
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_shares_the_graphql_cost_bucket_between_clients() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let rate_limiter = Arc::new(RateLimiter::new(&config));
+        let installed = installed_connection(&db_conn.get_conn());
+        let admin = || {
+            AdminClient::new(
+                Arc::new(reqwest::Client::new()),
+                rate_limiter.clone(),
+                &config,
+                &installed,
+            )
+            .unwrap()
+        };
+        let (first, second) = (admin(), admin());
+
+        // the first query leaves the bucket 20 points short at 100 a second
+        let m = mock("POST", api_path(&config, "graphql").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "data": { "shop": { "name": "Some Shop", "currencyCode": "USD" } },
+                    "extensions": cost(20.0, 0.0, 100.0)
+                })
+                .to_string(),
+            )
+            .expect(2)
+            .create();
+
+        let _: ShopQuery = first
+            .graphql("query { shop { name currencyCode } }", json!({}))
+            .await
+            .unwrap();
+        assert!(second.throttle_status().is_some());
+
+        // a client made for the next call still waits on the same bucket
+        let started = Instant::now();
+        let _: ShopQuery = second
+            .graphql("query { shop { name currencyCode } }", json!({}))
+            .await
+            .unwrap();
+
+        m.assert();
+        assert!(started.elapsed() >= Duration::from_millis(150));
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_leaves_the_rest_bucket_alone_for_graphql() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let rate_limiter = Arc::new(RateLimiter::new(&config));
+        let admin = AdminClient::new(
+            Arc::new(reqwest::Client::new()),
+            rate_limiter.clone(),
+            &config,
+            &installed_connection(&db_conn.get_conn()),
+        )
+        .unwrap();
+
+        let _m = mock("POST", api_path(&config, "graphql").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "data": { "shop": { "name": "Some Shop", "currencyCode": "USD" } },
+                    "extensions": cost(1.0, 999.0, 50.0)
+                })
+                .to_string(),
+            )
+            .create();
+
+        let _: ShopQuery = admin
+            .graphql("query { shop { name currencyCode } }", json!({}))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            rate_limiter.budget("some-shop.myshopify.com").remaining(),
+            config.shopify_api_bucket_size
+        );
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_falls_back_when_retry_after_is_not_a_real_wait() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let admin = admin_client(&config, &installed_connection(&db_conn.get_conn()));
+
+        let throttled = mock("GET", api_path(&config, "shop").as_str())
+            .with_status(429)
+            .with_header("retry-after", "inf")
+            .with_header("x-shopify-shop-api-call-limit", "inf/40")
+            .expect(1)
+            .create();
+        let ok = mock("GET", api_path(&config, "shop").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"shop": {
+                    "id": 690933842,
+                    "name": "Some Shop",
+                    "email": "owner@example.com",
+                    "domain": "shop.example.com",
+                    "myshopify_domain": "some-shop.myshopify.com",
+                    "currency": "USD",
+                    "plan_name": "basic"
+                }}"#,
+            )
+            .expect(1)
+            .create();
+
+        let shop = admin.shop().await.unwrap();
+
+        throttled.assert();
+        ok.assert();
+        assert_eq!(shop.id, 690933842);
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_counts_an_overflowing_call_limit_as_a_full_bucket() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let rate_limiter = Arc::new(RateLimiter::new(&config));
+        let admin = AdminClient::new(
+            Arc::new(reqwest::Client::new()),
+            rate_limiter.clone(),
+            &config,
+            &installed_connection(&db_conn.get_conn()),
+        )
+        .unwrap();
+
+        let m = mock("GET", api_path(&config, "gift_cards/1").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_header("x-shopify-shop-api-call-limit", "1e300/40")
+            .with_body(
+                r#"{"gift_card": {
+                    "id": 1,
+                    "balance": "25.00",
+                    "initial_value": "25.00",
+                    "currency": "USD",
+                    "code": null,
+                    "last_characters": "c293",
+                    "customer_id": null,
+                    "note": null,
+                    "expires_on": null,
+                    "disabled_at": null
+                }}"#,
+            )
+            .expect(2)
+            .create();
+
+        admin.gift_card(1).await.unwrap();
+        let budget = rate_limiter.budget("some-shop.myshopify.com");
+        assert!(budget.used <= 40.0);
+        assert_eq!(budget.capacity, 40.0);
+
+        // waits for one call to drain instead of panicking on how long that takes
+        admin.gift_card(1).await.unwrap();
+        m.assert();
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_retries_a_throttled_get_after_retry_after() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let rate_limiter = Arc::new(RateLimiter::new(&config));
+        let admin = AdminClient::new(
+            Arc::new(reqwest::Client::new()),
+            rate_limiter.clone(),
+            &config,
+            &installed_connection(&db_conn.get_conn()),
+        )
+        .unwrap();
+
+        let throttled = mock("GET", api_path(&config, "gift_cards/1").as_str())
+            .with_status(429)
+            .with_header("retry-after", "0.2")
+            .with_header("x-shopify-shop-api-call-limit", "40/40")
+            .expect(1)
+            .create();
+        let ok = mock("GET", api_path(&config, "gift_cards/1").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_header("x-shopify-shop-api-call-limit", "39/40")
+            .with_body(
+                r#"{"gift_card": {
+                    "id": 1,
+                    "balance": "25.00",
+                    "initial_value": "25.00",
+                    "currency": "USD",
+                    "code": null,
+                    "last_characters": "c293",
+                    "customer_id": null,
+                    "note": null,
+                    "expires_on": null,
+                    "disabled_at": null
+                }}"#,
+            )
+            .expect(1)
+            .create();
+
+        let started = Instant::now();
+        let gift_card = admin.gift_card(1).await.unwrap();
+
+        throttled.assert();
+        ok.assert();
+        assert_eq!(gift_card.id, 1);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(rate_limiter.budget("some-shop.myshopify.com").remaining() < 2.0);
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_does_not_retry_a_throttled_post() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let admin = admin_client(&config, &installed_connection(&db_conn.get_conn()));
+
+        let m = mock("POST", api_path(&config, "gift_cards").as_str())
+            .with_status(429)
+            .with_header("retry-after", "0.2")
+            .expect(1)
+            .create();
+
+        let res = admin
+            .create_gift_card(&NewGiftCard {
+                initial_value: String::from("25.00"),
+                ..NewGiftCard::default()
+            })
+            .await;
+
+        m.assert();
+        assert!(matches!(res, Err(AdminApiError::Throttled(_))));
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_waits_for_room_in_the_bucket() {
+        let mut config = admin_config();
+        // one call at a time, with room for another every 200ms
+        config.set_shopify_api_rate_limit(1.0, 5.0);
+        let db_conn = DbConn::new(&db_test_url());
+        let admin = admin_client(&config, &installed_connection(&db_conn.get_conn()));
+
+        let m = mock("GET", api_path(&config, "customers").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"customers": []}"#)
+            .expect(2)
+            .create();
+
+        let started = Instant::now();
+        admin.customers(&[]).await.unwrap();
+        admin.customers(&[]).await.unwrap();
+
+        m.assert();
+        assert!(started.elapsed() >= Duration::from_millis(150));
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    fn order_page(ids: &[i64]) -> String {
+        let orders: Vec<serde_json::Value> = ids
+            .iter()
+            .map(|id| json!({ "id": id, "name": format!("#{}", id), "currency": "USD", "total_price": "10.00" }))
+            .collect();
+
+        json!({ "orders": orders }).to_string()
+    }
+
+    fn next_page(config: &Config, page_info: &str) -> String {
+        format!(
+            r#"<{}{}?limit=2&page_info={}>; rel="next""#,
+            mockito::server_url(),
+            api_path(config, "orders"),
+            page_info
+        )
+    }
+
+    #[tokio::test]
+    async fn it_streams_orders_across_pages() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let admin = admin_client(&config, &installed_connection(&db_conn.get_conn()));
+
+        let first = mock("GET", api_path(&config, "orders").as_str())
+            .match_query(Matcher::Exact(String::from("limit=2&order=id+asc")))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_header("link", &next_page(&config, "second"))
+            .with_body(order_page(&[1, 2]))
+            .create();
+        let second = mock("GET", api_path(&config, "orders").as_str())
+            .match_query(Matcher::Exact(String::from("limit=2&page_info=second")))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(order_page(&[3]))
+            .create();
+
+        let orders: Vec<Order> = admin.list(&[("limit", "2")]).try_collect().await.unwrap();
+
+        first.assert();
+        second.assert();
+        assert_eq!(
+            orders.iter().map(|order| order.id).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_picks_up_after_an_expired_cursor() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let admin = admin_client(&config, &installed_connection(&db_conn.get_conn()));
+
+        let _first = mock("GET", api_path(&config, "orders").as_str())
+            .match_query(Matcher::Exact(String::from("limit=2&order=id+asc")))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_header("link", &next_page(&config, "stale"))
+            .with_body(order_page(&[1, 2]))
+            .create();
+        let _expired = mock("GET", api_path(&config, "orders").as_str())
+            .match_query(Matcher::Exact(String::from("limit=2&page_info=stale")))
+            .with_status(400)
+            .with_body(r#"{"errors": {"page_info": "Invalid value."}}"#)
+            .create();
+        let resumed = mock("GET", api_path(&config, "orders").as_str())
+            .match_query(Matcher::Exact(String::from(
+                "limit=2&order=id+asc&since_id=2",
+            )))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(order_page(&[3, 4]))
+            .create();
+
+        let orders: Vec<Order> = admin.list(&[("limit", "2")]).try_collect().await.unwrap();
+
+        resumed.assert();
+        assert_eq!(
+            orders.iter().map(|order| order.id).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4]
+        );
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_gives_up_on_an_expired_cursor_in_another_order() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let admin = admin_client(&config, &installed_connection(&db_conn.get_conn()));
+
+        let _first = mock("GET", api_path(&config, "orders").as_str())
+            .match_query(Matcher::Exact(String::from(
+                "limit=2&order=created_at+desc",
+            )))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_header("link", &next_page(&config, "stale"))
+            .with_body(order_page(&[4, 3]))
+            .create();
+        let _expired = mock("GET", api_path(&config, "orders").as_str())
+            .match_query(Matcher::Exact(String::from("limit=2&page_info=stale")))
+            .with_status(400)
+            .with_body(r#"{"errors": {"page_info": "Invalid value."}}"#)
+            .create();
+
+        // since_id=3 would skip 1 and 2 walking newest first
+        let orders: Vec<Result<Order, AdminApiError>> = admin
+            .list(&[("limit", "2"), ("order", "created_at desc")])
+            .collect()
+            .await;
+
+        assert_eq!(3, orders.len());
+        assert!(matches!(
+            orders[2],
+            Err(AdminApiError::Status(StatusCode::BAD_REQUEST, _))
+        ));
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct ProductNode {
+        id: String,
+    }
+
+    #[tokio::test]
+    async fn it_streams_a_graphql_connection() {
+        let config = admin_config();
+        let db_conn = DbConn::new(&db_test_url());
+        let admin = admin_client(&config, &installed_connection(&db_conn.get_conn()));
+
+        let first = mock("POST", api_path(&config, "graphql").as_str())
+            .match_body(Matcher::PartialJsonString(String::from(
+                r#"{"variables": {"first": 2, "after": null}}"#,
+            )))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "data": { "products": {
+                        "nodes": [{ "id": "gid://shopify/Product/1" }, { "id": "gid://shopify/Product/2" }],
+                        "pageInfo": { "hasNextPage": true, "endCursor": "eyJsYXN0X2lkIjoyfQ" }
+                    }},
+                    "extensions": cost(4.0, 996.0, 50.0)
+                })
+                .to_string(),
+            )
+            .create();
+        let second = mock("POST", api_path(&config, "graphql").as_str())
+            .match_body(Matcher::PartialJsonString(String::from(
+                r#"{"variables": {"first": 2, "after": "eyJsYXN0X2lkIjoyfQ"}}"#,
+            )))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "data": { "products": {
+                        "nodes": [{ "id": "gid://shopify/Product/3" }],
+                        "pageInfo": { "hasNextPage": false, "endCursor": "eyJsYXN0X2lkIjozfQ" }
+                    }},
+                    "extensions": cost(4.0, 996.0, 50.0)
+                })
+                .to_string(),
+            )
+            .create();
+
+        let products: Vec<ProductNode> = admin
+            .graphql_list(
+                "query products($first: Int!, $after: String) { products(first: $first, after: $after) { nodes { id } pageInfo { hasNextPage endCursor } } }",
+                json!({ "first": 2 }),
+                "products",
+            )
+            .try_collect()
+            .await
+            .unwrap();
+
+        first.assert();
+        second.assert();
+        assert_eq!(
+            products
+                .iter()
+                .map(|product| product.id.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "gid://shopify/Product/1",
+                "gid://shopify/Product/2",
+                "gid://shopify/Product/3"
+            ]
+        );
+
+        cleanup_table(&db_conn.get_conn());
+    }
+}
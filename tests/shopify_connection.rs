@@ -1,26 +1,35 @@
 mod shopify_integration_tests {
 
+    use chrono::Duration;
     use diesel::prelude::*;
     use dotenv::dotenv;
-    use mockito::mock;
+    use mockito::{mock, Matcher};
     use mocktopus::mocking::*;
     use rust_oauth2_study::{
-        config::Config,
+        config::{AccessMode, Config, ShopifyApp, DEFAULT_SHOPIFY_APP},
         db_conn::DbConn,
         db_test_url,
-        handlers::shopify_handler,
-        models::shopify_connection::{
-            create, read, read_by_shop, read_by_shop_and_nonce, NewShopifyConnection,
-            ShopifyConnection,
+        errors::handle_rejection,
+        models::{
+            shopify_connection::{
+                create, read, read_by_shop, read_by_shop_and_nonce, read_installed_by_shop,
+                NewShopifyConnection, ShopifyConnection,
+            },
+            shopify_online_token::{self, NewShopifyOnlineToken, OnlineTokenLookup},
+            webhook_subscription::{self, NewWebhookSubscription},
         },
-        routes::shopify_route,
-        schema::shopify_connections,
-        utils::gen_uuid,
+        platforms::shopify::{ShopifyPlatform, MAX_SCOPE_PROMPTS},
+        routes::platform_route,
+        schema::{shopify_connections, shopify_online_tokens, webhook_subscriptions},
+        services::{rate_limiter::RateLimiter, webhook_subscription_service},
+        utils::{gen_uuid, now_timestamp},
+        verification::hmac_message_from_query,
+        webhooks::WebhookRegistry,
         AccessTokenResponse,
     };
     use std::sync::Arc;
     use uuid::Uuid;
-    use warp::{self, Filter};
+    use warp::{self, filters::BoxedFilter, http::StatusCode, Filter, Reply};
 
     fn cleanup_table(conn: &PgConnection) {
         diesel::delete(shopify_connections::table)
@@ -28,15 +37,102 @@ mod shopify_integration_tests {
             .unwrap();
     }
 
+    // append the hmac shopify would have sent along with the query
+    fn sign_query(secret: &str, query: &str) -> String {
+        use hmac::{Hmac, Mac, NewMac};
+
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(hmac_message_from_query(query).as_bytes());
+        format!(
+            "{}&hmac={}",
+            query,
+            hex::encode(mac.finalize().into_bytes())
+        )
+    }
+
+    fn confirm_query(shop: &str, nonce: &str, timestamp: i64) -> String {
+        format!(
+            "code=0907a61c0c8d55e99db179b68161bc00\
+            &host=YmRyb2NrZXRzdG9yZS5teXNob3BpZnkuY29tL2FkbWlu\
+            &shop={}\
+            &state={}\
+            &timestamp={}",
+            shop, nonce, timestamp
+        )
+    }
+
+    fn mocking_config() -> Arc<Config> {
+        let mut config = Config::new(true);
+        config.set_shopify_api_uri(mockito::server_url());
+        config.set_shopify_secret_key(String::from("hush"));
+        config.set_shopify_scopes(vec![
+            String::from("read_orders"),
+            String::from("write_orders"),
+        ]);
+        // subscribing to webhooks after install is tested on its own
+        config.set_shopify_webhook_topics(vec![]);
+        Arc::new(config)
+    }
+
+    fn subscribing_config(topics: Vec<&str>) -> Arc<Config> {
+        let mut config = Config::new(true);
+        config.set_shopify_api_uri(mockito::server_url());
+        config.set_shopify_secret_key(String::from("hush"));
+        config.set_shopify_scopes(vec![String::from("write_orders")]);
+        config.set_shopify_webhook_topics(topics.into_iter().map(String::from).collect());
+        config.set_app_url(String::from("https://gifts.example.com"));
+        Arc::new(config)
+    }
+
+    fn mock_access_token() -> mockito::Mock {
+        mock("POST", "/admin/oauth/access_token")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"access_token": "f85632530bf277ec9ac6f649fc327f17","scope": "write_orders"}"#,
+            )
+            .create()
+    }
+
+    // the same routes main mounts for shopify
+    fn shopify_routes(config: Arc<Config>, db_conn: Arc<DbConn>) -> BoxedFilter<(Box<dyn Reply>,)> {
+        let client = Arc::new(reqwest::Client::new());
+        let rate_limiter = Arc::new(RateLimiter::new(&config));
+        let platform = ShopifyPlatform::new(config, db_conn.clone(), client, rate_limiter);
+
+        platform_route::routes_for(
+            Arc::new(platform),
+            db_conn,
+            Arc::new(WebhookRegistry::new()),
+        )
+    }
+
+    async fn confirm_status(config: Arc<Config>, db_conn: Arc<DbConn>, query: &str) -> StatusCode {
+        let shopify = shopify_routes(config, db_conn).recover(handle_rejection);
+
+        warp::test::request()
+            .method("GET")
+            .path(&format!("/shopify_confirm?{}", query))
+            .reply(&shopify)
+            .await
+            .status()
+    }
+
     #[tokio::test]
     async fn it_inserts_on_shopify_installation() {
-        let config = Arc::new(Config::new(false));
+        let mut config = Config::new(false);
+        config.set_shopify_secret_key(String::from("hush"));
+        config.set_shopify_scopes(vec![
+            String::from("read_orders"),
+            String::from("write_orders"),
+        ]);
+        config.set_shopify_access_mode(AccessMode::Offline);
+        config.set_app_url(String::from("https://gifts.example.com/"));
+        let config = Arc::new(config);
         let db_conn = Arc::new(DbConn::new(&db_test_url()));
         let client = Arc::new(reqwest::Client::new());
 
-        let shopify = shopify_route::shopify_install(config.clone(), db_conn.clone())
-            .and_then(shopify_handler::shopify_install)
-            .with(warp::log("shopify"));
+        let shopify = shopify_routes(config.clone(), db_conn.clone()).with(warp::log("shopify"));
 
         let shop_name = "bestbudz.myshopify.com";
         let nonce = "some-nonce";
@@ -48,15 +144,28 @@ mod shopify_integration_tests {
         let res = warp::test::request()
             .method("GET")
             .path(&format!(
-                "/shopify_install\
-                ?hmac=00a329c0648769a73afac7f9381e08fb43dbea72\
-                &shop={}\
-                &timestamp=1623154978",
-                shop_name
+                "/shopify_install?{}",
+                sign_query(
+                    "hush",
+                    &format!("shop={}&timestamp={}", shop_name, now_timestamp())
+                )
             ))
             .reply(&shopify)
             .await;
         assert_eq!(res.status(), 301);
+        assert_eq!(
+            res.headers()["location"],
+            format!(
+                "https://{}/admin/oauth/authorize\
+                ?client_id={}\
+                &scope=read_orders%2Cwrite_orders\
+                &redirect_uri=https%3A%2F%2Fgifts.example.com%2Fshopify_confirm\
+                &state={}",
+                shop_name,
+                config.default_shopify_app().api_key,
+                nonce
+            )
+        );
 
         let shopify_connection = read_by_shop_and_nonce(
             &db_conn.get_conn(),
@@ -75,20 +184,191 @@ mod shopify_integration_tests {
     }
 
     #[tokio::test]
-    async fn it_follows_shopify_confirm_flow() {
-        let test_db_url = db_test_url();
+    async fn it_requests_per_user_access_in_online_mode() {
+        let mut config = Config::new(false);
+        config.set_shopify_secret_key(String::from("hush"));
+        config.set_shopify_access_mode(AccessMode::Online);
+        let config = Arc::new(config);
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+
+        let shopify = shopify_routes(config.clone(), db_conn.clone());
+
+        let res = warp::test::request()
+            .method("GET")
+            .path(&format!(
+                "/shopify_install?{}",
+                sign_query(
+                    "hush",
+                    &format!("shop=bestbudz.myshopify.com&timestamp={}", now_timestamp())
+                )
+            ))
+            .reply(&shopify)
+            .await;
+        assert_eq!(res.status(), 301);
+
+        let location = res.headers()["location"].to_str().unwrap();
+        assert!(location.ends_with("&grant_options%5B%5D=per-user"));
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_shopify_installation_with_bad_hmac() {
+        let mut config = Config::new(false);
+        config.set_shopify_secret_key(String::from("hush"));
+        let config = Arc::new(config);
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+
+        let shopify = shopify_routes(config.clone(), db_conn.clone())
+            .recover(handle_rejection)
+            .with(warp::log("shopify"));
+
+        let shop_name = "forged.myshopify.com";
+
+        let res = warp::test::request()
+            .method("GET")
+            .path(&format!(
+                "/shopify_install\
+                ?hmac=00a329c0648769a73afac7f9381e08fb43dbea72\
+                &shop={}\
+                &timestamp=1623154978",
+                shop_name
+            ))
+            .reply(&shopify)
+            .await;
+        assert_eq!(res.status(), 401);
+
+        let shopify_connections = read_by_shop(&db_conn.get_conn(), shop_name.to_string());
+        assert_eq!(0, shopify_connections.len());
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_installs_another_app_under_its_own_path() {
+        let mut config = Config::new(false);
+        config.set_shopify_secret_key(String::from("hush"));
+        config.set_shopify_scopes(vec![String::from("write_orders")]);
+        config.set_app_url(String::from("https://gifts.example.com"));
+        config.add_shopify_app(ShopifyApp::new(
+            "staging",
+            String::from("staging-key"),
+            String::from("staging-hush"),
+        ));
+        let config = Arc::new(config);
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+
+        let shopify = shopify_routes(config.clone(), db_conn.clone()).recover(handle_rejection);
 
+        let shop_name = "staging-shop.myshopify.com";
+        let query = format!("shop={}&timestamp={}", shop_name, now_timestamp());
+
+        let install = |path: String| {
+            warp::test::request()
+                .method("GET")
+                .path(&path)
+                .reply(&shopify)
+        };
+
+        let res = install(format!(
+            "/shopify_install/staging?{}",
+            sign_query("staging-hush", &query)
+        ))
+        .await;
+        assert_eq!(res.status(), 301);
+
+        let location = res.headers()["location"].to_str().unwrap();
+        assert!(location.contains("client_id=staging-key&"));
+        assert!(location
+            .contains("redirect_uri=https%3A%2F%2Fgifts.example.com%2Fshopify_confirm%2Fstaging&"));
+
+        let shopify_connections = read_by_shop(&db_conn.get_conn(), shop_name.to_string());
+        assert_eq!(1, shopify_connections.len());
+        assert_eq!(shopify_connections[0].app, "staging");
+
+        // each app only takes what was signed with its own secret
+        let res = install(format!(
+            "/shopify_install/staging?{}",
+            sign_query("hush", &query)
+        ))
+        .await;
+        assert_eq!(res.status(), 401);
+
+        let res = install(format!(
+            "/shopify_install/nope?{}",
+            sign_query("hush", &query)
+        ))
+        .await;
+        assert_eq!(res.status(), 404);
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_confirms_another_app_with_its_own_keys() {
         let mut config = Config::new(true);
         config.set_shopify_api_uri(mockito::server_url());
         config.set_shopify_secret_key(String::from("hush"));
-        let arc_config = Arc::new(config);
+        config.set_shopify_scopes(vec![String::from("write_orders")]);
+        config.set_shopify_webhook_topics(vec![]);
+        config.add_shopify_app(ShopifyApp::new(
+            "staging",
+            String::from("staging-key"),
+            String::from("staging-hush"),
+        ));
+        let config = Arc::new(config);
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shopify = shopify_routes(config.clone(), db_conn.clone()).recover(handle_rejection);
+
+        let shop_name = "staging-shop.myshopify.com";
+        let nonce = "staging-nonce";
+
+        NewShopifyConnection::new(shop_name.to_string(), nonce.to_string())
+            .for_app("staging")
+            .insert(&db_conn.get_conn());
+
+        let m = mock("POST", "/admin/oauth/access_token")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("client_id".into(), "staging-key".into()),
+                Matcher::UrlEncoded("client_secret".into(), "staging-hush".into()),
+            ]))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"access_token": "f85632530bf277ec9ac6f649fc327f17","scope": "write_orders"}"#,
+            )
+            .create();
+
+        let res = warp::test::request()
+            .method("GET")
+            .path(&format!(
+                "/shopify_confirm/staging?{}",
+                sign_query(
+                    "staging-hush",
+                    &confirm_query(shop_name, nonce, now_timestamp())
+                )
+            ))
+            .reply(&shopify)
+            .await;
+        assert_eq!(res.status(), 301);
+        m.assert();
+
+        let shopify_connections = read_by_shop(&db_conn.get_conn(), shop_name.to_string());
+        assert_eq!(shopify_connections[0].app, "staging");
+        assert!(shopify_connections[0].access_token.is_some());
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_follows_shopify_confirm_flow() {
+        let test_db_url = db_test_url();
+
+        let arc_config = mocking_config();
 
         let db_conn = Arc::new(DbConn::new(&test_db_url));
-        let client = Arc::new(reqwest::Client::new());
         let shopify =
-            shopify_route::shopify_confirm(arc_config.clone(), db_conn.clone(), client.clone())
-                .and_then(shopify_handler::shopify_confirm)
-                .with(warp::log("shopify"));
+            shopify_routes(arc_config.clone(), db_conn.clone()).with(warp::log("shopify"));
 
         let shop_name = "some-shop.myshopify.com";
         let nonce = "0.6784241404160823";
@@ -110,14 +390,8 @@ mod shopify_integration_tests {
         let res = warp::test::request()
             .method("GET")
             .path(&format!(
-                "/shopify_confirm\
-                    ?code=0907a61c0c8d55e99db179b68161bc00\
-                    &hmac=700e2dadb827fcc8609e9d5ce208b2e9cdaab9df07390d2cbca10d7c328fc4bf\
-                    &host=YmRyb2NrZXRzdG9yZS5teXNob3BpZnkuY29tL2FkbWlu\
-                    &shop={}\
-                    &state={}\
-                    &timestamp=1337178173",
-                shop_name, nonce
+                "/shopify_confirm?{}",
+                sign_query("hush", &confirm_query(shop_name, nonce, now_timestamp()))
             ))
             .reply(&shopify)
             .await;
@@ -136,12 +410,485 @@ mod shopify_integration_tests {
         assert_eq!(
             my_shopify_connection
                 .unwrap()
-                .access_token
-                .as_ref()
+                .decrypt_access_token(&arc_config.token_cipher())
+                .unwrap()
                 .unwrap(),
             access_token
         );
+        assert_eq!(
+            my_shopify_connection.unwrap().scope.as_ref().unwrap(),
+            "write_orders,read_customers"
+        );
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_saves_online_access_tokens_per_user() {
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shop_name = "some-shop.myshopify.com";
+        let nonce = "some-nonce";
+
+        NewShopifyConnection::new(shop_name.to_string(), nonce.to_string())
+            .insert(&db_conn.get_conn());
+
+        let _m = mock("POST", "/admin/oauth/access_token")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{
+                    "access_token": "f85632530bf277ec9ac6f649fc327f17",
+                    "scope": "write_orders",
+                    "expires_in": 86399,
+                    "associated_user_scope": "write_orders",
+                    "associated_user": {
+                        "id": 902541635,
+                        "first_name": "John",
+                        "last_name": "Smith",
+                        "email": "john@example.com",
+                        "email_verified": true,
+                        "account_owner": true,
+                        "locale": "en",
+                        "collaborator": false
+                    }
+                }"#,
+            )
+            .create();
+
+        let query = sign_query("hush", &confirm_query(shop_name, nonce, now_timestamp()));
+        let status = confirm_status(mocking_config(), db_conn.clone(), &query).await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+
+        match shopify_online_token::lookup_valid(
+            &db_conn.get_conn(),
+            String::from(DEFAULT_SHOPIFY_APP),
+            shop_name.to_string(),
+            902541635,
+        ) {
+            OnlineTokenLookup::Valid(token) => {
+                assert_eq!(
+                    token
+                        .decrypt_access_token(&Config::new(true).token_cipher())
+                        .unwrap(),
+                    "f85632530bf277ec9ac6f649fc327f17"
+                );
+                assert_eq!(token.email.unwrap(), "john@example.com");
+                assert!(token.account_owner);
+            }
+            other => panic!("Expected a valid online token, got {:?}", other),
+        }
+
+        // the shop counts as installed, but the expiring token isn't the shop's
+        let shopify_connection =
+            read_installed_by_shop(&db_conn.get_conn(), shop_name.to_string()).unwrap();
+        assert!(shopify_connection.access_token.is_none());
+        assert_eq!(shopify_connection.scope.unwrap(), "write_orders");
+
+        diesel::delete(shopify_online_tokens::table)
+            .execute(&db_conn.get_conn())
+            .unwrap();
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_asks_again_when_shopify_grants_too_few_scopes() {
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let config = mocking_config();
+        let shopify = shopify_routes(config, db_conn.clone()).recover(handle_rejection);
+
+        let shop_name = "some-shop.myshopify.com";
+        let nonce = "some-nonce";
+
+        NewShopifyConnection::new(shop_name.to_string(), nonce.to_string())
+            .insert(&db_conn.get_conn());
+
+        let _m = mock("POST", "/admin/oauth/access_token")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body("{\"access_token\": \"f85632530bf277ec9ac6f649fc327f17\",\"scope\": \"read_orders\"}")
+            .create();
+
+        let res = warp::test::request()
+            .method("GET")
+            .path(&format!(
+                "/shopify_confirm?{}",
+                sign_query("hush", &confirm_query(shop_name, nonce, now_timestamp()))
+            ))
+            .reply(&shopify)
+            .await;
+
+        assert_eq!(res.status(), 301);
+        let location = res.headers()["location"].to_str().unwrap();
+        assert!(location.starts_with(&format!("https://{}/admin/oauth/authorize?", shop_name)));
+
+        // the token shopify gave us is kept, along with a fresh install request
+        let shopify_connections = read_by_shop(&db_conn.get_conn(), shop_name.to_string());
+        assert_eq!(2, shopify_connections.len());
+        let granted = shopify_connections
+            .iter()
+            .find(|x| x.nonce == nonce)
+            .unwrap();
+        assert!(granted.access_token.is_some());
+        assert_eq!(granted.scope.as_deref(), Some("read_orders"));
+        let pending = shopify_connections
+            .iter()
+            .find(|x| x.nonce != nonce)
+            .unwrap();
+        assert!(pending.access_token.is_none());
+        assert_eq!(pending.scope_prompts, 1);
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_stops_asking_when_shopify_keeps_granting_too_few_scopes() {
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shop_name = "some-shop.myshopify.com";
+        let nonce = "some-nonce";
+
+        NewShopifyConnection::new(shop_name.to_string(), nonce.to_string())
+            .prompted(MAX_SCOPE_PROMPTS)
+            .insert(&db_conn.get_conn());
+
+        let _m = mock("POST", "/admin/oauth/access_token")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body("{\"access_token\": \"f85632530bf277ec9ac6f649fc327f17\",\"scope\": \"read_orders\"}")
+            .create();
+
+        let query = sign_query("hush", &confirm_query(shop_name, nonce, now_timestamp()));
+
+        let status = confirm_status(mocking_config(), db_conn.clone(), &query).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // no new install request to loop through
+        let shopify_connections = read_by_shop(&db_conn.get_conn(), shop_name.to_string());
+        assert_eq!(1, shopify_connections.len());
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_shopify_confirm_with_invalid_shop() {
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let query = sign_query(
+            "hush",
+            &confirm_query("evil.example.com", "some-nonce", now_timestamp()),
+        );
+
+        let status = confirm_status(mocking_config(), db_conn, &query).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn it_rejects_shopify_confirm_with_bad_hmac() {
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let query = sign_query(
+            "not-hush",
+            &confirm_query("some-shop.myshopify.com", "some-nonce", now_timestamp()),
+        );
+
+        let status = confirm_status(mocking_config(), db_conn, &query).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn it_rejects_shopify_confirm_with_unknown_nonce() {
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let query = sign_query(
+            "hush",
+            &confirm_query("some-shop.myshopify.com", "unknown-nonce", now_timestamp()),
+        );
+
+        let status = confirm_status(mocking_config(), db_conn, &query).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn it_fails_shopify_confirm_when_access_token_request_fails() {
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shop_name = "some-shop.myshopify.com";
+        let nonce = "some-nonce";
+
+        NewShopifyConnection::new(shop_name.to_string(), nonce.to_string())
+            .insert(&db_conn.get_conn());
+
+        let _m = mock("POST", "/admin/oauth/access_token")
+            .with_status(500)
+            .create();
+
+        let query = sign_query("hush", &confirm_query(shop_name, nonce, now_timestamp()));
+
+        let status = confirm_status(mocking_config(), db_conn.clone(), &query).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+        let shopify_connections = read_by_shop(&db_conn.get_conn(), shop_name.to_string());
+        assert!(shopify_connections[0].access_token.is_none());
+        // not the shop's fault, it can try again
+        assert!(shopify_connections[0].nonce_consumed_at.is_none());
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_shopify_confirm_with_stale_timestamp() {
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shop_name = "some-shop.myshopify.com";
+        let nonce = "some-nonce";
+
+        NewShopifyConnection::new(shop_name.to_string(), nonce.to_string())
+            .insert(&db_conn.get_conn());
+
+        // a perfectly signed callback captured an hour ago
+        let query = sign_query(
+            "hush",
+            &confirm_query(shop_name, nonce, now_timestamp() - 60 * 60),
+        );
+
+        let status = confirm_status(mocking_config(), db_conn.clone(), &query).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_a_replayed_shopify_confirm() {
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shop_name = "some-shop.myshopify.com";
+        let nonce = "some-nonce";
+
+        NewShopifyConnection::new(shop_name.to_string(), nonce.to_string())
+            .insert(&db_conn.get_conn());
 
+        let m = mock("POST", "/admin/oauth/access_token")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body("{\"access_token\": \"f85632530bf277ec9ac6f649fc327f17\",\"scope\": \"write_orders\"}")
+            .expect(1)
+            .create();
+
+        let query = sign_query("hush", &confirm_query(shop_name, nonce, now_timestamp()));
+
+        let status = confirm_status(mocking_config(), db_conn.clone(), &query).await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+
+        let status = confirm_status(mocking_config(), db_conn.clone(), &query).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // the second callback never reached shopify
+        m.assert();
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_shopify_confirm_with_expired_nonce() {
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shop_name = "some-shop.myshopify.com";
+        let nonce = "some-nonce";
+
+        NewShopifyConnection::new(shop_name.to_string(), nonce.to_string())
+            .expires_in(Duration::seconds(-1))
+            .insert(&db_conn.get_conn());
+
+        let query = sign_query("hush", &confirm_query(shop_name, nonce, now_timestamp()));
+
+        let status = confirm_status(mocking_config(), db_conn.clone(), &query).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_subscribes_to_webhooks_after_confirming_without_doubling_up() {
+        let config = subscribing_config(vec!["app/uninstalled", "orders/create"]);
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shop_name = "some-shop.myshopify.com";
+        let nonce = "some-nonce";
+        let webhooks_path = format!("/admin/api/{}/webhooks", config.shopify_api_version);
+
+        NewShopifyConnection::new(shop_name.to_string(), nonce.to_string())
+            .insert(&db_conn.get_conn());
+
+        let _m = mock_access_token();
+
+        // left over from an earlier install
+        let _list = mock("GET", format!("{}.json", webhooks_path).as_str())
+            .match_header("x-shopify-access-token", "f85632530bf277ec9ac6f649fc327f17")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"webhooks": [
+                    {"id": 4759306, "address": "https://gifts.example.com/webhooks/shopify", "topic": "app/uninstalled", "format": "json"},
+                    {"id": 4759307, "address": "https://gifts.example.com/webhooks/shopify", "topic": "orders/paid", "format": "json"}
+                ]}"#,
+            )
+            .create();
+        let create = mock("POST", format!("{}.json", webhooks_path).as_str())
+            .match_body(Matcher::PartialJsonString(
+                r#"{"webhook": {"topic": "orders/create", "address": "https://gifts.example.com/webhooks/shopify"}}"#
+                    .to_string(),
+            ))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"webhook": {"id": 4759308, "address": "https://gifts.example.com/webhooks/shopify", "topic": "orders/create", "format": "json"}}"#,
+            )
+            .expect(1)
+            .create();
+        let delete = mock("DELETE", format!("{}/4759307.json", webhooks_path).as_str())
+            .with_status(200)
+            .with_body("{}")
+            .expect(1)
+            .create();
+
+        let query = sign_query("hush", &confirm_query(shop_name, nonce, now_timestamp()));
+        let status = confirm_status(config, db_conn.clone(), &query).await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        create.assert();
+        delete.assert();
+
+        let subscriptions =
+            webhook_subscription::read_by_shop(&db_conn.get_conn(), shop_name.to_string());
+        assert_eq!(2, subscriptions.len());
+        assert_eq!(subscriptions[0].topic, "app/uninstalled");
+        assert_eq!(subscriptions[0].shopify_webhook_id, Some(4759306));
+        assert_eq!(subscriptions[1].topic, "orders/create");
+        assert_eq!(subscriptions[1].shopify_webhook_id, Some(4759308));
+        assert!(subscriptions.iter().all(|s| s.failed_at.is_none()));
+
+        diesel::delete(webhook_subscriptions::table)
+            .execute(&db_conn.get_conn())
+            .unwrap();
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_records_webhook_subscriptions_that_fail_without_failing_the_install() {
+        let config = subscribing_config(vec!["orders/paid"]);
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shop_name = "some-shop.myshopify.com";
+        let nonce = "some-nonce";
+        let webhooks_path = format!("/admin/api/{}/webhooks.json", config.shopify_api_version);
+
+        NewShopifyConnection::new(shop_name.to_string(), nonce.to_string())
+            .insert(&db_conn.get_conn());
+
+        let _m = mock_access_token();
+        let _list = mock("GET", webhooks_path.as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"webhooks": []}"#)
+            .create();
+        let _create = mock("POST", webhooks_path.as_str())
+            .with_status(422)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"errors": {"address": ["for this topic has already been taken"]}}"#)
+            .create();
+
+        let query = sign_query("hush", &confirm_query(shop_name, nonce, now_timestamp()));
+        let status = confirm_status(config, db_conn.clone(), &query).await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+
+        let subscriptions =
+            webhook_subscription::read_by_shop(&db_conn.get_conn(), shop_name.to_string());
+        assert_eq!(1, subscriptions.len());
+        assert!(subscriptions[0].failed_at.is_some());
+        assert!(subscriptions[0].last_error.is_some());
+        assert_eq!(subscriptions[0].attempts, 1);
+        assert_eq!(
+            webhook_subscription::read_failed_shops(&db_conn.get_conn()),
+            vec![(String::from(DEFAULT_SHOPIFY_APP), shop_name.to_string())]
+        );
+
+        diesel::delete(webhook_subscriptions::table)
+            .execute(&db_conn.get_conn())
+            .unwrap();
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_retries_failed_webhook_subscriptions_with_an_online_token() {
+        let config = subscribing_config(vec!["orders/paid"]);
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shop_name = "online-shop.myshopify.com";
+        let address = config.webhook_address(config.default_shopify_app());
+        let webhooks_path = format!("/admin/api/{}/webhooks.json", config.shopify_api_version);
+
+        // online installs keep no token on the connection itself
+        NewShopifyConnection::installed_online(
+            shop_name.to_string(),
+            gen_uuid(),
+            String::from("write_orders"),
+        )
+        .insert(&db_conn.get_conn());
+        NewShopifyOnlineToken::new(
+            &config.token_cipher(),
+            shop_name.to_string(),
+            902541635,
+            String::from("online-token-4c1f"),
+            String::from("write_orders"),
+            String::from("write_orders"),
+            3600,
+        )
+        .upsert(&db_conn.get_conn())
+        .unwrap();
+        for shop in &[shop_name, "gone-shop.myshopify.com"] {
+            NewWebhookSubscription::failed(
+                String::from(DEFAULT_SHOPIFY_APP),
+                shop.to_string(),
+                String::from("orders/paid"),
+                address.clone(),
+                String::from("could not reach shopify"),
+            )
+            .upsert(&db_conn.get_conn())
+            .unwrap();
+        }
+
+        let _list = mock("GET", webhooks_path.as_str())
+            .match_header("x-shopify-access-token", "online-token-4c1f")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"webhooks": []}"#)
+            .create();
+        let create = mock("POST", webhooks_path.as_str())
+            .match_header("x-shopify-access-token", "online-token-4c1f")
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"webhook": {"id": 4759309, "address": "https://gifts.example.com/webhooks/shopify", "topic": "orders/paid", "format": "json"}}"#,
+            )
+            .expect(1)
+            .create();
+
+        let failures = webhook_subscription_service::retry_failed(
+            Arc::new(reqwest::Client::new()),
+            Arc::new(RateLimiter::new(&config)),
+            &config,
+            &db_conn,
+        )
+        .await;
+        assert_eq!(failures, 0);
+        create.assert();
+
+        let subscriptions =
+            webhook_subscription::read_by_shop(&db_conn.get_conn(), shop_name.to_string());
+        assert_eq!(1, subscriptions.len());
+        assert_eq!(subscriptions[0].shopify_webhook_id, Some(4759309));
+        assert!(subscriptions[0].failed_at.is_none());
+        // only the shop that's no longer installed loses its subscriptions
+        assert!(webhook_subscription::read_by_shop(
+            &db_conn.get_conn(),
+            String::from("gone-shop.myshopify.com")
+        )
+        .is_empty());
+
+        diesel::delete(webhook_subscriptions::table)
+            .execute(&db_conn.get_conn())
+            .unwrap();
+        diesel::delete(shopify_online_tokens::table)
+            .execute(&db_conn.get_conn())
+            .unwrap();
         cleanup_table(&db_conn.get_conn());
     }
 }
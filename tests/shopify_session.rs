@@ -0,0 +1,359 @@
+mod shopify_session_tests {
+
+    use diesel::prelude::*;
+    use mockito::{mock, Matcher};
+    use rust_oauth2_study::{
+        config::{AccessMode, Config, ShopifyApp, DEFAULT_SHOPIFY_APP},
+        db_conn::DbConn,
+        db_test_url,
+        errors::handle_rejection,
+        models::{
+            shopify_connection::{
+                read_installed_by_app_and_shop, read_installed_by_shop, update_access_token,
+                NewShopifyConnection,
+            },
+            shopify_online_token::{self, NewShopifyOnlineToken, OnlineTokenLookup},
+        },
+        routes::session_route,
+        schema::{shopify_connections, shopify_online_tokens},
+        services::rate_limiter::RateLimiter,
+        session_token::{self, Claims, ShopSession},
+        utils::now_timestamp,
+    };
+    use std::sync::Arc;
+    use warp::{self, http::StatusCode, Filter};
+
+    fn cleanup_table(conn: &PgConnection) {
+        diesel::delete(shopify_connections::table)
+            .execute(conn)
+            .unwrap();
+    }
+
+    fn session_config() -> Arc<Config> {
+        let mut config = Config::new(true);
+        config.set_shopify_secret_key(String::from("hush"));
+        config.set_shopify_api_uri(mockito::server_url());
+        config.set_shopify_webhook_topics(vec![]);
+        Arc::new(config)
+    }
+
+    fn mock_claims(config: &Config, shop: &str) -> Claims {
+        let now = now_timestamp();
+
+        Claims {
+            iss: format!("https://{}/admin", shop),
+            dest: format!("https://{}", shop),
+            aud: config.default_shopify_app().api_key.clone(),
+            sub: Some(String::from("902541635")),
+            exp: now + 60,
+            nbf: now,
+            iat: now,
+            jti: String::from("f8912129-1af6-4cad-9ca3-76b0f7621087"),
+            sid: None,
+        }
+    }
+
+    fn install(db_conn: &DbConn, shop: &str) {
+        let conn = db_conn.get_conn();
+        let shopify_connection =
+            NewShopifyConnection::new(shop.to_string(), String::from("some-nonce")).insert(&conn);
+        update_access_token(
+            &conn,
+            &Config::new(true).token_cipher(),
+            &shopify_connection,
+            String::from("f85632530bf277ec9ac6f649fc327f17"),
+            String::from("write_orders"),
+        )
+        .unwrap();
+    }
+
+    async fn request_with(
+        config: Arc<Config>,
+        db_conn: Arc<DbConn>,
+        authorization: Option<String>,
+    ) -> (StatusCode, String) {
+        let client = Arc::new(reqwest::Client::new());
+        let rate_limiter = Arc::new(RateLimiter::new(&config));
+        let api = session_route::with_shop_session(config, db_conn, client, rate_limiter)
+            .map(|session: ShopSession| session.shop)
+            .recover(handle_rejection);
+
+        let mut request = warp::test::request().method("GET").path("/");
+        if let Some(authorization) = authorization {
+            request = request.header("authorization", authorization);
+        }
+
+        let res = request.reply(&api).await;
+        (
+            res.status(),
+            String::from_utf8(res.body().to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn it_authenticates_a_session_token_for_an_installed_shop() {
+        let config = session_config();
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shop_name = "some-shop.myshopify.com";
+        install(&db_conn, shop_name);
+
+        let token = session_token::encode(&mock_claims(&config, shop_name), "hush");
+
+        let (status, body) =
+            request_with(config, db_conn.clone(), Some(format!("Bearer {}", token))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, shop_name);
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_a_missing_session_token() {
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+
+        let (status, _) = request_with(session_config(), db_conn, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn it_rejects_a_session_token_for_a_shop_that_is_not_installed() {
+        let config = session_config();
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shop_name = "some-shop.myshopify.com";
+
+        // started installing, but never confirmed
+        NewShopifyConnection::new(shop_name.to_string(), String::from("some-nonce"))
+            .insert(&db_conn.get_conn());
+
+        // and shopify won't trade the session token either
+        let _m = mock("POST", "/admin/oauth/access_token")
+            .with_status(400)
+            .with_header("content-type", "application/json")
+            .with_body("{\"error\": \"invalid_subject_token\"}")
+            .create();
+
+        let token = session_token::encode(&mock_claims(&config, shop_name), "hush");
+
+        let (status, _) =
+            request_with(config, db_conn.clone(), Some(format!("Bearer {}", token))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_exchanges_a_session_token_when_the_shop_has_no_access_token() {
+        let config = session_config();
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shop_name = "some-shop.myshopify.com";
+
+        let token = session_token::encode(&mock_claims(&config, shop_name), "hush");
+
+        let m = mock("POST", "/admin/oauth/access_token")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::UrlEncoded(
+                    String::from("grant_type"),
+                    String::from("urn:ietf:params:oauth:grant-type:token-exchange"),
+                ),
+                Matcher::UrlEncoded(String::from("subject_token"), token.clone()),
+            ]))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body("{\"access_token\": \"f85632530bf277ec9ac6f649fc327f17\",\"scope\": \"write_orders\"}")
+            .expect(1)
+            .create();
+
+        let (status, body) =
+            request_with(config, db_conn.clone(), Some(format!("Bearer {}", token))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, shop_name);
+        m.assert();
+
+        let shopify_connection =
+            read_installed_by_shop(&db_conn.get_conn(), shop_name.to_string()).unwrap();
+        assert_eq!(
+            shopify_connection
+                .decrypt_access_token(&Config::new(true).token_cipher())
+                .unwrap()
+                .as_deref(),
+            Some("f85632530bf277ec9ac6f649fc327f17")
+        );
+        assert_eq!(shopify_connection.scope.as_deref(), Some("write_orders"));
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_a_session_token_signed_with_another_secret() {
+        let config = session_config();
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shop_name = "some-shop.myshopify.com";
+        install(&db_conn, shop_name);
+
+        let token = session_token::encode(&mock_claims(&config, shop_name), "not-hush");
+
+        let (status, _) =
+            request_with(config, db_conn.clone(), Some(format!("Bearer {}", token))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_checks_a_session_token_against_the_app_it_was_issued_for() {
+        let mut config = Config::new(true);
+        config.set_shopify_secret_key(String::from("hush"));
+        config.set_shopify_api_uri(mockito::server_url());
+        config.set_shopify_webhook_topics(vec![]);
+        config.add_shopify_app(ShopifyApp::new(
+            "staging",
+            String::from("staging-key"),
+            String::from("staging-hush"),
+        ));
+        let config = Arc::new(config);
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shop_name = "some-shop.myshopify.com";
+        // only the default app is installed so far
+        install(&db_conn, shop_name);
+
+        let mut claims = mock_claims(&config, shop_name);
+        claims.aud = String::from("staging-key");
+
+        let forged = session_token::encode(&claims, "hush");
+        let (status, _) = request_with(
+            config.clone(),
+            db_conn.clone(),
+            Some(format!("Bearer {}", forged)),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let m = mock("POST", "/admin/oauth/access_token")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::UrlEncoded(String::from("client_id"), String::from("staging-key")),
+                Matcher::UrlEncoded(String::from("client_secret"), String::from("staging-hush")),
+            ]))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body("{\"access_token\": \"f85632530bf277ec9ac6f649fc327f17\",\"scope\": \"write_orders\"}")
+            .expect(1)
+            .create();
+
+        let token = session_token::encode(&claims, "staging-hush");
+        let (status, body) =
+            request_with(config, db_conn.clone(), Some(format!("Bearer {}", token))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, shop_name);
+        m.assert();
+
+        assert!(read_installed_by_app_and_shop(
+            &db_conn.get_conn(),
+            String::from("staging"),
+            shop_name.to_string()
+        )
+        .is_some());
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_a_session_token_exchange_missing_scopes() {
+        let config = session_config();
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shop_name = "some-shop.myshopify.com";
+
+        let _m = mock("POST", "/admin/oauth/access_token")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body("{\"access_token\": \"f85632530bf277ec9ac6f649fc327f17\",\"scope\": \"read_orders\"}")
+            .create();
+
+        let token = session_token::encode(&mock_claims(&config, shop_name), "hush");
+
+        let (status, _) =
+            request_with(config, db_conn.clone(), Some(format!("Bearer {}", token))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(read_installed_by_shop(&db_conn.get_conn(), shop_name.to_string()).is_none());
+
+        cleanup_table(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_exchanges_a_session_token_again_when_the_online_token_expired() {
+        let mut config = Config::new(true);
+        config.set_shopify_secret_key(String::from("hush"));
+        config.set_shopify_api_uri(mockito::server_url());
+        config.set_shopify_webhook_topics(vec![]);
+        config.set_shopify_access_mode(AccessMode::Online);
+        let config = Arc::new(config);
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let shop_name = "some-shop.myshopify.com";
+
+        let conn = db_conn.get_conn();
+        NewShopifyConnection::installed_online(
+            shop_name.to_string(),
+            String::from("some-nonce"),
+            String::from("write_orders"),
+        )
+        .upsert_installed(&conn)
+        .unwrap();
+        NewShopifyOnlineToken::new(
+            &config.token_cipher(),
+            shop_name.to_string(),
+            902541635,
+            String::from("stale"),
+            String::from("write_orders"),
+            String::from("write_orders"),
+            -1,
+        )
+        .upsert(&conn)
+        .unwrap();
+
+        let m = mock("POST", "/admin/oauth/access_token")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{
+                    "access_token": "f85632530bf277ec9ac6f649fc327f17",
+                    "scope": "write_orders",
+                    "expires_in": 86399,
+                    "associated_user_scope": "write_orders",
+                    "associated_user": {"id": 902541635, "account_owner": true}
+                }"#,
+            )
+            .expect(1)
+            .create();
+
+        let token = session_token::encode(&mock_claims(&config, shop_name), "hush");
+
+        let (status, _) =
+            request_with(config, db_conn.clone(), Some(format!("Bearer {}", token))).await;
+        assert_eq!(status, StatusCode::OK);
+        m.assert();
+
+        match shopify_online_token::lookup_valid(
+            &conn,
+            String::from(DEFAULT_SHOPIFY_APP),
+            shop_name.to_string(),
+            902541635,
+        ) {
+            OnlineTokenLookup::Valid(token) => {
+                assert_eq!(
+                    token
+                        .decrypt_access_token(&Config::new(true).token_cipher())
+                        .unwrap(),
+                    "f85632530bf277ec9ac6f649fc327f17"
+                )
+            }
+            other => panic!("Expected a valid online token, got {:?}", other),
+        }
+        // the staff member's token never lands on the shop's connection
+        let shopify_connection = read_installed_by_shop(&conn, shop_name.to_string()).unwrap();
+        assert!(shopify_connection.access_token.is_none());
+
+        diesel::delete(shopify_online_tokens::table)
+            .execute(&conn)
+            .unwrap();
+        cleanup_table(&conn);
+    }
+}
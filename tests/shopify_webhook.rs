@@ -0,0 +1,495 @@
+mod shopify_webhook_tests {
+
+    use diesel::prelude::*;
+    use rust_oauth2_study::{
+        config::{Config, ShopifyApp, DEFAULT_SHOPIFY_APP},
+        db_conn::DbConn,
+        db_test_url,
+        errors::{handle_rejection, WebhookError},
+        handlers::{compliance_handler, platform_handler, webhook_handler},
+        models::{
+            compliance_request,
+            job::{self, Job},
+            shopify_connection::{self, update_access_token, NewShopifyConnection},
+            shopify_online_token::{self, NewShopifyOnlineToken},
+        },
+        platforms::shopify::ShopifyPlatform,
+        routes::platform_route,
+        schema::{
+            compliance_requests, jobs, processed_webhooks, shopify_connections,
+            shopify_online_tokens,
+        },
+        services::rate_limiter::RateLimiter,
+        webhooks::{self, Webhook, WebhookJobs, WebhookRegistry},
+        workers::job_runner,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use uuid::Uuid;
+    use warp::{self, http::StatusCode, Filter};
+
+    fn cleanup_tables(conn: &PgConnection) {
+        diesel::delete(jobs::table).execute(conn).unwrap();
+        diesel::delete(processed_webhooks::table)
+            .execute(conn)
+            .unwrap();
+        diesel::delete(compliance_requests::table)
+            .execute(conn)
+            .unwrap();
+        diesel::delete(shopify_online_tokens::table)
+            .execute(conn)
+            .unwrap();
+        diesel::delete(shopify_connections::table)
+            .execute(conn)
+            .unwrap();
+    }
+
+    static DISPATCHED: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_orders_create(webhook: &Webhook, _db_conn: &DbConn) -> Result<(), WebhookError> {
+        assert_eq!(webhook.shop_domain, "some-shop.myshopify.com");
+        DISPATCHED.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn fail_orders_paid(_webhook: &Webhook, _db_conn: &DbConn) -> Result<(), WebhookError> {
+        Err(WebhookError::Database(
+            diesel::result::Error::RollbackTransaction,
+        ))
+    }
+
+    // the base64 hmac shopify would have sent along with the body
+    fn sign_body(secret: &str, body: &[u8]) -> String {
+        use hmac::{Hmac, Mac, NewMac};
+
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        base64::encode(mac.finalize().into_bytes())
+    }
+
+    fn webhook_config() -> Arc<Config> {
+        let mut config = Config::new(true);
+        config.set_shopify_secret_key(String::from("hush"));
+        config.set_shopify_previous_secret_keys(vec![String::from("old-hush")]);
+        config.add_shopify_app(ShopifyApp::new(
+            "staging",
+            String::from("staging-key"),
+            String::from("staging-hush"),
+        ));
+        Arc::new(config)
+    }
+
+    // every delivery gets its own webhook id, like shopify does
+    async fn deliver(topic: &str, body: &[u8], hmac: &str) -> StatusCode {
+        deliver_as(&Uuid::new_v4().to_string(), topic, body, hmac).await
+    }
+
+    fn webhook_registry() -> WebhookRegistry {
+        WebhookRegistry::new()
+            .register("orders/create", count_orders_create)
+            .register("orders/paid", fail_orders_paid)
+            .register(webhooks::APP_UNINSTALLED, webhook_handler::app_uninstalled)
+            .register(
+                webhooks::CUSTOMERS_DATA_REQUEST,
+                compliance_handler::customers_data_request,
+            )
+            .register(
+                webhooks::CUSTOMERS_REDACT,
+                compliance_handler::customers_redact,
+            )
+            .register(webhooks::SHOP_REDACT, compliance_handler::shop_redact)
+    }
+
+    // what the job runners in main would get around to
+    fn run_jobs(config: &Config) -> usize {
+        let db_conn = DbConn::new(&db_test_url());
+        let webhook_jobs = WebhookJobs::new().register(webhooks::WEBHOOK_JOB, webhook_registry());
+
+        let mut ran = 0;
+        while job_runner::run_next(config, &db_conn, &webhook_jobs).unwrap() {
+            ran += 1;
+        }
+        ran
+    }
+
+    fn read_jobs(conn: &PgConnection) -> Vec<Job> {
+        jobs::table.load::<Job>(conn).unwrap()
+    }
+
+    async fn deliver_as(webhook_id: &str, topic: &str, body: &[u8], hmac: &str) -> StatusCode {
+        deliver_to("/webhooks/shopify", webhook_id, topic, body, hmac).await
+    }
+
+    async fn deliver_to(
+        path: &str,
+        webhook_id: &str,
+        topic: &str,
+        body: &[u8],
+        hmac: &str,
+    ) -> StatusCode {
+        let config = webhook_config();
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let rate_limiter = Arc::new(RateLimiter::new(&config));
+        let platform = ShopifyPlatform::new(
+            config,
+            db_conn.clone(),
+            Arc::new(reqwest::Client::new()),
+            rate_limiter,
+        );
+        let api =
+            platform_route::webhook(Arc::new(platform), db_conn, Arc::new(webhook_registry()))
+                .and_then(platform_handler::webhook)
+                .recover(handle_rejection);
+
+        warp::test::request()
+            .method("POST")
+            .path(path)
+            .header("X-Shopify-Hmac-Sha256", hmac)
+            .header("X-Shopify-Topic", topic)
+            .header("X-Shopify-Shop-Domain", "some-shop.myshopify.com")
+            .header("X-Shopify-Webhook-Id", webhook_id)
+            .body(body)
+            .reply(&api)
+            .await
+            .status()
+    }
+
+    #[tokio::test]
+    async fn it_queues_a_verified_webhook_for_its_topic() {
+        let body = br#"{"id":820982911946154508,"email":"jon@example.com"}"#;
+        let before = DISPATCHED.load(Ordering::SeqCst);
+
+        let status = deliver("orders/create", body, &sign_body("hush", body)).await;
+
+        // answered before the handler ever runs
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(DISPATCHED.load(Ordering::SeqCst), before);
+
+        assert_eq!(1, run_jobs(&webhook_config()));
+        assert_eq!(DISPATCHED.load(Ordering::SeqCst), before + 1);
+
+        cleanup_tables(&DbConn::new(&db_test_url()).get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_a_webhook_with_a_bad_hmac() {
+        let body = br#"{"id":820982911946154508,"email":"jon@example.com"}"#;
+        let before = DISPATCHED.load(Ordering::SeqCst);
+
+        assert_eq!(
+            deliver("orders/create", body, &sign_body("not-hush", body)).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            deliver("orders/create", body, "not base64!").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(DISPATCHED.load(Ordering::SeqCst), before);
+    }
+
+    #[tokio::test]
+    async fn it_accepts_a_webhook_signed_with_a_previous_secret() {
+        let body = br#"{"id":820982911946154508,"email":"jon@example.com"}"#;
+
+        let status = deliver("orders/create", body, &sign_body("old-hush", body)).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(1, run_jobs(&webhook_config()));
+
+        cleanup_tables(&DbConn::new(&db_test_url()).get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_a_webhook_whose_body_was_changed() {
+        let body = br#"{"id":820982911946154508,"email":"jon@example.com"}"#;
+        let tampered = br#"{"id":820982911946154508, "email":"jon@example.com"}"#;
+
+        let status = deliver("orders/create", tampered, &sign_body("hush", body)).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn it_acknowledges_a_topic_nobody_registered_for() {
+        let body = br#"{"id":820982911946154508}"#;
+        let before = DISPATCHED.load(Ordering::SeqCst);
+
+        let status = deliver("products/update", body, &sign_body("hush", body)).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(DISPATCHED.load(Ordering::SeqCst), before);
+    }
+
+    #[tokio::test]
+    async fn it_deactivates_a_shop_that_uninstalled_the_app() {
+        let db_conn = DbConn::new(&db_test_url());
+        let conn = db_conn.get_conn();
+        let shop_name = "some-shop.myshopify.com";
+
+        let installed =
+            NewShopifyConnection::new(shop_name.to_string(), String::from("some-nonce"))
+                .insert(&conn);
+        update_access_token(
+            &conn,
+            &Config::new(true).token_cipher(),
+            &installed,
+            String::from("f85632530bf277ec9ac6f649fc327f17"),
+            String::from("write_orders"),
+        )
+        .unwrap();
+        NewShopifyOnlineToken::new(
+            &Config::new(true).token_cipher(),
+            shop_name.to_string(),
+            902541635,
+            String::from("f85632530bf277ec9ac6f649fc327f17"),
+            String::from("write_orders"),
+            String::from("write_orders"),
+            86399,
+        )
+        .upsert(&conn)
+        .unwrap();
+
+        let body = br#"{"id":548380009,"domain":"some-shop.myshopify.com"}"#;
+        let status = deliver(webhooks::APP_UNINSTALLED, body, &sign_body("hush", body)).await;
+        assert_eq!(status, StatusCode::OK);
+        run_jobs(&webhook_config());
+
+        assert!(shopify_connection::read_installed_by_shop(&conn, shop_name.to_string()).is_none());
+        let shopify_connections = shopify_connection::read_by_shop(&conn, shop_name.to_string());
+        assert_eq!(1, shopify_connections.len());
+        assert!(!shopify_connections[0].active);
+        assert!(shopify_connections[0].deleted_at.is_some());
+        assert!(shopify_connections[0].access_token.is_none());
+        assert!(shopify_online_token::read_by_shop(&conn, shop_name.to_string()).is_empty());
+
+        cleanup_tables(&conn);
+    }
+
+    #[tokio::test]
+    async fn it_deactivates_only_the_app_that_was_uninstalled() {
+        let db_conn = DbConn::new(&db_test_url());
+        let conn = db_conn.get_conn();
+        let cipher = Config::new(true).token_cipher();
+        let shop_name = "some-shop.myshopify.com";
+
+        for app in [DEFAULT_SHOPIFY_APP, "staging"].iter() {
+            NewShopifyConnection::installed(
+                &cipher,
+                shop_name.to_string(),
+                Uuid::new_v4().to_string(),
+                String::from("f85632530bf277ec9ac6f649fc327f17"),
+                String::from("write_orders"),
+            )
+            .for_app(app)
+            .insert(&conn);
+            NewShopifyOnlineToken::new(
+                &cipher,
+                shop_name.to_string(),
+                902541635,
+                String::from("f85632530bf277ec9ac6f649fc327f17"),
+                String::from("write_orders"),
+                String::from("write_orders"),
+                86399,
+            )
+            .for_app(app)
+            .upsert(&conn)
+            .unwrap();
+        }
+
+        let body = br#"{"id":548380009,"domain":"some-shop.myshopify.com"}"#;
+        let uninstall = |hmac: String| async move {
+            deliver_to(
+                "/webhooks/shopify/staging",
+                &Uuid::new_v4().to_string(),
+                webhooks::APP_UNINSTALLED,
+                body,
+                &hmac,
+            )
+            .await
+        };
+
+        // the default app's secret doesn't sign for staging
+        assert_eq!(
+            uninstall(sign_body("hush", body)).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            uninstall(sign_body("staging-hush", body)).await,
+            StatusCode::OK
+        );
+        run_jobs(&webhook_config());
+
+        assert!(shopify_connection::read_installed_by_app_and_shop(
+            &conn,
+            String::from("staging"),
+            shop_name.to_string()
+        )
+        .is_none());
+        assert!(shopify_connection::read_installed_by_app_and_shop(
+            &conn,
+            String::from(DEFAULT_SHOPIFY_APP),
+            shop_name.to_string()
+        )
+        .is_some());
+        let online_tokens = shopify_online_token::read_by_shop(&conn, shop_name.to_string());
+        assert_eq!(1, online_tokens.len());
+        assert_eq!(online_tokens[0].app, DEFAULT_SHOPIFY_APP);
+
+        cleanup_tables(&conn);
+    }
+
+    #[tokio::test]
+    async fn it_purges_a_shop_on_shop_redact() {
+        let db_conn = DbConn::new(&db_test_url());
+        let conn = db_conn.get_conn();
+        let shop_name = "some-shop.myshopify.com";
+
+        NewShopifyConnection::new(shop_name.to_string(), String::from("some-nonce")).insert(&conn);
+        NewShopifyConnection::new(
+            String::from("other-shop.myshopify.com"),
+            String::from("some-nonce"),
+        )
+        .insert(&conn);
+
+        let body = br#"{"shop_id":954889,"shop_domain":"some-shop.myshopify.com"}"#;
+        let status = deliver(webhooks::SHOP_REDACT, body, &sign_body("hush", body)).await;
+        assert_eq!(status, StatusCode::OK);
+        run_jobs(&webhook_config());
+
+        assert!(shopify_connection::read_by_shop(&conn, shop_name.to_string()).is_empty());
+        assert_eq!(
+            1,
+            shopify_connection::read_by_shop(&conn, String::from("other-shop.myshopify.com")).len()
+        );
+
+        let compliance_requests = compliance_request::read_by_shop(&conn, shop_name.to_string());
+        assert_eq!(1, compliance_requests.len());
+        assert_eq!(compliance_requests[0].topic, webhooks::SHOP_REDACT);
+        assert!(compliance_requests[0].completed_at.is_some());
+
+        cleanup_tables(&conn);
+    }
+
+    #[tokio::test]
+    async fn it_records_customer_data_requests_and_redactions() {
+        let db_conn = DbConn::new(&db_test_url());
+        let conn = db_conn.get_conn();
+        let shop_name = "some-shop.myshopify.com";
+
+        let body = br#"{
+            "shop_id":954889,
+            "shop_domain":"some-shop.myshopify.com",
+            "orders_requested":[299938,280263],
+            "customer":{"id":191167,"email":"john@example.com","phone":"555-625-1199"},
+            "data_request":{"id":9999}
+        }"#;
+        let status = deliver(
+            webhooks::CUSTOMERS_DATA_REQUEST,
+            body,
+            &sign_body("hush", body),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        // an order that keeps failing, waiting in the queue with the customer in it
+        let body = br#"{"id":299938,"customer":{"id":191167,"email":"john@example.com"}}"#;
+        let status = deliver("orders/paid", body, &sign_body("hush", body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(2, run_jobs(&webhook_config()));
+        assert_eq!(1, read_jobs(&conn).len());
+
+        let compliance_requests = compliance_request::read_by_shop(&conn, shop_name.to_string());
+        assert_eq!(1, compliance_requests.len());
+        assert_eq!(
+            compliance_requests[0].topic,
+            webhooks::CUSTOMERS_DATA_REQUEST
+        );
+        assert_eq!(compliance_requests[0].customer_id, Some(191167));
+
+        let body = br#"{
+            "shop_id":954889,
+            "shop_domain":"some-shop.myshopify.com",
+            "customer":{"id":191167,"email":"john@example.com","phone":"555-625-1199"},
+            "orders_to_redact":[299938]
+        }"#;
+        let status = deliver(webhooks::CUSTOMERS_REDACT, body, &sign_body("hush", body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(1, run_jobs(&webhook_config()));
+
+        // only the redaction is left, and it only knows the customer by id
+        let compliance_requests = compliance_request::read_by_shop(&conn, shop_name.to_string());
+        assert_eq!(1, compliance_requests.len());
+        assert_eq!(compliance_requests[0].topic, webhooks::CUSTOMERS_REDACT);
+        assert_eq!(compliance_requests[0].customer_id, Some(191167));
+        assert!(compliance_requests[0].completed_at.is_some());
+        assert!(!compliance_requests[0].payload.contains("john@example.com"));
+        assert!(!compliance_requests[0].payload.contains("555-625-1199"));
+        assert!(read_jobs(&conn).is_empty());
+
+        cleanup_tables(&conn);
+    }
+
+    #[tokio::test]
+    async fn it_retries_a_compliance_webhook_it_cannot_read() {
+        let conn = DbConn::new(&db_test_url()).get_conn();
+        let body = br#"{"shop_domain":"some-shop.myshopify.com"}"#;
+
+        let status = deliver(webhooks::CUSTOMERS_REDACT, body, &sign_body("hush", body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(1, run_jobs(&webhook_config()));
+
+        let jobs = read_jobs(&conn);
+        assert_eq!(1, jobs.len());
+        assert_eq!(jobs[0].status, job::PENDING);
+        assert_eq!(jobs[0].attempts, 1);
+        assert!(jobs[0]
+            .last_error
+            .as_ref()
+            .unwrap()
+            .contains("InvalidPayload"));
+
+        cleanup_tables(&conn);
+    }
+
+    #[tokio::test]
+    async fn it_handles_a_redelivered_webhook_only_once() {
+        let body = br#"{"id":820982911946154508,"email":"jon@example.com"}"#;
+        let hmac = sign_body("hush", body);
+        let webhook_id = Uuid::new_v4().to_string();
+        let before = DISPATCHED.load(Ordering::SeqCst);
+
+        assert_eq!(
+            deliver_as(&webhook_id, "orders/create", body, &hmac).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            deliver_as(&webhook_id, "orders/create", body, &hmac).await,
+            StatusCode::OK
+        );
+
+        assert_eq!(1, run_jobs(&webhook_config()));
+        assert_eq!(DISPATCHED.load(Ordering::SeqCst), before + 1);
+
+        cleanup_tables(&DbConn::new(&db_test_url()).get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_dead_letters_a_webhook_that_keeps_failing() {
+        let conn = DbConn::new(&db_test_url()).get_conn();
+        let body = br#"{"id":820982911946154508,"email":"jon@example.com"}"#;
+
+        let status = deliver("orders/paid", body, &sign_body("hush", body)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let mut config = Config::new(true);
+        config.set_job_max_attempts(1);
+        assert_eq!(1, run_jobs(&config));
+
+        let dead = job::read_dead(&conn);
+        assert_eq!(1, dead.len());
+        assert_eq!(dead[0].kind, webhooks::WEBHOOK_JOB);
+        assert!(dead[0].last_error.is_some());
+
+        cleanup_tables(&conn);
+    }
+}
@@ -0,0 +1,607 @@
+mod woocommerce_integration_tests {
+
+    use diesel::prelude::*;
+    use mockito::{mock, Matcher};
+    use mocktopus::mocking::*;
+    use rust_oauth2_study::{
+        config::{Config, WOOCOMMERCE_APP},
+        db_conn::DbConn,
+        db_test_url,
+        errors::{handle_rejection, AdminApiError, WebhookError},
+        models::woocommerce_connection::{self, NewWooCommerceConnection},
+        platforms::{
+            woocommerce::{self, validate_store_url, WooCommercePlatform},
+            CommercePlatform, NewGiftCard,
+        },
+        routes::platform_route,
+        schema::{jobs, processed_webhooks, woocommerce_connections},
+        utils::gen_uuid,
+        webhooks::{Webhook, WebhookRegistry},
+    };
+    use serde_json::json;
+    use std::sync::Arc;
+    use warp::{self, filters::BoxedFilter, http::StatusCode, Filter, Reply};
+
+    const CONSUMER_KEY: &str = "ck_8b3ac6a0f5c4e1d2b9f7a6e5d4c3b2a1f0e9d8c7";
+    const CONSUMER_SECRET: &str = "cs_1f2e3d4c5b6a79808f7e6d5c4b3a29181f0e2d3c";
+
+    fn cleanup_tables(conn: &PgConnection) {
+        diesel::delete(jobs::table).execute(conn).unwrap();
+        diesel::delete(processed_webhooks::table)
+            .execute(conn)
+            .unwrap();
+        diesel::delete(woocommerce_connections::table)
+            .execute(conn)
+            .unwrap();
+    }
+
+    fn woocommerce_config() -> Arc<Config> {
+        let mut config = Config::new(true);
+        config.set_app_url(String::from("https://gifts.example.com/"));
+        Arc::new(config)
+    }
+
+    fn platform(config: Arc<Config>, db_conn: Arc<DbConn>) -> WooCommercePlatform {
+        WooCommercePlatform::new(config, db_conn, Arc::new(reqwest::Client::new()))
+    }
+
+    fn count_orders(_webhook: &Webhook, _db_conn: &DbConn) -> Result<(), WebhookError> {
+        Ok(())
+    }
+
+    // the same routes main mounts for woocommerce
+    fn woocommerce_routes(
+        config: Arc<Config>,
+        db_conn: Arc<DbConn>,
+    ) -> BoxedFilter<(Box<dyn Reply>,)> {
+        let registry = WebhookRegistry::new().register("order.created", count_orders);
+
+        platform_route::routes_for(
+            Arc::new(platform(config, db_conn.clone())),
+            db_conn,
+            Arc::new(registry),
+        )
+    }
+
+    fn key_delivery(nonce: &str) -> String {
+        json!({
+            "key_id": 1,
+            "user_id": nonce,
+            "consumer_key": CONSUMER_KEY,
+            "consumer_secret": CONSUMER_SECRET,
+            "key_permissions": "read_write"
+        })
+        .to_string()
+    }
+
+    async fn deliver_keys(config: Arc<Config>, db_conn: Arc<DbConn>, body: &str) -> StatusCode {
+        let woocommerce = woocommerce_routes(config, db_conn).recover(handle_rejection);
+
+        warp::test::request()
+            .method("POST")
+            .path("/woocommerce_confirm")
+            .header("content-type", "application/json")
+            .body(body)
+            .reply(&woocommerce)
+            .await
+            .status()
+    }
+
+    // the base64 hmac woocommerce would have sent along with the body
+    fn sign_body(secret: &str, body: &[u8]) -> String {
+        use hmac::{Hmac, Mac, NewMac};
+
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        base64::encode(mac.finalize().into_bytes())
+    }
+
+    async fn deliver_webhook(source: &str, body: &[u8], signature: Option<&str>) -> StatusCode {
+        let config = woocommerce_config();
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let woocommerce = woocommerce_routes(config, db_conn).recover(handle_rejection);
+
+        let mut request = warp::test::request()
+            .method("POST")
+            .path("/webhooks/woocommerce")
+            .header("X-WC-Webhook-Source", source)
+            .body(body);
+        if let Some(signature) = signature {
+            request = request
+                .header("X-WC-Webhook-Signature", signature)
+                .header("X-WC-Webhook-Topic", "order.created")
+                .header("X-WC-Webhook-Delivery-ID", "9d1c0e3f1a2b");
+        }
+
+        request.reply(&woocommerce).await.status()
+    }
+
+    fn installed_store(
+        conn: &PgConnection,
+        config: &Config,
+        store_url: &str,
+        webhook_secret: &str,
+    ) {
+        NewWooCommerceConnection::installed(
+            &config.token_cipher(),
+            store_url.to_string(),
+            gen_uuid(),
+            String::from(CONSUMER_KEY),
+            String::from(CONSUMER_SECRET),
+            String::from("read_write"),
+            webhook_secret.to_string(),
+        )
+        .insert(conn);
+    }
+
+    fn installed_connection(conn: &PgConnection, config: &Config) {
+        installed_store(conn, config, &mockito::server_url(), "wc-hush");
+    }
+
+    fn basic_auth() -> String {
+        format!(
+            "Basic {}",
+            base64::encode(format!("{}:{}", CONSUMER_KEY, CONSUMER_SECRET))
+        )
+    }
+
+    // the store answering for the keys we were just handed
+    fn keys_work() -> mockito::Mock {
+        mock("GET", "/wp-json/wc/v3/system_status")
+            .match_header("authorization", basic_auth().as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(json!({ "environment": { "version": "5.5.2" } }).to_string())
+            .create()
+    }
+
+    fn coupon(id: i64, date_expires_gmt: Option<&str>) -> String {
+        json!({
+            "id": id,
+            "code": "birthday-4f2a",
+            "amount": "25.00",
+            "discount_type": "fixed_cart",
+            "description": "store credit",
+            "date_expires": null,
+            "date_expires_gmt": date_expires_gmt,
+            "usage_count": 0,
+            "usage_limit": 1
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn it_sends_the_merchant_to_approve_keys_on_their_store() {
+        let mut config = Config::new(false);
+        config.set_app_url(String::from("https://gifts.example.com/"));
+        let config = Arc::new(config);
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let woocommerce = woocommerce_routes(config, db_conn.clone());
+
+        let nonce = "some-nonce";
+        gen_uuid.mock_safe(move || MockResult::Return(nonce.to_string()));
+
+        let res = warp::test::request()
+            .method("GET")
+            .path("/woocommerce_install?store_url=https%3A%2F%2Fshop.example.com%2F")
+            .reply(&woocommerce)
+            .await;
+
+        assert_eq!(res.status(), 301);
+        assert_eq!(
+            res.headers()["location"],
+            "https://shop.example.com/wc-auth/v1/authorize\
+            ?app_name=Gift+Cards\
+            &scope=read_write\
+            &user_id=some-nonce\
+            &return_url=https%3A%2F%2Fgifts.example.com\
+            &callback_url=https%3A%2F%2Fgifts.example.com%2Fwoocommerce_confirm"
+        );
+
+        let connection =
+            woocommerce_connection::read_by_nonce(&db_conn.get_conn(), nonce.to_string()).unwrap();
+        assert_eq!(connection.store_url, "https://shop.example.com");
+        assert!(connection.consumer_key.is_none());
+
+        cleanup_tables(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_will_not_send_keys_to_a_store_without_https() {
+        let config = Arc::new(Config::new(false));
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let woocommerce = woocommerce_routes(config, db_conn.clone()).recover(handle_rejection);
+
+        for store_url in &[
+            "http%3A%2F%2Fshop.example.com",
+            "ftp%3A%2F%2Fshop.example.com",
+            "https%3A%2F%2Fshop.example.com%2F%3Fpage%3D1",
+            "shop.example.com",
+        ] {
+            let res = warp::test::request()
+                .method("GET")
+                .path(&format!("/woocommerce_install?store_url={}", store_url))
+                .reply(&woocommerce)
+                .await;
+
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", store_url);
+        }
+        assert!(woocommerce_connections::table
+            .load::<woocommerce_connection::WooCommerceConnection>(&db_conn.get_conn())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_will_not_send_keys_to_an_internal_address() {
+        let config = Arc::new(Config::new(false));
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let woocommerce = woocommerce_routes(config, db_conn.clone()).recover(handle_rejection);
+
+        for store_url in &[
+            "https://127.0.0.1",
+            "https://2130706433",
+            "https://10.0.0.8",
+            "https://192.168.1.20/shop",
+            "https://169.254.169.254/latest",
+            "https://[::1]",
+            "https://93.184.216.34",
+            "https://localhost",
+            "https://admin.localhost",
+            "https://metadata.google.internal",
+            "https://intranet",
+        ] {
+            let res = warp::test::request()
+                .method("GET")
+                .path(&format!(
+                    "/woocommerce_install?store_url={}",
+                    form_urlencoded::byte_serialize(store_url.as_bytes()).collect::<String>()
+                ))
+                .reply(&woocommerce)
+                .await;
+
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", store_url);
+        }
+        assert!(woocommerce_connections::table
+            .load::<woocommerce_connection::WooCommerceConnection>(&db_conn.get_conn())
+            .unwrap()
+            .is_empty());
+
+        // the mock store is the one exception
+        assert!(validate_store_url(&mockito::server_url(), true).is_ok());
+        assert!(validate_store_url("http://10.0.0.8", true).is_err());
+    }
+
+    #[tokio::test]
+    async fn it_saves_the_keys_woocommerce_delivers_and_uses_them() {
+        let mut config = Config::new(true);
+        config.set_app_url(String::from("https://gifts.example.com/"));
+        config.set_woocommerce_webhook_topics(vec![String::from("order.created")]);
+        let config = Arc::new(config);
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let nonce = "delivered-nonce";
+
+        NewWooCommerceConnection::new(mockito::server_url(), nonce.to_string())
+            .insert(&db_conn.get_conn());
+
+        let subscribed = mock("POST", "/wp-json/wc/v3/webhooks")
+            .match_header("authorization", basic_auth().as_str())
+            .match_body(Matcher::AllOf(vec![
+                Matcher::PartialJson(json!({
+                    "topic": "order.created",
+                    "delivery_url": "https://gifts.example.com/webhooks/woocommerce"
+                })),
+                Matcher::Regex(String::from(r#""secret":"[0-9a-f]{64}""#)),
+            ]))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(
+                json!({
+                    "id": 142,
+                    "topic": "order.created",
+                    "delivery_url": "https://gifts.example.com/webhooks/woocommerce",
+                    "status": "active"
+                })
+                .to_string(),
+            )
+            .create();
+
+        let keys_checked = keys_work();
+        let status = deliver_keys(config.clone(), db_conn.clone(), &key_delivery(nonce)).await;
+        assert_eq!(status, StatusCode::OK);
+        keys_checked.assert();
+        subscribed.assert();
+
+        let connection = woocommerce_connection::read_installed_by_store_url(
+            &db_conn.get_conn(),
+            mockito::server_url(),
+        )
+        .unwrap();
+        assert_eq!(connection.consumer_key.as_deref(), Some(CONSUMER_KEY));
+        assert_ne!(connection.consumer_secret.as_deref(), Some(CONSUMER_SECRET));
+        assert_eq!(connection.key_permissions.as_deref(), Some("read_write"));
+
+        // the store signs its webhooks with the secret we made up for it
+        let webhook_secret = connection
+            .decrypt_webhook_secret(&config.token_cipher())
+            .unwrap()
+            .unwrap();
+        let body = br#"{"id": 727, "status": "processing"}"#;
+        assert_eq!(
+            deliver_webhook(
+                &mockito::server_url(),
+                body,
+                Some(&sign_body(&webhook_secret, body))
+            )
+            .await,
+            StatusCode::OK
+        );
+
+        // the keys we saved are the ones the store's api gets
+        let m = mock("GET", "/wp-json/wc/v3/coupons/719")
+            .match_header("authorization", basic_auth().as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(coupon(719, None))
+            .create();
+
+        let gift_card = platform(config, db_conn.clone())
+            .gift_card(None, &mockito::server_url(), "719")
+            .await
+            .unwrap();
+
+        m.assert();
+        assert_eq!(gift_card.id, "719");
+        assert_eq!(gift_card.last_characters, "4f2a");
+        assert!(!gift_card.disabled);
+
+        cleanup_tables(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_takes_each_key_delivery_only_once() {
+        let config = woocommerce_config();
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let nonce = "once-nonce";
+        let _keys_checked = keys_work();
+
+        NewWooCommerceConnection::new(mockito::server_url(), nonce.to_string())
+            .insert(&db_conn.get_conn());
+
+        assert_eq!(
+            deliver_keys(config.clone(), db_conn.clone(), &key_delivery(nonce)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            deliver_keys(config.clone(), db_conn.clone(), &key_delivery(nonce)).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            deliver_keys(
+                config.clone(),
+                db_conn.clone(),
+                &key_delivery("made-up-nonce")
+            )
+            .await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            deliver_keys(config, db_conn.clone(), "not json").await,
+            StatusCode::BAD_REQUEST
+        );
+
+        cleanup_tables(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_keys_the_store_will_not_take() {
+        let config = woocommerce_config();
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        let nonce = "forged-nonce";
+        installed_connection(&db_conn.get_conn(), &config);
+
+        // whoever started this install has the nonce, not the store's keys
+        NewWooCommerceConnection::new(mockito::server_url(), nonce.to_string())
+            .insert(&db_conn.get_conn());
+        let forged_auth = format!("Basic {}", base64::encode("ck_forged:cs_forged"));
+        let m = mock("GET", "/wp-json/wc/v3/system_status")
+            .match_header("authorization", forged_auth.as_str())
+            .with_status(401)
+            .with_body(r#"{"code": "woocommerce_rest_authentication_error"}"#)
+            .create();
+
+        let forged = json!({
+            "key_id": 2,
+            "user_id": nonce,
+            "consumer_key": "ck_forged",
+            "consumer_secret": "cs_forged",
+            "key_permissions": "read_write"
+        })
+        .to_string();
+        assert_eq!(
+            deliver_keys(config.clone(), db_conn.clone(), &forged).await,
+            StatusCode::UNAUTHORIZED
+        );
+        m.assert();
+
+        // the store's real install is left as it was
+        let installed = woocommerce_connection::read_installed_by_store_url(
+            &db_conn.get_conn(),
+            mockito::server_url(),
+        )
+        .unwrap();
+        assert_eq!(installed.consumer_key.as_deref(), Some(CONSUMER_KEY));
+        assert_eq!(
+            installed
+                .decrypt_webhook_secret(&config.token_cipher())
+                .unwrap()
+                .as_deref(),
+            Some("wc-hush")
+        );
+
+        cleanup_tables(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_queues_a_signed_webhook() {
+        let db_conn = DbConn::new(&db_test_url());
+        installed_store(
+            &db_conn.get_conn(),
+            &woocommerce_config(),
+            "https://shop.example.com",
+            "wc-hush",
+        );
+        let body = br#"{"id": 727, "status": "processing"}"#;
+
+        let status = deliver_webhook(
+            "https://shop.example.com/",
+            body,
+            Some(&sign_body("wc-hush", body)),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        // kept apart from shopify's jobs, its uninstalls never touch these
+        let queued = jobs::table
+            .select((jobs::kind, jobs::app, jobs::shop))
+            .load::<(String, String, String)>(&db_conn.get_conn())
+            .unwrap();
+        assert_eq!(
+            queued,
+            vec![(
+                String::from(woocommerce::WEBHOOK_JOB),
+                String::from(WOOCOMMERCE_APP),
+                String::from("https://shop.example.com")
+            )]
+        );
+
+        cleanup_tables(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_a_webhook_signed_with_another_secret() {
+        let db_conn = DbConn::new(&db_test_url());
+        let config = woocommerce_config();
+        installed_store(
+            &db_conn.get_conn(),
+            &config,
+            "https://shop.example.com",
+            "wc-hush",
+        );
+        installed_store(
+            &db_conn.get_conn(),
+            &config,
+            "https://other.example.com",
+            "other-hush",
+        );
+        let body = br#"{"id": 727, "status": "processing"}"#;
+        let shop = "https://shop.example.com/";
+
+        assert_eq!(
+            deliver_webhook(shop, body, Some(&sign_body("not-wc-hush", body))).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            deliver_webhook(shop, body, None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        // another merchant signing with their own store's secret
+        assert_eq!(
+            deliver_webhook(shop, body, Some(&sign_body("other-hush", body))).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            deliver_webhook(
+                "https://unknown.example.com/",
+                body,
+                Some(&sign_body("wc-hush", body))
+            )
+            .await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert!(jobs::table
+            .select(jobs::id)
+            .load::<i32>(&db_conn.get_conn())
+            .unwrap()
+            .is_empty());
+
+        cleanup_tables(&db_conn.get_conn());
+    }
+
+    #[tokio::test]
+    async fn it_answers_the_ping_woocommerce_sends_a_new_webhook() {
+        let db_conn = DbConn::new(&db_test_url());
+
+        let status = deliver_webhook("https://shop.example.com/", b"webhook_id=12", None).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(jobs::table
+            .select(jobs::id)
+            .load::<i32>(&db_conn.get_conn())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_manages_gift_cards_as_coupons() {
+        let config = woocommerce_config();
+        let db_conn = Arc::new(DbConn::new(&db_test_url()));
+        installed_connection(&db_conn.get_conn(), &config);
+        let platform = platform(config, db_conn.clone());
+
+        let created = mock("POST", "/wp-json/wc/v3/coupons")
+            .match_header("authorization", basic_auth().as_str())
+            .match_body(Matcher::Json(json!({
+                "code": "birthday-4f2a",
+                "discount_type": "fixed_cart",
+                "amount": "25.00",
+                "description": "store credit",
+                "usage_limit": 1
+            })))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(coupon(719, None))
+            .create();
+        let disabled = mock("PUT", "/wp-json/wc/v3/coupons/719")
+            .match_header("authorization", basic_auth().as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(coupon(719, Some("2021-07-19T10:00:00")))
+            .create();
+
+        let gift_card = platform
+            .create_gift_card(
+                None,
+                &mockito::server_url(),
+                &NewGiftCard {
+                    initial_value: String::from("25.00"),
+                    code: Some(String::from("birthday-4f2a")),
+                    note: Some(String::from("store credit")),
+                    ..NewGiftCard::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(gift_card.code.as_deref(), Some("birthday-4f2a"));
+        assert_eq!(gift_card.balance, "25.00");
+        assert!(!gift_card.disabled);
+
+        let gift_card = platform
+            .disable_gift_card(None, &mockito::server_url(), &gift_card.id)
+            .await
+            .unwrap();
+
+        created.assert();
+        disabled.assert();
+        assert!(gift_card.disabled);
+        assert!(matches!(
+            platform
+                .gift_card(None, "https://not-installed.example.com", "719")
+                .await,
+            Err(AdminApiError::MissingAccessToken)
+        ));
+
+        cleanup_tables(&db_conn.get_conn());
+    }
+}